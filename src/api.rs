@@ -1,23 +1,351 @@
-use std::io::Read;
-use std::net::SocketAddr;
+use std::cmp;
+use std::env;
+use std::io::{self, BufReader, Read};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use reqwest;
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue, RANGE};
-use anyhow::Result;
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, RANGE};
+use anyhow::{anyhow, Result};
 use serde::Deserialize;
 use serde::de::DeserializeOwned;
-use quick_xml::de::from_str;
+use quick_xml::de::from_reader;
+use serde_json::Value;
+
+use super::deferred;
+use super::scheduler::{Scheduler, StreamPermit};
 
 pub struct PlexAPI {
-    host: SocketAddr,
-    token: String
+    /// Scheme, host and port, e.g. `https://plex.example.com:32400`.
+    base_url: String,
+    token: String,
+    /// Shared by every request so connections, and TLS sessions, are kept
+    /// alive and reused.
+    client: reqwest::blocking::Client,
+    options: ApiOptions,
+    scheduler: Arc<Scheduler>,
+    capabilities: Capabilities
+}
+
+/// The server's `/identity`.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct ServerInfo {
+    #[serde(rename="machineIdentifier", default)]
+    pub machine_identifier: String,
+    pub version: String,
+}
+
+impl ServerInfo {
+    /// The numeric part of the version, e.g. `1.19.1.2645-ccb6eb67e` is
+    /// `[1, 19, 1, 2645]`.
+    pub fn version_numbers(&self) -> Vec<u32> {
+        self.version.split('-').next().unwrap_or("")
+            .split('.')
+            .map(|n| n.parse().unwrap_or(0))
+            .collect()
+    }
+}
+
+/// Features that depend on the server version. Until the server has been
+/// identified, everything is assumed to be supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Responses as JSON with `Accept: application/json`.
+    pub json: bool,
+    /// `/:/websockets/notifications`, and the same notifications as
+    /// server-sent events from `/:/eventsource/notifications`.
+    pub notifications: bool,
+    /// Filter operators like `>>=` and `<<=` in listing queries.
+    pub filter_operators: bool,
+}
+
+impl Capabilities {
+    pub const ALL: Capabilities = Capabilities {
+        json: true,
+        notifications: true,
+        filter_operators: true,
+    };
+
+    pub fn for_server(info: &ServerInfo) -> Self {
+        let version = info.version_numbers();
+        let at_least = |min: &[u32]| version.as_slice() >= min;
+        Capabilities {
+            json: at_least(&[1, 3]),
+            notifications: at_least(&[1, 4]),
+            filter_operators: at_least(&[1, 13]),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiOptions {
+    /// How many times a ranged file request is resumed after failing or
+    /// ending early.
+    pub read_retries: u32,
+    /// Requests allowed in flight at once, across every caller.
+    pub max_connections: usize,
+    /// Accept TLS certificates that don't verify, such as self-signed ones.
+    pub insecure: bool,
+    /// How many times a request is repeated after a server error or a
+    /// failed connection.
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each after it.
+    pub retry_backoff: Duration,
+    /// Most extra wait added to each retry at random, so requests that failed
+    /// together aren't all retried at once.
+    pub retry_jitter: Duration,
+    /// Time allowed to establish a connection.
+    pub connect_timeout: Duration,
+    /// Time allowed for a response to start, and between reads of its body
+    /// after that. None waits indefinitely.
+    pub read_timeout: Option<Duration>,
+    /// Proxy for every request, such as `socks5h://jumphost:1080`. Without
+    /// one, the `HTTP_PROXY` and `HTTPS_PROXY` environment variables apply,
+    /// or failing those, `ALL_PROXY`.
+    pub proxy: Option<String>,
+    /// Restrict what sections list to items matching all of these.
+    pub filters: Vec<Filter>,
+    /// Order sections are listed in, as Plex's `sort` parameter such as
+    /// `addedAt:desc`. Checked with `check_sort`.
+    pub sort: Option<String>,
+}
+
+pub const DEFAULT_READ_RETRIES: u32 = 3;
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 8;
+
+pub const DEFAULT_RETRIES: u32 = 3;
+
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+pub const DEFAULT_RETRY_JITTER: Duration = Duration::from_millis(250);
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The artist compilations are credited to.
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+
+impl Default for ApiOptions {
+    fn default() -> Self {
+        ApiOptions {
+            read_retries: DEFAULT_READ_RETRIES,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            insecure: false,
+            retries: DEFAULT_RETRIES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            retry_jitter: DEFAULT_RETRY_JITTER,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+            proxy: None,
+            filters: vec![],
+            sort: None,
+        }
+    }
+}
+
+/// Checks a sort order: fields such as `addedAt` or `titleSort`, each
+/// optionally followed by `:asc` or `:desc`, separated by commas.
+pub fn check_sort(sort: &str) -> Result<()> {
+    for part in sort.split(',') {
+        let mut halves = part.splitn(2, ':');
+        let field = halves.next().unwrap_or("");
+        let valid_field = !field.is_empty() && field.chars().all(|c| c.is_ascii_alphanumeric() || c == '.');
+        match (valid_field, halves.next()) {
+            (true, None) | (true, Some("asc")) | (true, Some("desc")) => (),
+            _ => return Err(anyhow!("invalid sort \"{}\"; expected e.g. addedAt:desc or titleSort", sort))
+        }
+    }
+    Ok(())
+}
+
+/// A Plex filter expression, such as `artist.title=Beatles`, `unwatched=1`
+/// or `year>>=2000`, as it's added to a section listing's query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    /// The field with any operator but the `=`, as in `year>>`.
+    pub field: String,
+    pub value: String,
+}
+
+impl Filter {
+    pub fn parse(expression: &str) -> Result<Filter> {
+        let mut parts = expression.splitn(2, '=');
+        let field = parts.next().unwrap_or("");
+        let value = parts.next()
+            .ok_or_else(|| anyhow!("filter \"{}\" has no value; expected e.g. artist.title=Beatles", expression))?;
+        if field.is_empty() || !field.chars().all(|c| c.is_ascii_alphanumeric() || "._!<>".contains(c)) {
+            return Err(anyhow!("filter \"{}\" has an invalid field", expression));
+        }
+        Ok(Filter { field: field.to_string(), value: value.to_string() })
+    }
+
+    /// Whether it compares with `>>=` or `<<=`, which older servers don't
+    /// understand.
+    fn uses_operator(&self) -> bool {
+        self.field.ends_with(">>") || self.field.ends_with("<<")
+    }
+
+    fn to_query(&self) -> String {
+        format!("{}={}", self.field, percent_encode(&self.value))
+    }
+}
+
+/// `text` with everything but unreserved characters percent-encoded, for a
+/// query value or path segment.
+fn percent_encode(text: &str) -> String {
+    text.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b)
+    }).collect()
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MediaKind {
     Video = 1,
     TV = 2,
     Music = 8,
+    Photo = 14,
+}
+
+impl MediaKind {
+    /// The kind of a section from its `type` in `/library/sections`.
+    pub fn from_section_type(kind: &str) -> Option<MediaKind> {
+        match kind {
+            "movie" => Some(MediaKind::Video),
+            "show" => Some(MediaKind::TV),
+            "artist" => Some(MediaKind::Music),
+            "photo" => Some(MediaKind::Photo),
+            _ => None
+        }
+    }
+
+    /// The Plex metadata type of the playable items in a section of this
+    /// kind.
+    pub fn leaf_type(self) -> u8 {
+        match self {
+            MediaKind::Video => 1,
+            MediaKind::TV => 4,
+            MediaKind::Music => 10,
+            MediaKind::Photo => 13,
+        }
+    }
+}
+
+/// The Plex metadata type of albums.
+pub const ALBUM_TYPE: u8 = 9;
+
+/// A library section, as listed by `/library/sections`.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct Section {
+    pub key: u64,
+    #[serde(rename="type")]
+    pub kind: String,
+    pub title: String,
+    /// Folders on the server the section's media is scanned from.
+    #[serde(rename="Location", default)]
+    pub locations: Vec<Location>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct Location {
+    pub path: String,
+}
+
+/// Finds the section `spec` names, by key or by title ignoring case. A
+/// number is taken as a key first, so a section titled `1999` can still be
+/// found by title if no section has that key; `key:10` is always a key. The
+/// error lists the sections there are.
+pub fn find_section<'a>(sections: &'a [Section], spec: &str) -> Result<&'a Section> {
+    let by_key = |key: u64| sections.iter().find(|s| s.key == key);
+    let by_title = || sections.iter().find(|s| s.title.to_lowercase() == spec.to_lowercase());
+    let found = match spec.strip_prefix("key:") {
+        Some(key) => key.parse::<u64>().ok().and_then(by_key),
+        None => spec.parse::<u64>().ok().and_then(by_key).or_else(by_title)
+    };
+    found.ok_or_else(|| {
+        let available: Vec<String> = sections.iter()
+            .map(|s| format!("{} \"{}\"", s.key, s.title))
+            .collect();
+        anyhow!("no section \"{}\" on the server (sections: {})", spec, available.join(", "))
+    })
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct SectionContainer {
+    #[serde(rename="Directory", default)]
+    pub sections: Vec<Section>
+}
+
+/// A playlist, as listed by `/playlists`.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct Playlist {
+    #[serde(rename="ratingKey")]
+    pub rating_key: u64,
+    pub title: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct PlaylistContainer {
+    #[serde(rename="Playlist", default)]
+    pub playlists: Vec<Playlist>
+}
+
+/// A genre of a section, as listed by `/library/sections/{id}/genre`.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct Genre {
+    /// What the section's items are filtered by to list the genre's.
+    pub key: String,
+    pub title: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct GenreContainer {
+    #[serde(rename="Directory", default)]
+    pub genres: Vec<Genre>
+}
+
+/// A character that the sort titles of some of a section's items start
+/// with, as listed by `/library/sections/{id}/firstCharacter`. Those
+/// starting with anything but a letter are together under "#".
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct FirstCharacter {
+    pub key: String,
+    pub title: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct FirstCharacterContainer {
+    #[serde(rename="Directory", default)]
+    pub characters: Vec<FirstCharacter>
+}
+
+/// A subtitle one of the server's providers has for a video, as listed by
+/// `/library/metadata/{id}/subtitles`.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct SubtitleResult {
+    /// What the server downloads it by.
+    pub key: String,
+    #[serde(default)]
+    pub codec: Option<String>,
+    #[serde(rename="languageCode", default)]
+    pub language_code: Option<String>,
+    #[serde(rename="providerTitle", default)]
+    pub provider_title: Option<String>,
+    #[serde(rename="displayTitle", default)]
+    pub display_title: Option<String>,
+    /// How well it matches the video, by the provider's reckoning.
+    #[serde(default)]
+    pub score: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct SubtitleContainer {
+    #[serde(rename="Stream", default)]
+    pub results: Vec<SubtitleResult>
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
@@ -31,11 +359,55 @@ pub enum Item {
     Directory {
         #[serde(rename="ratingKey", default)]
         rating_key: u64,
+        /// Where the directory's children are listed.
+        #[serde(default)]
+        key: String,
+        // Folder listings leave these out.
+        #[serde(default)]
         guid: String,
         title: String,
         #[serde(rename="parentTitle", default)]
         parent_title: String,
+        /// The rating key of an album's artist or a season's show, 0 if
+        /// unknown.
+        #[serde(rename="parentRatingKey", default)]
+        parent_rating_key: u64,
+        #[serde(default)]
         summary: String,
+        /// "artist", "album", "show" or "season".
+        #[serde(rename="type", default)]
+        kind: String,
+        /// Season number of a season.
+        #[serde(default)]
+        index: Option<u64>,
+        #[serde(default)]
+        year: Option<u64>,
+        /// Release date, "YYYY-MM-DD".
+        #[serde(rename="originallyAvailableAt", default)]
+        originally_available_at: Option<String>,
+        /// Path of the artwork, e.g. an album's cover or a show's poster.
+        #[serde(default)]
+        thumb: Option<String>,
+        /// Path of the background art.
+        #[serde(default)]
+        art: Option<String>,
+        /// Number of items directly below this one.
+        #[serde(rename="childCount", default)]
+        child_count: u64,
+        /// Number of playable items anywhere below this one.
+        #[serde(rename="leafCount", default)]
+        leaf_count: u64,
+        /// Critics' rating out of 10.
+        #[serde(default)]
+        rating: Option<f64>,
+        /// The owner's rating out of 10.
+        #[serde(rename="userRating", default)]
+        user_rating: Option<f64>,
+        #[serde(rename="Genre", default)]
+        genres: Vec<Tag>,
+        /// Such as "Compilation" for an album tagged as one.
+        #[serde(rename="Subformat", default)]
+        subformats: Vec<Tag>,
         #[serde(rename="lastViewedAt", default)]
         last_viewed_at: u64,
         #[serde(rename="addedAt", default)]
@@ -44,20 +416,104 @@ pub enum Item {
         updated_at: u64,
     },
     Video {
+        #[serde(rename="ratingKey", default)]
+        rating_key: u64,
+        #[serde(default)]
+        guid: String,
+        /// "movie" or "episode".
+        #[serde(rename="type", default)]
+        kind: String,
         title: String,
         #[serde(rename="grandparentTitle", default)]
         grandparent_title: String,
+        /// The show of an episode.
+        #[serde(rename="grandparentRatingKey", default)]
+        grandparent_rating_key: u64,
+        #[serde(default)]
+        year: Option<u64>,
+        /// Episode number within the season.
+        #[serde(default)]
+        index: Option<u64>,
+        /// Season number of an episode.
+        #[serde(rename="parentIndex", default)]
+        parent_index: Option<u64>,
+        /// Episode number counting from the show's first, for shows the
+        /// agent numbers that way.
+        #[serde(rename="absoluteIndex", default)]
+        absolute_index: Option<u64>,
+        #[serde(default)]
+        summary: String,
+        #[serde(default)]
+        rating: Option<f64>,
+        #[serde(rename="userRating", default)]
+        user_rating: Option<f64>,
+        #[serde(rename="Genre", default)]
+        genres: Vec<Tag>,
+        #[serde(rename="lastViewedAt", default)]
+        last_viewed_at: u64,
+        #[serde(rename="addedAt", default)]
+        added_at: u64,
+        #[serde(rename="updatedAt", default)]
+        updated_at: u64,
+        #[serde(rename="Media", default)]
+        media: Vec<Media>
+    },
+    Photo {
+        #[serde(rename="ratingKey", default)]
+        rating_key: u64,
+        #[serde(default)]
+        guid: String,
+        title: String,
+        /// When it was taken, "YYYY-MM-DD".
+        #[serde(rename="originallyAvailableAt", default)]
+        originally_available_at: Option<String>,
+        #[serde(default)]
+        summary: String,
+        #[serde(rename="addedAt", default)]
+        added_at: u64,
+        #[serde(rename="updatedAt", default)]
+        updated_at: u64,
         #[serde(rename="Media", default)]
-        media: Media
+        media: Vec<Media>
     },
     Track {
         #[serde(rename="ratingKey", default)]
         rating_key: u64,
+        #[serde(default)]
         guid: String,
         title: String,
+        /// Track number on the album.
+        #[serde(default)]
+        index: Option<u64>,
+        /// Disc number on the album.
+        #[serde(rename="parentIndex", default)]
+        parent_index: Option<u64>,
+        /// The album's rating key, 0 if the track has none.
+        #[serde(rename="parentRatingKey", default)]
+        parent_rating_key: u64,
         #[serde(rename="parentTitle", default)]
         parent_title: String,
+        /// The album's release year.
+        #[serde(rename="parentYear", default)]
+        parent_year: Option<u64>,
+        /// The album's artist, such as "Various Artists" for a compilation,
+        /// whoever performs the track.
+        #[serde(rename="grandparentTitle", default)]
+        grandparent_title: String,
+        /// The rating key of the album's artist, 0 if unknown.
+        #[serde(rename="grandparentRatingKey", default)]
+        grandparent_rating_key: u64,
+        /// Who performs the track, if that's not the album's artist.
+        #[serde(rename="originalTitle", default)]
+        original_title: Option<String>,
+        #[serde(default)]
         summary: String,
+        #[serde(default)]
+        rating: Option<f64>,
+        #[serde(rename="userRating", default)]
+        user_rating: Option<f64>,
+        #[serde(rename="Genre", default)]
+        genres: Vec<Tag>,
         #[serde(rename="lastViewedAt", default)]
         last_viewed_at: u64,
         #[serde(rename="addedAt", default)]
@@ -65,15 +521,28 @@ pub enum Item {
         #[serde(rename="updatedAt", default)]
         updated_at: u64,
         #[serde(rename="Media", default)]
-        media: Media
+        media: Vec<Media>
     }
 }
 
-#[derive(Deserialize, PartialEq, Debug)]
+/// A tag such as a genre, as Plex lists them under an item.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct Tag {
+    pub tag: String,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
 pub struct Media {
     pub container: Option<String>,
     #[serde(rename="videoResolution", default)]
     pub video_resolution: Option<String>,
+    #[serde(default)]
+    pub height: Option<u64>,
+    #[serde(default)]
+    pub bitrate: Option<u64>,
+    #[serde(rename="audioCodec", default)]
+    pub audio_codec: Option<String>,
+    #[serde(default)]
     pub duration: u64,
     #[serde(rename="Part", default)]
     pub part: Part
@@ -84,18 +553,81 @@ impl Default for Media {
         Media {
             container: None,
             video_resolution: None,
+            height: None,
+            bitrate: None,
+            audio_codec: None,
             duration: 0,
             part: Part::default()
         }
     }
 }
 
-#[derive(Deserialize, PartialEq, Debug)]
+impl Media {
+    /// A file extension for this media, from the part's or media's container,
+    /// or failing that the audio codec.
+    pub fn extension(&self) -> Option<&'static str> {
+        let is_video = self.video_resolution.is_some();
+        let container = self.part.container.as_ref().or(self.container.as_ref());
+        let from_container = container.and_then(|c| match &c.to_lowercase()[..] {
+            "mp4" | "m4a" if !is_video => Some("m4a"),
+            "mp4" => Some("mp4"),
+            "mpegts" | "ts" => Some("ts"),
+            "mkv" | "matroska" => Some("mkv"),
+            "avi" => Some("avi"),
+            "mov" => Some("mov"),
+            "webm" => Some("webm"),
+            "wmv" => Some("wmv"),
+            "asf" => Some("wma"),
+            "flac" => Some("flac"),
+            "mp3" => Some("mp3"),
+            "ogg" => Some("ogg"),
+            "wav" => Some("wav"),
+            "aiff" => Some("aiff"),
+            _ => None
+        });
+        from_container.or_else(|| {
+            self.audio_codec.as_ref().and_then(|c| match &c.to_lowercase()[..] {
+                "flac" => Some("flac"),
+                "mp3" => Some("mp3"),
+                "aac" | "alac" => Some("m4a"),
+                "vorbis" => Some("ogg"),
+                "opus" => Some("opus"),
+                "wmav2" | "wmapro" => Some("wma"),
+                _ => None
+            })
+        })
+    }
+
+    /// Whether the Part deserialized with a key to request it by. Missing
+    /// or malformed Parts fall back to an empty default.
+    pub fn has_part(&self) -> bool {
+        !self.part.key.is_empty()
+    }
+
+    /// Vertical resolution in pixels, from `height` if present and
+    /// `videoResolution` ("1080", "4k", "sd") otherwise.
+    pub fn resolution(&self) -> Option<u64> {
+        if let Some(height) = self.height {
+            return Some(height);
+        }
+        match self.video_resolution.as_ref().map(|r| r.to_lowercase()) {
+            Some(ref r) if r == "4k" => Some(2160),
+            Some(ref r) if r == "sd" => Some(480),
+            Some(r) => r.trim_end_matches('p').parse().ok(),
+            None => None
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
 pub struct Part {
     pub key: String,
     pub file: String,
     pub size: u64,
     pub container: Option<String>,
+    /// Only listed in an item's full metadata.
+    #[serde(rename="Stream", default)]
+    pub streams: Vec<Stream>,
 }
 
 impl Default for Part {
@@ -105,36 +637,600 @@ impl Default for Part {
             file: String::new(),
             size: 0,
             container: None,
+            streams: vec![],
+        }
+    }
+}
+
+impl Part {
+    /// The first lyrics stream that can be fetched on its own.
+    pub fn lyrics(&self) -> Option<&Stream> {
+        self.streams.iter().find(|stream| stream.stream_type == STREAM_LYRICS && stream.key.is_some())
+    }
+
+    /// The subtitle streams that can be fetched on their own, which are
+    /// those in files beside the media. Plex doesn't serve embedded ones.
+    pub fn subtitles(&self) -> impl Iterator<Item = &Stream> {
+        self.streams.iter().filter(|stream| stream.stream_type == STREAM_SUBTITLES && stream.key.is_some())
+    }
+}
+
+/// `streamType` of subtitles.
+pub const STREAM_SUBTITLES: u8 = 3;
+
+/// `streamType` of lyrics.
+pub const STREAM_LYRICS: u8 = 4;
+
+/// A track of a Part: video, audio, subtitles or lyrics.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct Stream {
+    pub id: u64,
+    #[serde(rename="streamType")]
+    pub stream_type: u8,
+    /// Where the stream is fetched from, if it's in a file of its own or the
+    /// server provides it.
+    #[serde(default)]
+    pub key: Option<String>,
+    /// e.g. "lrc", "txt", "srt", "ass".
+    #[serde(default)]
+    pub codec: Option<String>,
+    /// ISO 639-2, e.g. "eng".
+    #[serde(rename="languageCode", default)]
+    pub language_code: Option<String>,
+}
+
+impl Stream {
+    /// A file extension for a text subtitle stream. Image formats such as
+    /// PGS have none.
+    pub fn subtitle_extension(&self) -> Option<&'static str> {
+        match &self.codec.as_ref()?.to_lowercase()[..] {
+            "srt" | "subrip" => Some("srt"),
+            "ass" => Some("ass"),
+            "ssa" => Some("ssa"),
+            "vtt" | "webvtt" => Some("vtt"),
+            "smi" => Some("smi"),
+            _ => None
+        }
+    }
+}
+
+/// Building a response from Plex's JSON, which nests the same attributes as
+/// the XML differently: every item is in a `Metadata` array with its kind in
+/// `type`, keys are strings, and each Media has an array of Parts.
+pub trait FromJson: Sized {
+    /// `container` is the response's `MediaContainer` object.
+    fn from_json(container: Value) -> Result<Self>;
+}
+
+/// The elements of the array `name` in `container`, none if it's missing.
+fn json_array(container: &mut Value, name: &str) -> Vec<Value> {
+    match container[name].take() {
+        Value::Array(values) => values,
+        _ => vec![]
+    }
+}
+
+/// Replaces the string in `value[name]` with the number it holds.
+fn json_number(value: &mut Value, name: &str) {
+    let number = value[name].as_str().and_then(|s| s.parse::<u64>().ok());
+    if let Some(number) = number {
+        value[name] = Value::from(number);
+    }
+}
+
+impl FromJson for MediaContainer {
+    /// Items that don't parse are left out rather than failing the whole
+    /// listing.
+    fn from_json(mut container: Value) -> Result<Self> {
+        let mut values = json_array(&mut container, "Metadata");
+        values.extend(json_array(&mut container, "Directory"));
+
+        let mut items = vec![];
+        for mut value in values {
+            for name in ["ratingKey", "parentRatingKey", "grandparentRatingKey"].iter() {
+                json_number(&mut value, name);
+            }
+            // Whether it's a file, and so which variant, goes by whether it
+            // has Media; the same `type` can be either in photo sections.
+            let variant = match (value.get("Media").is_some(), value["type"].as_str()) {
+                (false, _) => "Directory",
+                (true, Some("track")) => "Track",
+                (true, Some("photo")) => "Photo",
+                (true, _) => "Video"
+            };
+            if let Some(Value::Array(media)) = value.get_mut("Media") {
+                // The first Part, as the XML has.
+                for media in media.iter_mut() {
+                    match json_array(media, "Part").into_iter().next() {
+                        Some(part) => media["Part"] = part,
+                        None => if let Value::Object(fields) = media {
+                            fields.remove("Part");
+                        }
+                    }
+                }
+            }
+            let mut tagged = serde_json::Map::new();
+            tagged.insert(variant.to_string(), value);
+            match serde_json::from_value(Value::Object(tagged)) {
+                Ok(item) => items.push(item),
+                Err(e) => warn!("skipping an item that didn't parse: {}", e)
+            }
+        }
+        Ok(MediaContainer { items: items })
+    }
+}
+
+impl FromJson for SectionContainer {
+    fn from_json(mut container: Value) -> Result<Self> {
+        let sections = json_array(&mut container, "Directory").into_iter()
+            .map(|mut value| {
+                json_number(&mut value, "key");
+                serde_json::from_value(value)
+            })
+            .collect::<serde_json::Result<_>>()?;
+        Ok(SectionContainer { sections: sections })
+    }
+}
+
+impl FromJson for PlaylistContainer {
+    fn from_json(mut container: Value) -> Result<Self> {
+        let playlists = json_array(&mut container, "Metadata").into_iter()
+            .map(|mut value| {
+                json_number(&mut value, "ratingKey");
+                serde_json::from_value(value)
+            })
+            .collect::<serde_json::Result<_>>()?;
+        Ok(PlaylistContainer { playlists: playlists })
+    }
+}
+
+impl FromJson for GenreContainer {
+    fn from_json(mut container: Value) -> Result<Self> {
+        let genres = json_array(&mut container, "Directory").into_iter()
+            .map(serde_json::from_value)
+            .collect::<serde_json::Result<_>>()?;
+        Ok(GenreContainer { genres: genres })
+    }
+}
+
+impl FromJson for FirstCharacterContainer {
+    fn from_json(mut container: Value) -> Result<Self> {
+        let characters = json_array(&mut container, "Directory").into_iter()
+            .map(serde_json::from_value)
+            .collect::<serde_json::Result<_>>()?;
+        Ok(FirstCharacterContainer { characters: characters })
+    }
+}
+
+impl FromJson for SubtitleContainer {
+    fn from_json(mut container: Value) -> Result<Self> {
+        let results = json_array(&mut container, "Stream").into_iter()
+            .map(serde_json::from_value)
+            .collect::<serde_json::Result<_>>()?;
+        Ok(SubtitleContainer { results: results })
+    }
+}
+
+impl FromJson for ServerInfo {
+    fn from_json(container: Value) -> Result<Self> {
+        Ok(serde_json::from_value(container)?)
+    }
+}
+
+impl Item {
+    pub fn rating_key(&self) -> u64 {
+        match self {
+            Item::Directory { rating_key, .. } => *rating_key,
+            Item::Video { rating_key, .. } => *rating_key,
+            Item::Track { rating_key, .. } => *rating_key,
+            Item::Photo { rating_key, .. } => *rating_key,
+        }
+    }
+
+    pub fn guid(&self) -> &str {
+        match self {
+            Item::Directory { guid, .. } => guid,
+            Item::Video { guid, .. } => guid,
+            Item::Track { guid, .. } => guid,
+            Item::Photo { guid, .. } => guid,
         }
     }
+
+    pub fn title(&self) -> &str {
+        match self {
+            Item::Directory { title, .. } => title,
+            Item::Video { title, .. } => title,
+            Item::Track { title, .. } => title,
+            Item::Photo { title, .. } => title,
+        }
+    }
+
+    pub fn summary(&self) -> &str {
+        match self {
+            Item::Directory { summary, .. } => summary,
+            Item::Video { summary, .. } => summary,
+            Item::Track { summary, .. } => summary,
+            Item::Photo { summary, .. } => summary,
+        }
+    }
+
+    /// Path of a directory item's artwork, or failing that its background
+    /// art. Fetched with `resource`.
+    pub fn thumb(&self) -> Option<&str> {
+        match self {
+            Item::Directory { thumb, art, .. } => thumb.as_ref().or(art.as_ref()).map(|path| &path[..]),
+            _ => None
+        }
+    }
+
+    /// The critics' and the owner's ratings, out of 10.
+    pub fn ratings(&self) -> (Option<f64>, Option<f64>) {
+        match self {
+            Item::Directory { rating, user_rating, .. } => (*rating, *user_rating),
+            Item::Video { rating, user_rating, .. } => (*rating, *user_rating),
+            Item::Track { rating, user_rating, .. } => (*rating, *user_rating),
+            Item::Photo { .. } => (None, None),
+        }
+    }
+
+    pub fn genres(&self) -> &[Tag] {
+        match self {
+            Item::Directory { genres, .. } => &genres[..],
+            Item::Video { genres, .. } => &genres[..],
+            Item::Track { genres, .. } => &genres[..],
+            Item::Photo { .. } => &[]
+        }
+    }
+
+    /// Whether the item is an album tagged as a compilation, or credited to
+    /// Various Artists.
+    pub fn is_compilation(&self) -> bool {
+        match self {
+            Item::Directory { kind, parent_title, subformats, .. } if kind == "album" => {
+                parent_title.eq_ignore_ascii_case(VARIOUS_ARTISTS)
+                    || subformats.iter().any(|subformat| subformat.tag.eq_ignore_ascii_case("compilation"))
+            }
+            _ => false
+        }
+    }
+
+    /// Release year, from `year` or else `originallyAvailableAt`.
+    pub fn year(&self) -> Option<u64> {
+        match self {
+            Item::Directory { year: Some(year), .. } => Some(*year),
+            Item::Directory { originally_available_at: Some(date), .. } => date.get(..4)?.parse().ok(),
+            Item::Video { year, .. } => *year,
+            Item::Track { parent_year, .. } => *parent_year,
+            _ => None
+        }
+    }
+
+    /// Whether the item is a file with Media, rather than a container.
+    pub fn is_playable(&self) -> bool {
+        match self {
+            Item::Directory { .. } => false,
+            Item::Video { .. } | Item::Track { .. } | Item::Photo { .. } => true,
+        }
+    }
+
+    /// `lastViewedAt`, `updatedAt` and `addedAt`, 0 where unknown.
+    pub fn timestamps(&self) -> (u64, u64, u64) {
+        match self {
+            Item::Directory { last_viewed_at, updated_at, added_at, .. } => (*last_viewed_at, *updated_at, *added_at),
+            Item::Track { last_viewed_at, updated_at, added_at, .. } => (*last_viewed_at, *updated_at, *added_at),
+            Item::Video { last_viewed_at, updated_at, added_at, .. } => (*last_viewed_at, *updated_at, *added_at),
+            Item::Photo { updated_at, added_at, .. } => (0, *updated_at, *added_at),
+        }
+    }
+
+    pub fn media(&self) -> &[Media] {
+        match self {
+            Item::Video { media, .. } => &media[..],
+            Item::Track { media, .. } => &media[..],
+            Item::Photo { media, .. } => &media[..],
+            _ => &[]
+        }
+    }
+}
+
+/// Turns `--host` into a base URL. A bare `host:port` is taken as plain
+/// HTTP; otherwise it must be an `http://` or `https://` URL.
+pub fn base_url(host: &str) -> Result<String> {
+    let url = if host.contains("://") {
+        host.trim_end_matches('/').to_string()
+    } else {
+        format!("http://{}", host)
+    };
+    let parsed = reqwest::Url::parse(&url)
+        .map_err(|e| anyhow!("invalid host \"{}\": {}", host, e))?;
+    match parsed.scheme() {
+        "http" | "https" if parsed.host_str().is_some() => Ok(url),
+        _ => Err(anyhow!("invalid host \"{}\": expected host:port or an http(s) URL", host))
+    }
+}
+
+/// A client builder with the TLS, timeout and proxy settings in `options`,
+/// for every client that talks to a server or plex.tv.
+pub fn client_builder(options: &ApiOptions) -> Result<reqwest::blocking::ClientBuilder> {
+    let builder = reqwest::blocking::Client::builder()
+        .danger_accept_invalid_certs(options.insecure)
+        .connect_timeout(options.connect_timeout);
+    // reqwest reads HTTP_PROXY and HTTPS_PROXY itself, but not ALL_PROXY.
+    let specific = ["HTTP_PROXY", "http_proxy", "HTTPS_PROXY", "https_proxy"].iter()
+        .any(|name| env::var_os(name).is_some());
+    let all = match specific {
+        true => None,
+        false => env::var("ALL_PROXY").or_else(|_| env::var("all_proxy")).ok()
+    };
+    match options.proxy.clone().or(all) {
+        Some(url) => {
+            let proxy = reqwest::Proxy::all(&url)
+                .map_err(|e| anyhow!("invalid proxy \"{}\": {}", url, e))?;
+            Ok(builder.proxy(proxy))
+        }
+        None => Ok(builder)
+    }
+}
+
+/// Whether `e` may not happen again if the request is repeated: the server
+/// answered with a 5xx, such as 503 during maintenance, or couldn't be
+/// reached or stopped answering.
+fn transient(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| match cause.downcast_ref::<reqwest::Error>() {
+        Some(e) => match e.status() {
+            Some(status) => status.is_server_error(),
+            None => e.is_connect() || e.is_timeout() || e.is_body()
+        },
+        None => false
+    })
+}
+
+/// How long to wait before retry number `attempt`, counting from 1.
+pub fn backoff(options: &ApiOptions, attempt: u32) -> Duration {
+    let jitter = options.retry_jitter.as_millis() as u64;
+    let jitter = match jitter {
+        0 => 0,
+        // Random enough to spread retries out.
+        jitter => SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64 % (jitter + 1))
+            .unwrap_or(0)
+    };
+    options.retry_backoff * 2u32.pow(cmp::min(attempt - 1, 16)) + Duration::from_millis(jitter)
+}
+
+/// Runs `request` again, after a growing wait, while it fails for a reason
+/// that may pass, up to `retries` times. The request should take its own
+/// connection slot, so none is held while waiting.
+pub fn retrying<T, F>(options: &ApiOptions, what: &str, request: F) -> Result<T>
+    where F: Fn() -> Result<T>
+{
+    let mut attempt = 0;
+    loop {
+        match request() {
+            Err(e) if attempt < options.retries && transient(&e) => {
+                attempt += 1;
+                let delay = backoff(options, attempt);
+                warn!("{} failed, retrying in {:?} ({}/{}): {}", what, delay, attempt, options.retries, e);
+                thread::sleep(delay);
+            }
+            result => return result
+        }
+    }
+}
+
+/// Reads `size` bytes of a part starting at `offset` with `range`, which
+/// appends up to the bytes asked for to the buffer. If it fails or the body
+/// ends early, the rest is requested again from where it left off, up to
+/// `read_retries` times. Returns fewer bytes only if the server reports the
+/// end of the file.
+pub fn read_resuming<F>(options: &ApiOptions, part: &Part, offset: i64, size: u32, range: F) -> Result<Vec<u8>>
+    where F: Fn(i64, u32, &mut Vec<u8>) -> Result<()>
+{
+    let mut buf = Vec::with_capacity(size as usize);
+    let mut attempt = 0;
+
+    while buf.len() < size as usize {
+        let start = buf.len();
+        let result = range(offset + start as i64, size - start as u32, &mut buf);
+        let got = buf.len() - start;
+        match result {
+            Ok(()) if got == 0 => break,
+            Ok(()) => continue,
+            Err(e) => {
+                attempt += 1;
+                if attempt > options.read_retries {
+                    return Err(e);
+                }
+                warn!("read of {} at {} failed, resuming ({}/{}): {}",
+                      part.key, offset + buf.len() as i64, attempt, options.read_retries, e);
+                thread::sleep(backoff(options, attempt));
+            }
+        }
+    }
+
+    Ok(buf)
+}
+
+/// The body of a streaming response, which keeps its connection's slot
+/// until it's dropped.
+pub struct StreamBody {
+    response: reqwest::blocking::Response,
+    _permit: StreamPermit,
+}
+
+impl StreamBody {
+    pub fn new(response: reqwest::blocking::Response, permit: StreamPermit) -> Self {
+        StreamBody {
+            response: response,
+            _permit: permit,
+        }
+    }
+}
+
+impl Read for StreamBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.response.read(buf)
+    }
 }
 
 impl PlexAPI {
-    pub fn new(host: SocketAddr, token: String) -> Self {
-        PlexAPI {
-            host: host,
-            token: token
+    pub fn new(base_url: String, token: String, options: ApiOptions) -> Result<Self> {
+        let scheduler = Arc::new(Scheduler::new(options.max_connections));
+        // Keep as many idle connections as may be in use at once, so a burst
+        // of reads doesn't close ones the next burst would reuse.
+        let client = client_builder(&options)?
+            .pool_max_idle_per_host(options.max_connections)
+            .timeout(options.read_timeout)
+            .build()?;
+        Ok(PlexAPI {
+            base_url: base_url,
+            token: token,
+            client: client,
+            options: options,
+            scheduler: scheduler,
+            capabilities: Capabilities::ALL
+        })
+    }
+
+    /// Fetches the server's version and limits `capabilities` to what it
+    /// supports.
+    pub fn identify(&mut self) -> Result<ServerInfo> {
+        // As XML, which every version answers with.
+        self.capabilities.json = false;
+        let info: ServerInfo = self.get("/identity", "")?;
+        self.capabilities = Capabilities::for_server(&info);
+        debug!("server {} version {}: {:?}", info.machine_identifier, info.version, self.capabilities);
+        Ok(info)
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The cap on concurrent requests. Callers fanning work out over
+    /// threads gain nothing from running more than this.
+    pub fn max_connections(&self) -> usize {
+        self.options.max_connections
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn require(&self, supported: bool, feature: &str) -> Result<()> {
+        if supported {
+            Ok(())
+        } else {
+            Err(anyhow!("the server is too old to support {}", feature))
         }
     }
 
+    /// Query arguments for `filters` and `sort`, each starting with `&`.
+    fn listing_args(&self) -> Result<String> {
+        let mut args = String::new();
+        for filter in self.options.filters.iter() {
+            if filter.uses_operator() {
+                self.require(self.capabilities.filter_operators, "filter operators like >>=")?;
+            }
+            args.push('&');
+            args.push_str(&filter.to_query());
+        }
+        if let Some(sort) = &self.options.sort {
+            args.push_str("&sort=");
+            args.push_str(sort);
+        }
+        Ok(args)
+    }
+
     fn get_paged<T>(&self, url: &str, args: &str, start: u64, size: u64) -> Result<(T, u64)>
-        where T: DeserializeOwned
+        where T: DeserializeOwned + FromJson
     {
+        let request = deferred::Request {
+            url: url.to_string(),
+            args: args.to_string(),
+            start: start,
+            size: size,
+        };
+        if let Some(body) = deferred::answer(request) {
+            let (bytes, total) = body?;
+            return Ok((self.parse(&bytes[..])?, total));
+        }
+        retrying(&self.options, url, || {
+            let _permit = self.scheduler.acquire();
+            let (resp, total) = self.send_paged(url, args, start, size)?;
+            // Deserialize straight off the socket rather than buffering the
+            // whole body as a string first.
+            Ok((self.parse(BufReader::new(resp))?, total))
+        })
+    }
+
+    /// Fetches what `get_paged` would for `request`, unparsed, so a listing
+    /// deferred to the background can be built from it later.
+    pub fn get_body(&self, request: &deferred::Request) -> Result<deferred::Body> {
+        retrying(&self.options, &request.url, || {
+            let _permit = self.scheduler.acquire();
+            let (mut resp, total) = self.send_paged(&request.url, &request.args, request.start, request.size)?;
+            let mut bytes = Vec::new();
+            resp.copy_to(&mut bytes)?;
+            Ok((bytes, total))
+        })
+    }
+
+    /// Sends a paged GET, returning the response and the container's total
+    /// size from its header.
+    fn send_paged(&self, url: &str, args: &str, start: u64, size: u64) -> Result<(reqwest::blocking::Response, u64)> {
         let args = format!("{}&X-Plex-Container-Start={}&X-Plex-Container-Size={}", args, start, size);
-        let full_url = format!("http://{}{}?X-Plex-Token={}{}", self.host, url, self.token, args);
-        let resp = reqwest::blocking::get(&full_url)?;
+        let full_url = format!("{}{}?X-Plex-Token={}{}", self.base_url, url, self.token, args);
+        let mut request = self.client.get(&full_url);
+        if self.capabilities.json {
+            request = request.header(ACCEPT, "application/json");
+        }
+        let resp = request.send()?.error_for_status()?;
         debug!("GET {}", full_url);
         let header_name = HeaderName::from_static("x-plex-container-total-size");
-        let page_size = resp.headers()
+        let total = resp.headers()
             .get(header_name)
             .map(|h| h.to_str().unwrap().parse::<u64>())
             .unwrap_or(Ok(0))?;
-        let result = from_str(&resp.text()?)?;
-        Ok((result, page_size))
+        Ok((resp, total))
+    }
+
+    /// Parses a response body as JSON or XML, whichever was asked for. A
+    /// body that doesn't parse is as much a failure of the server as a
+    /// broken connection.
+    fn parse<T, R>(&self, reader: R) -> Result<T>
+        where T: DeserializeOwned + FromJson,
+              R: Read
+    {
+        let result = match self.capabilities.json {
+            true => {
+                let mut body: Value = serde_json::from_reader(reader)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                T::from_json(body["MediaContainer"].take())?
+            }
+            false => from_reader(BufReader::new(reader))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        };
+        Ok(result)
+    }
+
+    /// Fetches `url` with Plex's JSON output instead of XML, unparsed.
+    fn get_json(&self, url: &str, args: &str) -> Result<String> {
+        self.require(self.capabilities.json, "JSON responses")?;
+        retrying(&self.options, url, || {
+            let _permit = self.scheduler.acquire();
+            let full_url = format!("{}{}?X-Plex-Token={}{}", self.base_url, url, self.token, args);
+            debug!("GET {}", full_url);
+            let resp = self.client.get(&full_url)
+                .header(ACCEPT, "application/json")
+                .send()?
+                .error_for_status()?;
+            Ok(resp.text()?)
+        })
     }
 
     fn get<T>(&self, url: &str, args: &str) -> Result<T>
-        where T: DeserializeOwned
+        where T: DeserializeOwned + FromJson
     {
         self.get_paged(url, args, 0, 100).map(|(resp, _)| resp)
     }
@@ -145,34 +1241,394 @@ impl PlexAPI {
     }
 
     pub fn all(&self, section: u64, kind: MediaKind, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        match kind {
+            // The top level of a photo section mixes albums and photos, which
+            // filtering by either type would split.
+            MediaKind::Photo => {
+                let url = format!("/library/sections/{}/all", section);
+                self.get_paged(&url, &self.listing_args()?, start, size)
+            }
+            kind => self.all_of_type(section, kind as u8, start, size)
+        }
+    }
+
+    pub fn all_of_type(&self, section: u64, type_id: u8, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
         let url = format!("/library/sections/{}/all", section);
-        let args = format!("&type={}", kind as u8);
+        let args = format!("&type={}{}", type_id, self.listing_args()?);
         self.get_paged(&url, &args, start, size)
     }
 
+    pub fn sections(&self) -> Result<Vec<Section>> {
+        let container: SectionContainer = self.get("/library/sections", "")?;
+        Ok(container.sections)
+    }
+
+    /// Looks up a section by key or title. See `find_section`.
+    pub fn section(&self, spec: &str) -> Result<Section> {
+        let sections = self.sections()?;
+        find_section(&sections, spec).map(Section::clone)
+    }
+
+    /// Number of top-level items in a section that pass `filters`, read
+    /// from the container size header without fetching any items.
+    pub fn section_size(&self, section: u64) -> Result<u64> {
+        let url = format!("/library/sections/{}/all", section);
+        let args = self.listing_args()?;
+        let request = deferred::Request {
+            url: url.clone(),
+            args: args.clone(),
+            start: 0,
+            size: 0,
+        };
+        if let Some(body) = deferred::answer(request) {
+            return body.map(|(_, total)| total);
+        }
+        let _permit = self.scheduler.acquire();
+        let (_, size) = self.send_paged(&url, &args, 0, 0)?;
+        Ok(size)
+    }
+
     pub fn metadata(&self, rating_key: u64) -> Result<MediaContainer> {
         let url = format!("/library/metadata/{}", rating_key);
         self.get(&url, "")
     }
 
+    pub fn metadata_json(&self, rating_key: u64) -> Result<String> {
+        let url = format!("/library/metadata/{}", rating_key);
+        self.get_json(&url, "")
+    }
+
     pub fn metadata_children(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
         let url = format!("/library/metadata/{}/children", rating_key);
         self.get_paged(&url, "&excludeAllLeaves=1&includeExternalMedia=1", start, size)
     }
 
+    /// Fetches a file the server gave the path of, such as an item's
+    /// `thumb`.
+    pub fn resource(&self, path: &str) -> Result<Vec<u8>> {
+        let separator = if path.contains('?') { '&' } else { '?' };
+        retrying(&self.options, path, || {
+            let _permit = self.scheduler.acquire();
+            let full_url = format!("{}{}{}X-Plex-Token={}", self.base_url, path, separator, self.token);
+            debug!("GET {}", full_url);
+            let mut resp = self.client.get(&full_url).send()?.error_for_status()?;
+            let mut body = vec![];
+            resp.copy_to(&mut body)?;
+            Ok(body)
+        })
+    }
+
+    /// An item's artwork from its `thumb`, scaled down by the server's photo
+    /// transcoder to fit `size`, width by height, if given.
+    pub fn artwork(&self, thumb: &str, size: Option<(u32, u32)>) -> Result<Vec<u8>> {
+        match size {
+            Some((width, height)) => {
+                let path = format!("/photo/:/transcode?width={}&height={}&minSize=1&upscale=0&url={}",
+                                   width, height, percent_encode(thumb));
+                self.resource(&path)
+            }
+            None => self.resource(thumb)
+        }
+    }
+
+    /// Asks the server to scan `section` for new and changed files, only
+    /// under `path` (as the server sees it) if given.
+    pub fn refresh_section(&self, section: u64, path: Option<&str>) -> Result<()> {
+        let _permit = self.scheduler.acquire();
+        let full_url = format!("{}/library/sections/{}/refresh?X-Plex-Token={}", self.base_url, section, self.token);
+        debug!("GET {} (path {:?})", full_url, path);
+        let mut req = self.client.get(&full_url);
+        if let Some(path) = path {
+            req = req.query(&[("path", path)]);
+        }
+        req.send()?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Subtitles in `language`, such as "en", that the server's providers
+    /// have for a video.
+    pub fn search_subtitles(&self, rating_key: u64, language: &str) -> Result<Vec<SubtitleResult>> {
+        let url = format!("/library/metadata/{}/subtitles", rating_key);
+        let args = format!("&language={}&hearingImpaired=0&forced=0", percent_encode(language));
+        let container: SubtitleContainer = self.get(&url, &args)?;
+        Ok(container.results)
+    }
+
+    /// Has the server download `subtitle` and add it to the video, beside
+    /// its file.
+    pub fn download_subtitle(&self, rating_key: u64, subtitle: &SubtitleResult) -> Result<()> {
+        let _permit = self.scheduler.acquire();
+        let full_url = format!("{}/library/metadata/{}/subtitles?X-Plex-Token={}", self.base_url, rating_key, self.token);
+        debug!("PUT {} (subtitle {})", full_url, subtitle.key);
+        let mut query = vec![("key", &subtitle.key[..]), ("hearingImpaired", "0"), ("forced", "0")];
+        let optional = [("codec", &subtitle.codec), ("language", &subtitle.language_code), ("providerTitle", &subtitle.provider_title)];
+        for (name, value) in optional.iter() {
+            if let Some(value) = value {
+                query.push((*name, &value[..]));
+            }
+        }
+        self.client.put(&full_url).query(&query).send()?.error_for_status()?;
+        Ok(())
+    }
+
+    /// A page of a section's collections. Their children are their members.
+    pub fn collections(&self, section: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let url = format!("/library/sections/{}/collections", section);
+        self.get_paged(&url, "", start, size)
+    }
+
+    /// A page of a collection's members. Unlike its metadata's children,
+    /// this lists those of smart collections, which are a saved filter
+    /// rather than a list, and collections nested in it.
+    pub fn collection_children(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let url = format!("/library/collections/{}/children", rating_key);
+        self.get_paged(&url, "&excludeAllLeaves=1", start, size)
+    }
+
+    /// The videos of a section that are partly watched, or are the next
+    /// episode of a show being watched.
+    pub fn on_deck(&self, section: u64) -> Result<MediaContainer> {
+        self.get(&format!("/library/sections/{}/onDeck", section), "")
+    }
+
+    /// The genres of a section's items.
+    pub fn genres(&self, section: u64) -> Result<Vec<Genre>> {
+        let container: GenreContainer = self.get(&format!("/library/sections/{}/genre", section), "")?;
+        Ok(container.genres)
+    }
+
+    /// A page of the items at the top of a section, such as artists, that
+    /// are in the genre with key `genre`.
+    pub fn genre_items(&self, section: u64, kind: MediaKind, genre: &str, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let url = format!("/library/sections/{}/all", section);
+        let filter = Filter { field: "genre".to_string(), value: genre.to_string() };
+        let args = format!("&type={}&{}{}", kind as u8, filter.to_query(), self.listing_args()?);
+        self.get_paged(&url, &args, start, size)
+    }
+
+    /// The first characters of the sort titles of the items at the top of
+    /// a section.
+    pub fn first_characters(&self, section: u64, kind: MediaKind) -> Result<Vec<FirstCharacter>> {
+        let url = format!("/library/sections/{}/firstCharacter", section);
+        let container: FirstCharacterContainer = self.get(&url, &format!("&type={}", kind as u8))?;
+        Ok(container.characters)
+    }
+
+    /// A page of the items at the top of a section whose sort titles start
+    /// with the character with key `character`.
+    pub fn first_character_items(&self, section: u64, kind: MediaKind, character: &str, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let url = format!("/library/sections/{}/firstCharacter/{}", section, percent_encode(character));
+        let args = format!("&type={}{}", kind as u8, self.listing_args()?);
+        self.get_paged(&url, &args, start, size)
+    }
+
+    /// Every audio playlist on the server.
+    pub fn playlists(&self) -> Result<Vec<Playlist>> {
+        let container: PlaylistContainer = self.get("/playlists", "&playlistType=audio")?;
+        Ok(container.playlists)
+    }
+
+    /// A playlist's items, in playlist order.
+    pub fn playlist_items(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let url = format!("/playlists/{}/items", rating_key);
+        self.get_paged(&url, "", start, size)
+    }
+
+    /// One level of the section's folder view, the top level if `parent` is
+    /// None. Subfolders are Directory items whose `key` gives their ID.
+    pub fn folder(&self, section: u64, parent: Option<u64>, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let url = format!("/library/sections/{}/folder", section);
+        let args = match parent {
+            Some(parent) => format!("&parent={}", parent),
+            None => String::new()
+        };
+        self.get_paged(&url, &args, start, size)
+    }
+
+    /// Every playable item below `rating_key`, however deeply nested.
+    pub fn metadata_leaves(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let url = format!("/library/metadata/{}/allLeaves", rating_key);
+        self.get_paged(&url, "", start, size)
+    }
+
+    /// Subscribes to the server's notifications, as a stream of server-sent
+    /// events. Doesn't take a request slot, since it stays open.
+    pub fn notifications(&self) -> Result<reqwest::blocking::Response> {
+        self.require(self.capabilities.notifications, "notifications")?;
+        let full_url = format!("{}/:/eventsource/notifications?X-Plex-Token={}&filters=timeline",
+                               self.base_url, self.token);
+        debug!("GET {}", full_url);
+        let resp = self.client.get(&full_url)
+            .header(ACCEPT, "text/event-stream")
+            .send()?
+            .error_for_status()?;
+        Ok(resp)
+    }
+
+    pub fn sessions_json(&self) -> Result<String> {
+        self.get_json("/status/sessions", "")
+    }
+
+    /// Reads `size` bytes of a part starting at `offset`. See
+    /// `read_resuming`.
     pub fn file(&self, part: &Part, offset: i64, size: u32) -> Result<Vec<u8>> {
-        let full_url = format!("http://{}{}?X-Plex-Token={}&X-Plex-Container-Start=0&X-Plex-Container-Size=100",
-                          self.host, part.key, self.token);
+        read_resuming(&self.options, part, offset, size, |offset, size, buf| self.file_range(part, offset, size, buf))
+    }
+
+    /// Starts a request for the rest of a part from `offset`, for reading
+    /// sequentially as the body arrives. The response holds one of the
+    /// `max_connections` until it's dropped. Returns None at the end of the
+    /// file, or when streams already hold their share of connections; a
+    /// range request tells which.
+    pub fn stream(&self, part: &Part, offset: u64) -> Result<Option<StreamBody>> {
+        retrying(&self.options, &part.key, || {
+            let permit = match self.scheduler.clone().try_acquire_stream() {
+                Some(permit) => permit,
+                None => return Ok(None)
+            };
+            let full_url = format!("{}{}?X-Plex-Token={}", self.base_url, part.key, self.token);
+            debug!("GET {} (streaming from {})", full_url, offset);
+            let resp = self.client.get(&full_url)
+                .header(RANGE, format!("bytes={}-", offset))
+                .send()?;
+            if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+                return Ok(None);
+            }
+            Ok(Some(StreamBody::new(resp.error_for_status()?, permit)))
+        })
+    }
+
+    /// Appends up to `size` bytes at `offset` to `buf`. Bytes received
+    /// before an error are kept.
+    fn file_range(&self, part: &Part, offset: i64, size: u32, buf: &mut Vec<u8>) -> Result<()> {
+        let _permit = self.scheduler.acquire();
+        let full_url = format!("{}{}?X-Plex-Token={}&X-Plex-Container-Start=0&X-Plex-Container-Size=100",
+                          self.base_url, part.key, self.token);
         debug!("GET {}", full_url);
-        let range = format!("bytes={}-{}", offset, offset + size as i64);
-        let client = reqwest::blocking::Client::new();
+        let range = format!("bytes={}-{}", offset, offset + size as i64 - 1);
         let mut headers = HeaderMap::new();
         headers.insert(RANGE, HeaderValue::from_str(&range).unwrap());
-        let mut resp = client.get(&full_url)
+        let resp = self.client.get(&full_url)
             .headers(headers)
             .send()?;
-        let mut buf = vec![];
-        resp.read_to_end(&mut buf)?;
-        Ok(buf)
+        if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(());
+        }
+        let resp = resp.error_for_status()?;
+        resp.take(size as u64).read_to_end(buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `/library/sections/3/all` of a photo and music library, as served
+    /// with `Accept: application/json`, cut down to a few items.
+    const LISTING: &str = r#"{"MediaContainer": {"size": 5, "totalSize": 5, "offset": 0, "allowSync": true, "librarySectionID": 3, "Metadata": [
+        {"ratingKey": "1001", "key": "/library/metadata/1001", "parentRatingKey": "1000", "grandparentRatingKey": "999",
+         "guid": "plex://track/5d07cdd5403c640290f6b3d2", "type": "track", "title": "Song", "grandparentTitle": "Band",
+         "parentTitle": "Album", "summary": "", "index": 3, "parentIndex": 1, "parentYear": 1999, "ratingCount": 12,
+         "thumb": "/library/metadata/1000/thumb/1600000000", "duration": 215000, "addedAt": 1600000000, "updatedAt": 1600000100,
+         "Media": [{"id": 2001, "duration": 215000, "bitrate": 1000, "audioChannels": 2, "audioCodec": "flac", "container": "flac",
+                    "Part": [{"id": 3001, "key": "/library/parts/3001/1600000000/file.flac", "duration": 215000,
+                              "file": "/music/Band/Album/03 Song.flac", "size": 27000000, "container": "flac"}]}]},
+        {"ratingKey": "1000", "key": "/library/metadata/1000/children", "parentRatingKey": "999", "guid": "plex://album/5d07c17b403c640290869f1b",
+         "type": "album", "title": "Album", "parentTitle": "Band", "year": 1999, "originallyAvailableAt": "1999-04-01",
+         "thumb": "/library/metadata/1000/thumb/1600000000", "leafCount": 10, "viewedLeafCount": 0,
+         "addedAt": 1600000000, "updatedAt": 1600000100, "Genre": [{"tag": "Rock"}, {"tag": "Pop"}]},
+        {"ratingKey": "1100", "key": "/library/metadata/1100", "guid": "com.plexapp.agents.none://1100", "type": "photo",
+         "title": "IMG_0001", "originallyAvailableAt": "2019-07-14", "addedAt": 1600000200, "updatedAt": 1600000200,
+         "Media": [{"id": 2100, "width": 4032, "height": 3024, "aspectRatio": 1.33, "container": "jpeg",
+                    "Part": [{"id": 3100, "key": "/library/parts/3100/1600000200/file.jpg",
+                              "file": "/photos/2019/IMG_0001.jpg", "size": 3500000, "container": "jpeg"}]}]},
+        {"ratingKey": "1200", "key": "/library/metadata/1200", "guid": "plex://movie/5d776b59ad5437001f79c6f8", "type": "movie",
+         "title": "Unavailable", "year": 2001, "addedAt": 1600000300, "updatedAt": 1600000300},
+        {"ratingKey": "1300", "type": "track", "Media": []}
+    ]}}"#;
+
+    fn listing() -> MediaContainer {
+        let mut body: Value = serde_json::from_str(LISTING).unwrap();
+        MediaContainer::from_json(body["MediaContainer"].take()).unwrap()
+    }
+
+    #[test]
+    fn track() {
+        match &listing().items[0] {
+            Item::Track { rating_key, title, index, parent_index, parent_rating_key, parent_title, parent_year,
+                          grandparent_title, grandparent_rating_key, added_at, media, .. } => {
+                assert_eq!(*rating_key, 1001);
+                assert_eq!(title, "Song");
+                assert_eq!((*index, *parent_index), (Some(3), Some(1)));
+                assert_eq!((*parent_rating_key, &parent_title[..], *parent_year), (1000, "Album", Some(1999)));
+                assert_eq!((&grandparent_title[..], *grandparent_rating_key), ("Band", 999));
+                assert_eq!(*added_at, 1600000000);
+                assert_eq!(media.len(), 1);
+                assert_eq!(media[0].audio_codec.as_deref(), Some("flac"));
+                assert_eq!(media[0].part.key, "/library/parts/3001/1600000000/file.flac");
+                assert_eq!(media[0].part.file, "/music/Band/Album/03 Song.flac");
+                assert_eq!(media[0].part.size, 27000000);
+            }
+            item => panic!("not a track: {:?}", item)
+        }
+    }
+
+    #[test]
+    fn album() {
+        match &listing().items[1] {
+            Item::Directory { rating_key, kind, title, parent_title, parent_rating_key, year,
+                              originally_available_at, leaf_count, genres, .. } => {
+                assert_eq!(*rating_key, 1000);
+                assert_eq!(kind, "album");
+                assert_eq!((&title[..], &parent_title[..], *parent_rating_key), ("Album", "Band", 999));
+                assert_eq!(*year, Some(1999));
+                assert_eq!(originally_available_at.as_deref(), Some("1999-04-01"));
+                assert_eq!(*leaf_count, 10);
+                let genres: Vec<&str> = genres.iter().map(|g| &g.tag[..]).collect();
+                assert_eq!(genres, vec!["Rock", "Pop"]);
+            }
+            item => panic!("not a directory: {:?}", item)
+        }
+    }
+
+    #[test]
+    fn photo() {
+        match &listing().items[2] {
+            Item::Photo { rating_key, title, originally_available_at, media, .. } => {
+                assert_eq!(*rating_key, 1100);
+                assert_eq!(title, "IMG_0001");
+                assert_eq!(originally_available_at.as_deref(), Some("2019-07-14"));
+                assert_eq!(media[0].height, Some(3024));
+                assert_eq!(media[0].part.file, "/photos/2019/IMG_0001.jpg");
+                assert_eq!(media[0].part.container.as_deref(), Some("jpeg"));
+            }
+            item => panic!("not a photo: {:?}", item)
+        }
+    }
+
+    #[test]
+    fn item_without_media_is_a_directory() {
+        match &listing().items[3] {
+            Item::Directory { rating_key, kind, title, .. } => {
+                assert_eq!(*rating_key, 1200);
+                assert_eq!((&kind[..], &title[..]), ("movie", "Unavailable"));
+            }
+            item => panic!("not a directory: {:?}", item)
+        }
+    }
+
+    #[test]
+    fn skips_items_that_dont_parse() {
+        // The last item has no title.
+        let items = listing().items;
+        assert_eq!(items.len(), 4);
+        assert!(items.iter().all(|item| item.rating_key() != 1300));
+    }
+
+    #[test]
+    fn empty_listing() {
+        let container = MediaContainer::from_json(serde_json::json!({"size": 0})).unwrap();
+        assert!(container.items.is_empty());
     }
 }
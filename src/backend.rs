@@ -0,0 +1,260 @@
+//! What the filesystem needs from a media server, so servers other than
+//! Plex can be mounted. Listings use Plex's model: other backends describe
+//! their items as `MediaContainer`s of `Item`s.
+
+use anyhow::Result;
+use reqwest::blocking::Response;
+
+use super::deferred;
+use super::api::{FirstCharacter, Genre, MediaContainer, MediaKind, Part, Playlist, PlexAPI, Section, StreamBody, SubtitleResult};
+
+/// Which kind of server to talk to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    Plex,
+    /// Jellyfin, or Emby, which it was forked from.
+    Jellyfin,
+}
+
+impl BackendKind {
+    pub const NAMES: &'static [&'static str] = &["plex", "jellyfin", "emby"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "plex" => Some(BackendKind::Plex),
+            "jellyfin" | "emby" => Some(BackendKind::Jellyfin),
+            _ => None
+        }
+    }
+}
+
+impl Default for BackendKind {
+    fn default() -> Self {
+        BackendKind::Plex
+    }
+}
+
+/// A media server. Items are identified by rating keys, which backends
+/// without numeric IDs allocate themselves.
+pub trait MediaBackend: Send + Sync {
+    /// Identifies the server in cache keys.
+    fn base_url(&self) -> &str;
+
+    /// The cap on concurrent requests. Callers fanning work out over
+    /// threads gain nothing from running more than this.
+    fn max_connections(&self) -> usize;
+
+    fn sections(&self) -> Result<Vec<Section>>;
+
+    /// Number of top-level items in a section.
+    fn section_size(&self, section: u64) -> Result<u64>;
+
+    /// A page of the top-level items of a section, and the total.
+    fn all(&self, section: u64, kind: MediaKind, start: u64, size: u64) -> Result<(MediaContainer, u64)>;
+
+    /// A page of a section's items of a Plex metadata type, at any depth.
+    fn all_of_type(&self, section: u64, type_id: u8, start: u64, size: u64) -> Result<(MediaContainer, u64)>;
+
+    fn metadata(&self, rating_key: u64) -> Result<MediaContainer>;
+
+    /// An item's full metadata as the server describes it.
+    fn metadata_json(&self, rating_key: u64) -> Result<String>;
+
+    fn metadata_children(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)>;
+
+    /// The playable items anywhere below an item.
+    fn metadata_leaves(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)>;
+
+    /// A folder of a section as it is on disk; the section's top folders if
+    /// `parent` is None.
+    fn folder(&self, section: u64, parent: Option<u64>, start: u64, size: u64) -> Result<(MediaContainer, u64)>;
+
+    /// A page of a section's collections, as directory items whose
+    /// children are their members.
+    fn collections(&self, section: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)>;
+
+    /// A page of a collection's members, smart and nested collections
+    /// included.
+    fn collection_children(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)>;
+
+    fn genres(&self, section: u64) -> Result<Vec<Genre>>;
+
+    /// A page of the items at the top of a section of `kind` that are in
+    /// a genre.
+    fn genre_items(&self, section: u64, kind: MediaKind, genre: &str, start: u64, size: u64) -> Result<(MediaContainer, u64)>;
+
+    /// The first characters of the sort titles of the items at the top of
+    /// a section, such as its artists.
+    fn first_characters(&self, section: u64, kind: MediaKind) -> Result<Vec<FirstCharacter>>;
+
+    /// A page of the items at the top of a section whose sort titles start
+    /// with a character from `first_characters`.
+    fn first_character_items(&self, section: u64, kind: MediaKind, character: &str, start: u64, size: u64) -> Result<(MediaContainer, u64)>;
+
+    fn playlists(&self) -> Result<Vec<Playlist>>;
+
+    /// A playlist's items, in playlist order.
+    fn playlist_items(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)>;
+
+    /// What was last added to sections of `kind`, newest first.
+    fn recently_added(&self, kind: MediaKind) -> Result<MediaContainer>;
+
+    /// A video section's partly watched videos and next episodes, most
+    /// recently watched first.
+    fn on_deck(&self, section: u64) -> Result<MediaContainer>;
+
+    /// Subtitles in `language` the server's providers have for a video.
+    fn search_subtitles(&self, rating_key: u64, language: &str) -> Result<Vec<SubtitleResult>>;
+
+    /// Has the server download a subtitle `search_subtitles` found and add
+    /// it to the video.
+    fn download_subtitle(&self, rating_key: u64, subtitle: &SubtitleResult) -> Result<()>;
+
+    /// Current playback sessions, as JSON.
+    fn sessions_json(&self) -> Result<String>;
+
+    /// A stream of changes to the library as server-sent events.
+    fn notifications(&self) -> Result<Response>;
+
+    /// Fetches a file the server gave the path of, such as an item's
+    /// `thumb`.
+    fn resource(&self, path: &str) -> Result<Vec<u8>>;
+
+    /// An item's artwork from its `thumb`, scaled down to fit `size`, width
+    /// by height, if given.
+    fn artwork(&self, thumb: &str, size: Option<(u32, u32)>) -> Result<Vec<u8>>;
+
+    /// Reads up to `size` bytes of a part at `offset`, returning fewer only
+    /// at the end of the file.
+    fn file(&self, part: &Part, offset: i64, size: u32) -> Result<Vec<u8>>;
+
+    /// Starts a request for the rest of a part from `offset`. None at the
+    /// end of the file, or when no connection can be spared for it.
+    fn stream(&self, part: &Part, offset: u64) -> Result<Option<StreamBody>>;
+
+    /// Fetches a response a listing built in the background asked for. See
+    /// `deferred`.
+    fn fetch_body(&self, request: &deferred::Request) -> Result<deferred::Body>;
+}
+
+impl MediaBackend for PlexAPI {
+    fn base_url(&self) -> &str {
+        PlexAPI::base_url(self)
+    }
+
+    fn max_connections(&self) -> usize {
+        PlexAPI::max_connections(self)
+    }
+
+    fn sections(&self) -> Result<Vec<Section>> {
+        PlexAPI::sections(self)
+    }
+
+    fn section_size(&self, section: u64) -> Result<u64> {
+        PlexAPI::section_size(self, section)
+    }
+
+    fn all(&self, section: u64, kind: MediaKind, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        PlexAPI::all(self, section, kind, start, size)
+    }
+
+    fn all_of_type(&self, section: u64, type_id: u8, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        PlexAPI::all_of_type(self, section, type_id, start, size)
+    }
+
+    fn metadata(&self, rating_key: u64) -> Result<MediaContainer> {
+        PlexAPI::metadata(self, rating_key)
+    }
+
+    fn metadata_json(&self, rating_key: u64) -> Result<String> {
+        PlexAPI::metadata_json(self, rating_key)
+    }
+
+    fn metadata_children(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        PlexAPI::metadata_children(self, rating_key, start, size)
+    }
+
+    fn metadata_leaves(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        PlexAPI::metadata_leaves(self, rating_key, start, size)
+    }
+
+    fn folder(&self, section: u64, parent: Option<u64>, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        PlexAPI::folder(self, section, parent, start, size)
+    }
+
+    fn collections(&self, section: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        PlexAPI::collections(self, section, start, size)
+    }
+
+    fn collection_children(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        PlexAPI::collection_children(self, rating_key, start, size)
+    }
+
+    fn genres(&self, section: u64) -> Result<Vec<Genre>> {
+        PlexAPI::genres(self, section)
+    }
+
+    fn genre_items(&self, section: u64, kind: MediaKind, genre: &str, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        PlexAPI::genre_items(self, section, kind, genre, start, size)
+    }
+
+    fn first_characters(&self, section: u64, kind: MediaKind) -> Result<Vec<FirstCharacter>> {
+        PlexAPI::first_characters(self, section, kind)
+    }
+
+    fn first_character_items(&self, section: u64, kind: MediaKind, character: &str, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        PlexAPI::first_character_items(self, section, kind, character, start, size)
+    }
+
+    fn playlists(&self) -> Result<Vec<Playlist>> {
+        PlexAPI::playlists(self)
+    }
+
+    fn playlist_items(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        PlexAPI::playlist_items(self, rating_key, start, size)
+    }
+
+    fn recently_added(&self, kind: MediaKind) -> Result<MediaContainer> {
+        PlexAPI::recently_added(self, kind)
+    }
+
+    fn on_deck(&self, section: u64) -> Result<MediaContainer> {
+        PlexAPI::on_deck(self, section)
+    }
+
+    fn search_subtitles(&self, rating_key: u64, language: &str) -> Result<Vec<SubtitleResult>> {
+        PlexAPI::search_subtitles(self, rating_key, language)
+    }
+
+    fn download_subtitle(&self, rating_key: u64, subtitle: &SubtitleResult) -> Result<()> {
+        PlexAPI::download_subtitle(self, rating_key, subtitle)
+    }
+
+    fn sessions_json(&self) -> Result<String> {
+        PlexAPI::sessions_json(self)
+    }
+
+    fn notifications(&self) -> Result<Response> {
+        PlexAPI::notifications(self)
+    }
+
+    fn resource(&self, path: &str) -> Result<Vec<u8>> {
+        PlexAPI::resource(self, path)
+    }
+
+    fn artwork(&self, thumb: &str, size: Option<(u32, u32)>) -> Result<Vec<u8>> {
+        PlexAPI::artwork(self, thumb, size)
+    }
+
+    fn file(&self, part: &Part, offset: i64, size: u32) -> Result<Vec<u8>> {
+        PlexAPI::file(self, part, offset, size)
+    }
+
+    fn stream(&self, part: &Part, offset: u64) -> Result<Option<StreamBody>> {
+        PlexAPI::stream(self, part, offset)
+    }
+
+    fn fetch_body(&self, request: &deferred::Request) -> Result<deferred::Body> {
+        self.get_body(request)
+    }
+}
@@ -0,0 +1,268 @@
+//! File data kept on disk between reads and mounts, in fixed-size chunks,
+//! evicting the least recently used once over a size budget.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::UNIX_EPOCH;
+use anyhow::{Context, Result};
+
+/// Chunks start at multiples of this, and are this long except at the end
+/// of a file.
+pub const CHUNK_SIZE: u64 = 1024 * 1024;
+
+pub const DEFAULT_CACHE_SIZE: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, Default)]
+struct State {
+    /// Size and last use of each chunk file, by path relative to the cache
+    /// directory.
+    chunks: HashMap<PathBuf, (u64, u64)>,
+    total: u64,
+    /// Incremented on every use, ordering chunks by recency.
+    clock: u64,
+}
+
+#[derive(Debug)]
+pub struct ChunkCache {
+    dir: PathBuf,
+    max_size: u64,
+    state: Mutex<State>,
+    /// Numbers temporary files, so writers of the same chunk don't share one.
+    writes: AtomicU64,
+}
+
+/// 64-bit FNV-1a, so chunk paths are the same across builds and runs.
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+impl ChunkCache {
+    /// Opens the cache in `dir`, creating it if needed. Chunks already there
+    /// are kept, oldest written treated as least recently used.
+    pub fn open(dir: &Path, max_size: u64) -> Result<ChunkCache> {
+        fs::create_dir_all(dir).with_context(|| format!("creating cache directory {}", dir.display()))?;
+
+        let mut found = vec![];
+        for file_dir in fs::read_dir(dir)? {
+            let file_dir = file_dir?;
+            if !file_dir.file_type()?.is_dir() {
+                continue;
+            }
+            for chunk in fs::read_dir(file_dir.path())? {
+                let chunk = chunk?;
+                if chunk.path().extension().map_or(false, |ext| ext == "tmp") {
+                    // Left by a write that was interrupted.
+                    let _ = fs::remove_file(chunk.path());
+                    continue;
+                }
+                let meta = chunk.metadata()?;
+                let written = meta.modified().ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                let path = PathBuf::from(file_dir.file_name()).join(chunk.file_name());
+                found.push((written, path, meta.len()));
+            }
+        }
+        found.sort();
+
+        let mut state = State::default();
+        for (_, path, size) in found {
+            state.clock += 1;
+            state.total += size;
+            state.chunks.insert(path, (size, state.clock));
+        }
+        debug!("cache {}: {} chunks, {} bytes", dir.display(), state.chunks.len(), state.total);
+
+        let cache = ChunkCache {
+            dir: dir.to_owned(),
+            max_size: max_size,
+            state: Mutex::new(state),
+            writes: AtomicU64::new(0),
+        };
+        cache.evict(&mut cache.state.lock().unwrap());
+        Ok(cache)
+    }
+
+    /// Where chunk `index` of the file identified by `file` is kept.
+    /// `file` must change whenever the file's content does.
+    fn path(file: &str, index: u64) -> PathBuf {
+        PathBuf::from(format!("{:016x}", fnv1a(file))).join(index.to_string())
+    }
+
+    /// The length chunk `index` of a file of `file_size` bytes has: the
+    /// whole chunk size, but for the last chunk of a file whose size is
+    /// known.
+    pub fn chunk_len(file_size: u64, index: u64) -> u64 {
+        let start = index * CHUNK_SIZE;
+        match file_size {
+            0 => CHUNK_SIZE,
+            size => cmp::min(CHUNK_SIZE, size.saturating_sub(start))
+        }
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Whether chunk `index` of `file` is cached, without reading it.
+    pub fn contains(&self, file: &str, index: u64) -> bool {
+        self.state.lock().unwrap().chunks.contains_key(&ChunkCache::path(file, index))
+    }
+
+    /// Chunk `index` of a file of `file_size` bytes, if it's cached whole.
+    pub fn get(&self, file: &str, index: u64, file_size: u64) -> Option<Vec<u8>> {
+        let path = ChunkCache::path(file, index);
+        {
+            let mut state = self.state.lock().unwrap();
+            state.clock += 1;
+            let clock = state.clock;
+            state.chunks.get_mut(&path)?.1 = clock;
+        }
+        match fs::read(self.dir.join(&path)) {
+            Ok(data) if data.len() as u64 == ChunkCache::chunk_len(file_size, index) => Some(data),
+            Ok(data) => {
+                warn!("dropping cached chunk {}: {} bytes, expected {}", path.display(), data.len(),
+                      ChunkCache::chunk_len(file_size, index));
+                self.remove(&path);
+                None
+            }
+            Err(e) => {
+                warn!("reading cached chunk {}: {}", path.display(), e);
+                self.forget(&path);
+                None
+            }
+        }
+    }
+
+    pub fn put(&self, file: &str, index: u64, data: &[u8]) {
+        let path = ChunkCache::path(file, index);
+        if let Err(e) = self.write(&path, data) {
+            warn!("caching chunk {}: {}", path.display(), e);
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        if let Some((old, _)) = state.chunks.insert(path, (data.len() as u64, clock)) {
+            state.total -= old;
+        }
+        state.total += data.len() as u64;
+        self.evict(&mut state);
+    }
+
+    /// Writes to a temporary name first, so a chunk is never seen half
+    /// written.
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let full = self.dir.join(path);
+        if let Some(parent) = full.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let n = self.writes.fetch_add(1, Ordering::Relaxed);
+        let tmp = full.with_extension(format!("{}.{}.tmp", process::id(), n));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &full)
+    }
+
+    fn remove(&self, path: &Path) {
+        self.forget(path);
+        if let Err(e) = fs::remove_file(self.dir.join(path)) {
+            warn!("removing cached chunk {}: {}", path.display(), e);
+        }
+    }
+
+    fn forget(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((size, _)) = state.chunks.remove(path) {
+            state.total -= size;
+        }
+    }
+
+    fn evict(&self, state: &mut State) {
+        while state.total > self.max_size {
+            let oldest = match state.chunks.iter().min_by_key(|(_, (_, used))| *used) {
+                Some((path, _)) => path.clone(),
+                None => break
+            };
+            let (size, _) = state.chunks.remove(&oldest).unwrap();
+            state.total -= size;
+            if let Err(e) = fs::remove_file(self.dir.join(&oldest)) {
+                warn!("evicting cached chunk {}: {}", oldest.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// An empty directory for a test's cache.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("plexfs-cache-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = temp_dir("evict");
+        let cache = ChunkCache::open(&dir, 30).unwrap();
+        cache.put("a", 0, &[1; 10]);
+        cache.put("b", 0, &[2; 10]);
+        cache.put("c", 0, &[3; 10]);
+        assert_eq!(cache.get("a", 0, 10), Some(vec![1; 10]));
+
+        cache.put("d", 0, &[4; 10]);
+        assert!(cache.contains("a", 0));
+        assert!(!cache.contains("b", 0));
+        assert!(cache.contains("c", 0));
+        assert!(cache.contains("d", 0));
+        assert!(!dir.join(ChunkCache::path("b", 0)).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopening_evicts_oldest_written() {
+        let dir = temp_dir("reopen");
+        {
+            let cache = ChunkCache::open(&dir, 30).unwrap();
+            cache.put("a", 0, &[1; 10]);
+            cache.put("a", 1, &[2; 10]);
+        }
+        let cache = ChunkCache::open(&dir, 10).unwrap();
+        assert_eq!(cache.state.lock().unwrap().total, 10);
+        // Written in the same second, chunks go by path.
+        assert!(!cache.contains("a", 0));
+        assert!(cache.contains("a", 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_truncated_chunks() {
+        let dir = temp_dir("truncated");
+        let cache = ChunkCache::open(&dir, DEFAULT_CACHE_SIZE).unwrap();
+        // Chunk 0 of a 10 byte file should have all 10.
+        cache.put("a", 0, &[1; 5]);
+        assert_eq!(cache.get("a", 0, 10), None);
+        assert!(!cache.contains("a", 0));
+        assert!(!dir.join(ChunkCache::path("a", 0)).exists());
+        assert_eq!(cache.state.lock().unwrap().total, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn last_chunk_is_short() {
+        assert_eq!(ChunkCache::chunk_len(CHUNK_SIZE + 10, 0), CHUNK_SIZE);
+        assert_eq!(ChunkCache::chunk_len(CHUNK_SIZE + 10, 1), 10);
+        assert_eq!(ChunkCache::chunk_len(0, 5), CHUNK_SIZE);
+    }
+}
@@ -0,0 +1,105 @@
+use std::ffi::OsString;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::{anyhow, Result};
+use fuse::FileType;
+use time::OffsetDateTime;
+
+use crate::fs::PlexFS;
+
+const HELP: &str = "\
+  <n>       enter directory n, or show file n's details
+  ..        go up
+  i <n>     show entry n's Plex metadata
+  o <n>     open entry n from the mount with xdg-open
+  p <n>     pin entry n, storing its files in the cache for offline use
+  q         quit";
+
+/// Navigates the tree interactively on the terminal.
+pub fn run(fs: &mut PlexFS, mountpoint: Option<&Path>) -> Result<()> {
+    let mut stack: Vec<(OsString, u64)> = vec![];
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        let ino = stack.last().map(|(_, ino)| *ino).unwrap_or(crate::fs::INO_ROOT);
+        let path: PathBuf = Path::new("/").join(stack.iter().map(|(name, _)| name).collect::<PathBuf>());
+        let children: Vec<_> = fs.children(ino)
+            .into_iter()
+            .filter(|(name, _, _)| !name.to_string_lossy().starts_with('.'))
+            .collect();
+
+        println!("\n{}", path.display());
+        for (i, (name, _, kind)) in children.iter().enumerate() {
+            let slash = match kind {
+                FileType::Directory => "/",
+                _ => ""
+            };
+            println!("{:>4}  {}{}", i + 1, name.to_string_lossy(), slash);
+        }
+
+        print!("> ");
+        io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(())
+        };
+        let mut words = line.split_whitespace();
+        let (command, arg) = match (words.next(), words.next()) {
+            (Some(n), None) if n.parse::<usize>().is_ok() => ("", Some(n)),
+            (Some(command), arg) => (command, arg),
+            (None, _) => continue
+        };
+        let entry = arg.and_then(|n| n.parse::<usize>().ok())
+            .and_then(|n| n.checked_sub(1))
+            .and_then(|n| children.get(n));
+
+        let result = match (command, entry) {
+            ("q", _) => return Ok(()),
+            ("..", _) => {
+                stack.pop();
+                Ok(())
+            }
+            ("", Some((name, child, FileType::Directory))) => {
+                stack.push((name.clone(), *child));
+                Ok(())
+            }
+            ("", Some((_, child, _))) => details(fs, *child),
+            ("i", Some((_, child, _))) => fs.metadata_json(*child).map(|json| println!("{}", json)),
+            ("o", Some((name, _, _))) => open(mountpoint, &path.join(name)),
+            ("p", Some((_, child, _))) => pin(fs, *child),
+            _ => {
+                println!("{}", HELP);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            println!("error: {:#}", e);
+        }
+    }
+}
+
+fn details(fs: &PlexFS, ino: u64) -> Result<()> {
+    let attr = fs.attr(ino)?;
+    let mtime = OffsetDateTime::from(attr.mtime);
+    println!("size {}, modified {}", attr.size, mtime.format("%Y-%m-%d %H:%M"));
+    Ok(())
+}
+
+fn pin(fs: &mut PlexFS, ino: u64) -> Result<()> {
+    let stored = fs.pin(ino)?;
+    match fs.has_cache() {
+        true => println!("pinned, {} file(s) stored in the cache", stored),
+        false => println!("pinned; pass --cache-dir to store its files for offline use")
+    }
+    Ok(())
+}
+
+fn open(mountpoint: Option<&Path>, path: &Path) -> Result<()> {
+    let mountpoint = mountpoint
+        .ok_or_else(|| anyhow!("pass --mountpoint to open files from a mount"))?;
+    let full = mountpoint.join(path.strip_prefix("/").unwrap_or(path));
+    Command::new("xdg-open").arg(&full).spawn()?;
+    Ok(())
+}
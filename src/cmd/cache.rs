@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use fuse::FileType;
+
+use crate::fs::PlexFS;
+
+/// Fills the chunk cache from `local`, a copy of `path` in the mount, so
+/// the files in it are never downloaded. Each file is matched to the one
+/// at the same path in the copy if that's the same size, or failing that
+/// to one anywhere in the copy with the same name and size.
+pub fn seed(fs: &mut PlexFS, path: &Path, local: &Path) -> Result<()> {
+    if !fs.has_cache() {
+        return Err(anyhow!("no cache to seed; pass --cache-dir or set cache-dir in a profile"));
+    }
+    let (ino, kind) = fs.resolve(path)
+        .ok_or_else(|| anyhow!("{}: no such file or directory", path.display()))?;
+
+    let mut by_name = HashMap::new();
+    index(local, &mut by_name)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut seeder = Seeder { fs: fs, out: &mut out, by_name: by_name, seeded: 0, missing: 0 };
+    match kind {
+        FileType::Directory => seeder.dir(ino, local, Path::new(""))?,
+        _ => seeder.file(ino, local, Path::new(path.file_name().unwrap_or_default()))?
+    }
+    writeln!(out, "{} file(s) seeded, {} not found locally", seeder.seeded, seeder.missing)?;
+    Ok(())
+}
+
+/// Records every file under `dir` by name and size.
+fn index(dir: &Path, by_name: &mut HashMap<(OsString, u64), PathBuf>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') {
+            continue
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            index(&entry.path(), by_name)?;
+        } else {
+            by_name.entry((name, metadata.len())).or_insert_with(|| entry.path());
+        }
+    }
+    Ok(())
+}
+
+struct Seeder<'a, W: Write> {
+    fs: &'a mut PlexFS,
+    out: &'a mut W,
+    by_name: HashMap<(OsString, u64), PathBuf>,
+    seeded: usize,
+    missing: usize,
+}
+
+impl<'a, W: Write> Seeder<'a, W> {
+    fn dir(&mut self, ino: u64, local: &Path, path: &Path) -> Result<()> {
+        for (name, child, kind) in self.fs.children(ino) {
+            // Artwork and other generated files aren't on the server.
+            if name.to_string_lossy().starts_with('.') || self.fs.is_generated(child) {
+                continue
+            }
+            let (local, path) = (local.join(&name), path.join(&name));
+            match kind {
+                FileType::Directory => self.dir(child, &local, &path)?,
+                _ => self.file(child, &local, &path)?
+            }
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, ino: u64, local: &Path, path: &Path) -> Result<()> {
+        let size = match self.fs.attr(ino)?.size {
+            // Without a size there's nothing to match the copy against.
+            0 => return Ok(()),
+            size => size
+        };
+        let found = match fs::metadata(local) {
+            Ok(metadata) if metadata.len() == size => Some(local.to_owned()),
+            _ => path.file_name()
+                .and_then(|name| self.by_name.get(&(name.to_owned(), size)))
+                .cloned()
+        };
+        let found = match found {
+            Some(found) => found,
+            None => {
+                self.missing += 1;
+                return Ok(());
+            }
+        };
+
+        if self.fs.seed_file(ino, &found)? {
+            self.seeded += 1;
+            writeln!(self.out, "{} <- {}", path.display(), found.display())?;
+        } else {
+            self.missing += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use std::cmp;
+use std::io::{self, Write};
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use fuse::FileType;
+
+use crate::fs::PlexFS;
+
+const CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// Parses an HTTP-style inclusive byte range, `START-END` or `START-`.
+pub fn parse_range(range: &str) -> Result<(u64, Option<u64>)> {
+    let mut parts = range.splitn(2, '-');
+    let start = parts.next().unwrap_or("").parse()
+        .map_err(|_| anyhow!("invalid range \"{}\"", range))?;
+    let end = match parts.next() {
+        Some("") | None => None,
+        Some(end) => Some(end.parse().map_err(|_| anyhow!("invalid range \"{}\"", range))?)
+    };
+    match end {
+        Some(end) if end < start => Err(anyhow!("invalid range \"{}\"", range)),
+        _ => Ok((start, end))
+    }
+}
+
+/// Streams a file to stdout through the same open/read path as the mount.
+pub fn run(fs: &mut PlexFS, path: &Path, range: Option<(u64, Option<u64>)>) -> Result<()> {
+    let (ino, kind) = fs.resolve(path)
+        .ok_or_else(|| anyhow!("{}: no such file or directory", path.display()))?;
+    if let FileType::Directory = kind {
+        return Err(anyhow!("{}: is a directory", path.display()));
+    }
+
+    let (mut offset, end) = range.unwrap_or((0, None));
+    let (fh, _) = fs.open_file(ino)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let result = (|| -> Result<()> {
+        loop {
+            let size = match end {
+                Some(end) if offset > end => break,
+                Some(end) => cmp::min(end + 1 - offset, CHUNK_SIZE as u64) as u32,
+                None => CHUNK_SIZE
+            };
+            let body = fs.read_file(ino, fh, offset, size)?;
+            if body.is_empty() {
+                break;
+            }
+            out.write_all(&body)?;
+            offset += body.len() as u64;
+        }
+        out.flush()?;
+        Ok(())
+    })();
+
+    fs.release_file(fh);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("0-99").unwrap(), (0, Some(99)));
+        assert_eq!(parse_range("100-").unwrap(), (100, None));
+        assert_eq!(parse_range("5").unwrap(), (5, None));
+        assert_eq!(parse_range("7-7").unwrap(), (7, Some(7)));
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        assert!(parse_range("").is_err());
+        assert!(parse_range("-100").is_err());
+        assert!(parse_range("10-5").is_err());
+        assert!(parse_range("a-b").is_err());
+        assert!(parse_range("1-2-3").is_err());
+    }
+}
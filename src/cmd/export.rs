@@ -0,0 +1,210 @@
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use anyhow::{anyhow, Context, Result};
+use fuse::FileType;
+use tar::{Builder, EntryType, Header};
+use time::OffsetDateTime;
+use zip::{CompressionMethod, DateTime, ZipWriter};
+use zip::write::FileOptions;
+
+use crate::fs::PlexFS;
+
+const CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// Reads a file through the same open/read path as the mount.
+struct FileReader<'a> {
+    fs: &'a mut PlexFS,
+    ino: u64,
+    fh: u64,
+    offset: u64,
+}
+
+impl<'a> Read for FileReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = cmp::min(buf.len(), CHUNK_SIZE as usize) as u32;
+        let body = self.fs.read_file(self.ino, self.fh, self.offset, size)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:#}", e)))?;
+        buf[..body.len()].copy_from_slice(&body);
+        self.offset += body.len() as u64;
+        Ok(body.len())
+    }
+}
+
+/// Reads exactly `remaining` bytes, failing instead of ending early if the
+/// file turns out shorter than its header said.
+struct Exact<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for Exact<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, self.remaining) as usize;
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      format!("file ended {} bytes short", self.remaining)));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+pub enum Format {
+    Tar,
+    Zip,
+}
+
+impl Format {
+    pub const NAMES: &'static [&'static str] = &["tar", "zip"];
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "tar" => Some(Format::Tar),
+            "zip" => Some(Format::Zip),
+            _ => None
+        }
+    }
+
+    /// The format implied by `output`'s extension.
+    pub fn from_output(output: &str) -> Format {
+        match Path::new(output).extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("zip") => Format::Zip,
+            _ => Format::Tar
+        }
+    }
+}
+
+enum Archive {
+    Tar(Builder<Box<dyn Write>>),
+    // Zip's central directory points back at each entry, so it needs a
+    // seekable file rather than a pipe.
+    Zip(ZipWriter<File>),
+}
+
+impl Archive {
+    fn add_dir(&mut self, path: &Path, mtime: u64) -> Result<()> {
+        match self {
+            Archive::Tar(builder) => {
+                let mut header = tar_header(mtime);
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                builder.append_data(&mut header, path, io::empty())?;
+            },
+            Archive::Zip(zip) => {
+                zip.add_directory(zip_name(path), zip_options(mtime, 0).unix_permissions(0o755))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_file<R: Read>(&mut self, path: &Path, mtime: u64, size: u64, reader: R) -> Result<()> {
+        match self {
+            Archive::Tar(builder) => {
+                let mut header = tar_header(mtime);
+                header.set_size(size);
+                builder.append_data(&mut header, path, reader)?;
+            },
+            Archive::Zip(zip) => {
+                zip.start_file(zip_name(path), zip_options(mtime, size))?;
+                let mut reader = reader;
+                io::copy(&mut reader, zip)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Archive::Tar(builder) => builder.into_inner()?.flush()?,
+            Archive::Zip(mut zip) => zip.finish()?.flush()?
+        }
+        Ok(())
+    }
+}
+
+fn tar_header(mtime: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header
+}
+
+fn zip_name(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn zip_options(mtime: u64, size: u64) -> FileOptions {
+    // Media is already compressed, so entries are stored as they are.
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .unix_permissions(0o644)
+        .large_file(size >= u32::max_value() as u64);
+    let date = OffsetDateTime::from_unix_timestamp(mtime as i64);
+    match DateTime::from_date_and_time(date.year() as u16, date.month(), date.day(),
+                                       date.hour(), date.minute(), date.second()) {
+        Ok(date) => options.last_modified_time(date),
+        // Zip can't represent times before 1980.
+        Err(_) => options
+    }
+}
+
+/// Writes everything under `path` to a tar or zip archive at `output`, or a
+/// tar to stdout if it's "-". Files are streamed from the server straight
+/// into the archive.
+pub fn run(fs: &mut PlexFS, path: &Path, output: &str, format: Format) -> Result<()> {
+    let (ino, kind) = fs.resolve(path)
+        .ok_or_else(|| anyhow!("{}: no such file or directory", path.display()))?;
+    // Entries are named from the last component of `path`, like `tar -C`.
+    let name = match path.file_name() {
+        Some(name) => PathBuf::from(name),
+        None => PathBuf::from(".")
+    };
+
+    let mut archive = match (format, output) {
+        (Format::Tar, "-") => Archive::Tar(Builder::new(Box::new(io::stdout()))),
+        (Format::Tar, output) => Archive::Tar(Builder::new(Box::new(File::create(output)?))),
+        (Format::Zip, "-") => return Err(anyhow!("zip archives can't be written to stdout; give a file with -o")),
+        (Format::Zip, output) => Archive::Zip(ZipWriter::new(File::create(output)?))
+    };
+    append(&mut archive, fs, ino, kind, &name)?;
+    archive.finish()
+}
+
+fn append(archive: &mut Archive, fs: &mut PlexFS, ino: u64, kind: FileType, path: &Path) -> Result<()> {
+    let attr = fs.attr(ino).with_context(|| path.display().to_string())?;
+    let mtime = attr.mtime.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    if let FileType::Directory = kind {
+        archive.add_dir(path, mtime)?;
+        for (name, child, kind) in fs.children(ino) {
+            if name.to_string_lossy().starts_with('.') {
+                continue
+            }
+            append(archive, fs, child, kind, &path.join(name))?;
+        }
+        return Ok(());
+    }
+
+    // The header needs the size up front, so files the server doesn't report
+    // a size for can't be streamed.
+    if attr.size == 0 {
+        warn!("{}: skipping, size unknown", path.display());
+        return Ok(());
+    }
+
+    let (fh, _) = fs.open_file(ino)?;
+    let result = {
+        let reader = FileReader { fs: fs, ino: ino, fh: fh, offset: 0 };
+        archive.add_file(path, mtime, attr.size, Exact { inner: reader, remaining: attr.size })
+    };
+    fs.release_file(fh);
+    result.with_context(|| path.display().to_string())
+}
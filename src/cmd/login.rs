@@ -0,0 +1,72 @@
+use std::io::BufReader;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use quick_xml::de::from_reader;
+use serde::Deserialize;
+
+use super::super::api::{self, ApiOptions};
+
+const PINS_URL: &str = "https://plex.tv/pins.xml";
+const LINK_URL: &str = "https://plex.tv/link";
+/// plex.tv expires PINs after 15 minutes.
+const PIN_LIFETIME: Duration = Duration::from_secs(15 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A PIN from `/pins.xml`. `auth-token` is empty until the PIN is linked.
+#[derive(Debug, Deserialize)]
+struct Pin {
+    id: u64,
+    code: String,
+    #[serde(rename="auth-token", default)]
+    auth_token: Option<String>,
+}
+
+impl Pin {
+    fn token(&self) -> Option<&str> {
+        self.auth_token.as_ref().map(|t| &t[..]).filter(|t| !t.is_empty())
+    }
+}
+
+/// Identifies this login to plex.tv. The linked token is tied to it, and it
+/// is listed under the account's authorized devices.
+fn client_identifier() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("plexfs-{:x}-{:x}", process::id(), nanos)
+}
+
+fn request(builder: reqwest::blocking::RequestBuilder, client_id: &str) -> Result<Pin> {
+    let resp = builder
+        .header("X-Plex-Client-Identifier", client_id)
+        .header("X-Plex-Product", "plexfs")
+        .header("X-Plex-Version", env!("CARGO_PKG_VERSION"))
+        .send()?
+        .error_for_status()?;
+    Ok(from_reader(BufReader::new(resp))?)
+}
+
+/// Requests a PIN, asks the user to link it at plex.tv/link, and waits for
+/// them to, returning the account token.
+pub fn run(options: &ApiOptions) -> Result<String> {
+    let client = api::client_builder(options)?.build()?;
+    let client_id = client_identifier();
+
+    let pin = request(client.post(PINS_URL), &client_id)?;
+    println!("Go to {} and enter the code {}", LINK_URL, pin.code);
+
+    let started = Instant::now();
+    while started.elapsed() < PIN_LIFETIME {
+        thread::sleep(POLL_INTERVAL);
+        let url = format!("https://plex.tv/pins/{}.xml", pin.id);
+        match request(client.get(&url), &client_id) {
+            Ok(pin) => if let Some(token) = pin.token() {
+                return Ok(token.to_string());
+            },
+            Err(e) => debug!("polling PIN {}: {}", pin.id, e)
+        }
+    }
+    Err(anyhow!("the code {} expired before it was linked", pin.code))
+}
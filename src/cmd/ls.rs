@@ -0,0 +1,45 @@
+use std::io::{self, Write};
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use fuse::FileType;
+use time::OffsetDateTime;
+
+use crate::fs::PlexFS;
+
+fn kind_char(kind: FileType) -> char {
+    match kind {
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+        _ => '-'
+    }
+}
+
+/// Lists `path` as the mount would, or just names it if it's a file.
+pub fn run(fs: &mut PlexFS, path: &Path, long: bool, all: bool) -> Result<()> {
+    let (ino, kind) = fs.resolve(path)
+        .ok_or_else(|| anyhow!("{}: no such file or directory", path.display()))?;
+
+    let entries = match kind {
+        FileType::Directory => fs.children(ino),
+        _ => vec![(path.as_os_str().to_owned(), ino, kind)]
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (name, ino, kind) in entries {
+        let name = name.to_string_lossy();
+        if !all && name.starts_with('.') {
+            continue;
+        }
+        if long {
+            let (size, mtime) = match fs.attr(ino) {
+                Ok(attr) => (attr.size, OffsetDateTime::from(attr.mtime).format("%Y-%m-%d %H:%M")),
+                Err(_) => (0, "?".into())
+            };
+            writeln!(out, "{} {:>12} {} {}", kind_char(kind), size, mtime, name)?;
+        } else {
+            writeln!(out, "{}", name)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,13 @@
+//! Subcommands that work against the server without mounting.
+
+pub mod browse;
+pub mod cache;
+pub mod cat;
+pub mod export;
+pub mod login;
+pub mod ls;
+pub mod sections;
+pub mod serve;
+pub mod tree;
+pub mod verify;
+pub mod watch;
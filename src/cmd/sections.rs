@@ -0,0 +1,20 @@
+use std::io::{self, Write};
+use anyhow::Result;
+
+use crate::api::MediaKind;
+use crate::backend::MediaBackend;
+
+/// Prints the key, type and title of each library section on the server.
+/// Sections of a type plexfs can't mount are marked.
+pub fn run(api: &dyn MediaBackend) -> Result<()> {
+    let sections = api.sections()?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for section in sections {
+        let supported = MediaKind::from_section_type(&section.kind).is_some();
+        writeln!(out, "{:>4}  {:<8} {}{}", section.key, section.kind, section.title,
+                 if supported { "" } else { " (unsupported)" })?;
+    }
+    Ok(())
+}
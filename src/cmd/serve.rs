@@ -0,0 +1,244 @@
+use std::cmp;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::str;
+use anyhow::{anyhow, Result};
+use fuse::FileType;
+
+use crate::fs::PlexFS;
+use super::cat::parse_range;
+
+const CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// Serves the tree over HTTP at `addr`: directories as HTML listings, files
+/// with Range support. Requests are handled one at a time. Runs until
+/// killed.
+pub fn run(fs: &mut PlexFS, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("serving on http://{}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("accepting a connection: {}", e);
+                continue
+            }
+        };
+        if let Err(e) = handle(fs, stream) {
+            debug!("serving a request: {:#}", e);
+        }
+    }
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    target: String,
+    path: PathBuf,
+    range: Option<String>,
+}
+
+fn parse_request(stream: &TcpStream) -> Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().ok_or_else(|| anyhow!("empty request"))?.to_string();
+    let target = parts.next().ok_or_else(|| anyhow!("no request target"))?;
+    let target = target.split('?').next().unwrap_or("");
+
+    let mut range = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break
+        }
+        let mut header = line.splitn(2, ':');
+        let name = header.next().unwrap_or("").trim();
+        let value = header.next().unwrap_or("").trim();
+        if name.eq_ignore_ascii_case("range") {
+            range = value.strip_prefix("bytes=").map(String::from);
+        }
+    }
+
+    Ok(Request {
+        method: method,
+        target: target.to_string(),
+        path: PathBuf::from(percent_decode(target)),
+        range: range,
+    })
+}
+
+fn handle(fs: &mut PlexFS, mut stream: TcpStream) -> Result<()> {
+    let request = parse_request(&stream)?;
+    debug!("{} {}", request.method, request.path.display());
+    let head = match &request.method[..] {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return respond(&mut stream, "405 Method Not Allowed", "text/plain", &[], b"method not allowed\n")
+    };
+
+    let (ino, kind) = match fs.resolve(&request.path) {
+        Some(found) => found,
+        None => return respond(&mut stream, "404 Not Found", "text/plain", &[], b"not found\n")
+    };
+
+    if let FileType::Directory = kind {
+        // The listing's links are relative, so they need the slash to
+        // resolve inside the directory.
+        if !request.target.ends_with('/') {
+            let location = format!("Location: {}/", request.target);
+            return respond(&mut stream, "301 Moved Permanently", "text/plain", &[&location[..]], b"");
+        }
+        let body = listing(fs, ino, &request.path);
+        let body = if head { vec![] } else { body.into_bytes() };
+        return respond(&mut stream, "200 OK", "text/html; charset=utf-8", &[], &body);
+    }
+
+    let size = fs.attr(ino).map(|a| a.size).unwrap_or(0);
+    let range = match request.range.as_ref() {
+        // A suffix range asks for the last n bytes.
+        Some(range) if range.starts_with('-') => match range[1..].parse::<u64>() {
+            Ok(0) => Some(Ok((size, None))),
+            Ok(n) => Some(Ok((size.saturating_sub(n), None))),
+            Err(_) => None
+        },
+        Some(range) => Some(parse_range(range)),
+        None => None
+    };
+    let (status, start, end) = match range {
+        Some(Ok((start, _))) if start >= size => {
+            let range = format!("Content-Range: bytes */{}", size);
+            return respond(&mut stream, "416 Range Not Satisfiable", "text/plain", &[&range[..]], b"");
+        }
+        Some(Ok((start, end))) => {
+            let end = cmp::min(end.unwrap_or(size - 1), size - 1);
+            ("206 Partial Content", start, end)
+        }
+        _ => ("200 OK", 0, size.saturating_sub(1))
+    };
+    let length = if size == 0 { 0 } else { end + 1 - start };
+
+    let mut headers = vec![String::from("Accept-Ranges: bytes"), format!("Content-Length: {}", length)];
+    if status.starts_with("206") {
+        headers.push(format!("Content-Range: bytes {}-{}/{}", start, end, size));
+    }
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/octet-stream\r\nConnection: close\r\n", status)?;
+    for header in headers.iter() {
+        write!(stream, "{}\r\n", header)?;
+    }
+    write!(stream, "\r\n")?;
+    if head || length == 0 {
+        return Ok(());
+    }
+
+    let (fh, _) = fs.open_file(ino)?;
+    let result = (|| -> Result<()> {
+        let mut offset = start;
+        while offset <= end {
+            let chunk = cmp::min(end + 1 - offset, CHUNK_SIZE as u64) as u32;
+            let body = fs.read_file(ino, fh, offset, chunk)?;
+            if body.is_empty() {
+                break
+            }
+            stream.write_all(&body)?;
+            offset += body.len() as u64;
+        }
+        Ok(())
+    })();
+    fs.release_file(fh);
+    result
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, headers: &[&str], body: &[u8]) -> Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+           status, content_type, body.len())?;
+    for header in headers {
+        write!(stream, "{}\r\n", header)?;
+    }
+    write!(stream, "\r\n")?;
+    stream.write_all(body)?;
+    Ok(())
+}
+
+fn listing(fs: &mut PlexFS, ino: u64, path: &Path) -> String {
+    let title = html_escape(&path.to_string_lossy());
+    let mut body = format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head><body>\n<h1>{0}</h1>\n<ul>\n", title);
+    if path != Path::new("/") {
+        body.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, _, kind) in fs.children(ino) {
+        let name = name.to_string_lossy();
+        if name.starts_with('.') {
+            continue
+        }
+        let slash = match kind {
+            FileType::Directory => "/",
+            _ => ""
+        };
+        body.push_str(&format!("<li><a href=\"{}{}\">{}{}</a></li>\n",
+                               percent_encode(&name), slash, html_escape(&name), slash));
+    }
+    body.push_str("</ul>\n</body></html>\n");
+    body
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte))
+        }
+    }
+    out
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(byte);
+                i += 3;
+                continue
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(percent_decode("/Band/01%20Song.flac"), "/Band/01 Song.flac");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("%2f%2F"), "//");
+    }
+
+    #[test]
+    fn keeps_malformed_escapes() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("a%2"), "a%2");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+
+    #[test]
+    fn decodes_what_listings_encode() {
+        let name = "AC/DC & Friends: 50% <live> café";
+        assert_eq!(percent_decode(&percent_encode(name)), name);
+    }
+}
@@ -0,0 +1,48 @@
+use std::io::{self, Write};
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use fuse::FileType;
+
+use crate::fs::PlexFS;
+
+/// Prints the tree under `path` as the mount would show it.
+pub fn run(fs: &mut PlexFS, path: &Path, depth: Option<usize>, all: bool) -> Result<()> {
+    let (ino, kind) = fs.resolve(path)
+        .ok_or_else(|| anyhow!("{}: no such file or directory", path.display()))?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    writeln!(out, "{}", path.display())?;
+    if let FileType::Directory = kind {
+        print_dir(&mut out, fs, ino, "", depth, all)?;
+    }
+    Ok(())
+}
+
+fn print_dir<W: Write>(out: &mut W, fs: &mut PlexFS, ino: u64, prefix: &str, depth: Option<usize>, all: bool) -> Result<()> {
+    if depth == Some(0) {
+        return Ok(());
+    }
+
+    let children: Vec<_> = fs.children(ino)
+        .into_iter()
+        .filter(|(name, _, _)| all || !name.to_string_lossy().starts_with('.'))
+        .collect();
+    let count = children.len();
+
+    for (i, (name, child, kind)) in children.into_iter().enumerate() {
+        let last = i + 1 == count;
+        let is_dir = match kind {
+            FileType::Directory => true,
+            _ => false
+        };
+        writeln!(out, "{}{}{}{}", prefix, if last { "└── " } else { "├── " },
+                 name.to_string_lossy(), if is_dir { "/" } else { "" })?;
+        if is_dir {
+            let prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+            print_dir(out, fs, child, &prefix, depth.map(|d| d - 1), all)?;
+        }
+    }
+
+    Ok(())
+}
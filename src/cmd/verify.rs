@@ -0,0 +1,121 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use fuse::FileType;
+
+use crate::fs::PlexFS;
+
+/// Bytes compared at each spot-check offset.
+const SAMPLE_SIZE: u32 = 64 * 1024;
+
+/// Compares the local mirror at `local` against `path` in the mount,
+/// printing each difference. Fails if there are any.
+pub fn run(fs: &mut PlexFS, path: &Path, local: &Path, spot_check: bool) -> Result<()> {
+    let (ino, kind) = fs.resolve(path)
+        .ok_or_else(|| anyhow!("{}: no such file or directory", path.display()))?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut verifier = Verifier { fs: fs, out: &mut out, spot_check: spot_check, differences: 0 };
+    match kind {
+        FileType::Directory => verifier.dir(ino, local, Path::new(""))?,
+        _ => verifier.file(ino, local, Path::new(path.file_name().unwrap_or_default()))?
+    }
+
+    match verifier.differences {
+        0 => Ok(()),
+        n => Err(anyhow!("{} difference(s) from the server", n))
+    }
+}
+
+fn is_hidden(name: &OsStr) -> bool {
+    name.to_string_lossy().starts_with('.')
+}
+
+struct Verifier<'a, W: Write> {
+    fs: &'a mut PlexFS,
+    out: &'a mut W,
+    spot_check: bool,
+    differences: usize,
+}
+
+impl<'a, W: Write> Verifier<'a, W> {
+    fn report(&mut self, what: &str, path: &Path, detail: String) -> Result<()> {
+        self.differences += 1;
+        writeln!(self.out, "{}: {}{}", what, path.display(), detail)?;
+        Ok(())
+    }
+
+    fn dir(&mut self, ino: u64, local: &Path, path: &Path) -> Result<()> {
+        let mut seen = HashSet::new();
+        for (name, child, kind) in self.fs.children(ino) {
+            // Artwork and other generated files may or may not be in the
+            // mirror, so they're left out on both sides.
+            if is_hidden(&name) || self.fs.is_generated(child) {
+                seen.insert(name);
+                continue
+            }
+            let (local, path) = (local.join(&name), path.join(&name));
+            match kind {
+                FileType::Directory if local.is_dir() => self.dir(child, &local, &path)?,
+                FileType::Directory => self.report("missing", &path, "/".into())?,
+                _ => self.file(child, &local, &path)?
+            }
+            seen.insert(name);
+        }
+
+        for entry in fs::read_dir(local)? {
+            let name: OsString = entry?.file_name();
+            if !is_hidden(&name) && !seen.contains(&name) {
+                self.report("extra", &path.join(name), String::new())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, ino: u64, local: &Path, path: &Path) -> Result<()> {
+        let metadata = match fs::metadata(local) {
+            Ok(metadata) => metadata,
+            Err(_) => return self.report("missing", path, String::new())
+        };
+        let attr = self.fs.attr(ino).with_context(|| path.display().to_string())?;
+
+        if attr.size > 0 && metadata.len() != attr.size {
+            return self.report("size", path, format!(" (local {}, server {})", metadata.len(), attr.size));
+        }
+        // The mirror's mtime is when it was synced, so the server's copy
+        // changing since then means it's stale.
+        if metadata.modified()? < attr.mtime {
+            return self.report("changed", path, " (updated on the server since the copy)".into());
+        }
+        if self.spot_check && attr.size > 0 && !self.samples_match(ino, local, attr.size)? {
+            return self.report("content", path, " (spot check failed)".into());
+        }
+        Ok(())
+    }
+
+    /// Compares the start, middle and end of the file.
+    fn samples_match(&mut self, ino: u64, local: &Path, size: u64) -> Result<bool> {
+        let mut file = File::open(local)?;
+        let offsets = [0, size / 2, size.saturating_sub(SAMPLE_SIZE as u64)];
+        let (fh, _) = self.fs.open_file(ino)?;
+        let result = (|| -> Result<bool> {
+            for offset in offsets.iter() {
+                let remote = self.fs.read_file(ino, fh, *offset, SAMPLE_SIZE)?;
+                let mut ours = vec![0; cmp::min(SAMPLE_SIZE as u64, size - cmp::min(*offset, size)) as usize];
+                file.seek(SeekFrom::Start(*offset))?;
+                file.read_exact(&mut ours)?;
+                if remote != ours {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        })();
+        self.fs.release_file(fh);
+        result
+    }
+}
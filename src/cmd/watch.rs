@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use anyhow::Result;
+
+use crate::api::PlexAPI;
+
+/// Where dropped files go, and how the server finds them there.
+pub struct Target<'a> {
+    pub section: u64,
+    /// The library folder, as seen from this machine.
+    pub library: &'a Path,
+    /// The same folder as the server sees it. Without it the whole section
+    /// is scanned.
+    pub server_path: Option<&'a str>,
+}
+
+/// Moves everything dropped into `incoming` to the library folder once it
+/// stops growing, then has the server scan it. Runs until killed.
+pub fn run(api: &PlexAPI, incoming: &Path, target: &Target, interval: Duration) -> Result<()> {
+    // Sizes seen at the last poll. An entry is moved once its size is the
+    // same two polls in a row, so files still being copied in are left alone.
+    let mut sizes: HashMap<PathBuf, u64> = HashMap::new();
+
+    loop {
+        let mut current = HashMap::new();
+        for entry in fs::read_dir(incoming)? {
+            let path = entry?.path();
+            let size = match total_size(&path) {
+                Ok(size) => size,
+                Err(e) => {
+                    warn!("{}: {}", path.display(), e);
+                    continue
+                }
+            };
+            if sizes.get(&path) == Some(&size) {
+                if let Err(e) = import(api, &path, target) {
+                    warn!("importing {}: {:#}", path.display(), e);
+                }
+            } else {
+                current.insert(path, size);
+            }
+        }
+        sizes = current;
+        thread::sleep(interval);
+    }
+}
+
+fn import(api: &PlexAPI, path: &Path, target: &Target) -> Result<()> {
+    let name = free_name(target.library, &path.file_name().unwrap().to_string_lossy());
+    let dest = target.library.join(&name);
+    info!("moving {} to {}", path.display(), dest.display());
+    move_entry(path, &dest)?;
+
+    let server_path = target.server_path
+        .map(|p| format!("{}/{}", p.trim_end_matches('/'), name));
+    api.refresh_section(target.section, server_path.as_ref().map(|p| &p[..]))
+}
+
+/// Numbers `name` until nothing in `dir` has it, keeping any extension last,
+/// so an import never replaces or merges into media already in the library.
+fn free_name(dir: &Path, name: &str) -> String {
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, "")
+    };
+    let mut candidate = name.to_owned();
+    let mut n = 2;
+    while fs::symlink_metadata(dir.join(&candidate)).is_ok() {
+        candidate = format!("{} ({}){}", stem, n, ext);
+        n += 1;
+    }
+    candidate
+}
+
+fn total_size(path: &Path) -> io::Result<u64> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += total_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// Renames `from` to `to`, copying and deleting instead if they're on
+/// different filesystems. Fails if `to` already exists.
+fn move_entry(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(to).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", to.display())));
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_entry(from, to)?;
+    if from.is_dir() {
+        fs::remove_dir_all(from)
+    } else {
+        fs::remove_file(from)
+    }
+}
+
+fn copy_entry(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_entry(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
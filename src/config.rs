@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+/// The contents of `config.toml`.
+///
+/// ```toml
+/// default-profile = "home"
+///
+/// [profile.home]
+/// host = "192.168.1.100:32400"
+/// token = "..."
+/// section = 10
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    pub default_profile: Option<String>,
+    #[serde(rename = "profile", default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+/// Settings for one server/library, mirroring the command line flags of the
+/// same names. Flags given on the command line take precedence.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Profile {
+    pub backend: Option<String>,
+    pub host: Option<String>,
+    pub token: Option<String>,
+    pub token_file: Option<String>,
+    pub insecure: Option<bool>,
+    pub proxy: Option<String>,
+    pub section: Option<SectionName>,
+    pub sections: Option<Vec<SectionName>>,
+    pub all_sections: Option<bool>,
+    pub read_retries: Option<u32>,
+    pub max_connections: Option<usize>,
+    pub connect_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub retries: Option<u32>,
+    pub retry_backoff: Option<u64>,
+    pub retry_jitter: Option<u64>,
+    pub min_resolution: Option<u64>,
+    pub max_bitrate: Option<u64>,
+    pub layout: Option<String>,
+    pub root: Option<String>,
+    pub playlist: Option<String>,
+    pub views: Option<Vec<String>>,
+    pub filters: Option<Vec<String>>,
+    pub sort: Option<String>,
+    pub time_fallback: Option<Vec<String>>,
+    pub atime: Option<String>,
+    pub metadata_concurrency: Option<usize>,
+    pub sanitize: Option<String>,
+    pub max_name_length: Option<usize>,
+    pub replacement: Option<String>,
+    pub nfc: Option<bool>,
+    pub strip_control: Option<bool>,
+    pub naming: Option<String>,
+    pub track_numbers: Option<bool>,
+    pub absolute_episodes: Option<bool>,
+    pub absolute_shows: Option<Vec<String>>,
+    pub album_order: Option<String>,
+    pub year_prefix: Option<bool>,
+    pub disc_dirs: Option<bool>,
+    pub bucket_alpha: Option<bool>,
+    pub metadata_dir: Option<bool>,
+    pub covers: Option<bool>,
+    pub artwork_size: Option<String>,
+    pub lyrics: Option<bool>,
+    pub subtitles: Option<bool>,
+    pub subtitle_language: Option<String>,
+    pub nfo: Option<bool>,
+    pub page_size: Option<u64>,
+    pub dir_ttl: Option<u64>,
+    pub live: Option<bool>,
+    pub readahead: Option<u64>,
+    pub cache_dir: Option<String>,
+    pub cache_size: Option<u64>,
+    pub prefetch: Option<bool>,
+    pub prefetch_rate: Option<u64>,
+    /// Cache and listing behavior for sections, by key or title.
+    pub section_policy: Option<HashMap<String, Policy>>,
+    /// Cache and listing behavior for views, by name.
+    pub view_policy: Option<HashMap<String, Policy>>,
+}
+
+/// How a section or view is cached and listed, where it differs from the
+/// rest of the mount.
+///
+/// ```toml
+/// [profile.home.section-policy."4K Movies"]
+/// cache = false
+///
+/// [profile.home.view-policy.on-deck]
+/// dir-ttl = 300
+///
+/// [profile.home.view-policy.genres]
+/// album-order = "date"
+/// year-prefix = true
+/// ```
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Policy {
+    pub cache: Option<bool>,
+    pub dir_ttl: Option<u64>,
+    pub album_order: Option<String>,
+    pub year_prefix: Option<bool>,
+}
+
+/// A section by key, `section = 10`, or by title, `section = "Music"`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SectionName {
+    Key(u64),
+    Title(String),
+}
+
+impl SectionName {
+    pub fn to_spec(&self) -> String {
+        match self {
+            SectionName::Key(key) => key.to_string(),
+            SectionName::Title(title) => title.clone(),
+        }
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/plexfs/config.toml`, or `~/.config/plexfs/config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config")
+        };
+        Some(base.join("plexfs").join("config.toml"))
+    }
+
+    /// Loads `path`, or the default path if it exists.
+    pub fn load(path: Option<&str>) -> Result<Config> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => match Config::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default())
+            }
+        };
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        let config: Config = toml::from_str(&text)
+            .with_context(|| format!("parsing {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Sets `token`, and `host` if given, in profile `name` of the config at
+    /// `path`, creating the file if needed. The profile becomes the default
+    /// if there is none yet. Comments in an existing file are not kept.
+    pub fn save_token(path: &Path, name: &str, token: &str, host: Option<&str>) -> Result<()> {
+        let mut root = match fs::read_to_string(path) {
+            Ok(text) => text.parse::<toml::Value>()
+                .with_context(|| format!("parsing {}", path.display()))?,
+            Err(_) => toml::Value::Table(Default::default())
+        };
+        let table = root.as_table_mut()
+            .ok_or_else(|| anyhow!("{} isn't a table", path.display()))?;
+        table.entry("default-profile").or_insert_with(|| name.into());
+
+        let profile = table.entry("profile")
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .and_then(|profiles| {
+                profiles.entry(name)
+                    .or_insert_with(|| toml::Value::Table(Default::default()))
+                    .as_table_mut()
+            })
+            .ok_or_else(|| anyhow!("profile \"{}\" in {} isn't a table", name, path.display()))?;
+        profile.insert("token".into(), token.into());
+        if let Some(host) = host {
+            profile.insert("host".into(), host.into());
+        }
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // The token grants full access to the account.
+        let mut file = fs::OpenOptions::new()
+            .write(true).create(true).truncate(true).mode(0o600)
+            .open(path)
+            .with_context(|| format!("writing {}", path.display()))?;
+        file.write_all(toml::to_string_pretty(&root)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Looks up `name`, falling back to `default-profile`, or empty settings
+    /// if neither is given.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        let name = match name.or(self.default_profile.as_ref().map(|s| &s[..])) {
+            Some(name) => name,
+            None => return Ok(Profile::default())
+        };
+        match self.profiles.get(name) {
+            Some(profile) => Ok(profile.clone()),
+            None => {
+                let mut names: Vec<_> = self.profiles.keys().map(|s| &s[..]).collect();
+                names.sort();
+                Err(anyhow!("no profile named \"{}\" (available: {})", name, names.join(", ")))
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashSet;
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Requests from outside the FUSE session, applied by the filesystem before
+/// it handles its next operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Drop every cached directory listing that isn't pinned.
+    FlushCache,
+    /// Drop the cached listings an item of a section is in, and its own, or
+    /// with no item, or one that isn't listed yet, all of the section's.
+    /// Pinned ones are loaded again in the background instead.
+    Refresh(u64, Option<u64>),
+    /// Load an item's listing and keep it cached across `FlushCache`.
+    Pin(u64),
+    /// Download the mounted sections' media into the chunk cache in the
+    /// background.
+    Prefetch,
+    /// Build a listing again now that what it was waiting on has been
+    /// fetched in the background.
+    Relist(u64),
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub lookups: AtomicU64,
+    pub getattrs: AtomicU64,
+    pub readdirs: AtomicU64,
+    pub reads: AtomicU64,
+    pub bytes_read: AtomicU64,
+    pub cached_dirs: AtomicU64,
+}
+
+impl Stats {
+    pub fn incr(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn set(counter: &AtomicU64, n: u64) {
+        counter.store(n, Ordering::Relaxed);
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+}
+
+/// State shared between the filesystem and control interfaces running on
+/// other threads.
+#[derive(Debug, Default)]
+pub struct Control {
+    pub stats: Stats,
+    pending: Mutex<Vec<Command>>,
+    pinned: Mutex<HashSet<u64>>,
+}
+
+impl Control {
+    pub fn send(&self, command: Command) {
+        if let Command::Pin(rating_key) = command {
+            self.pinned.lock().unwrap().insert(rating_key);
+        }
+        let mut pending = self.pending.lock().unwrap();
+        // Applying a command twice in a row does nothing more.
+        if !pending.contains(&command) {
+            pending.push(command);
+        }
+    }
+
+    pub fn take(&self) -> Vec<Command> {
+        mem::replace(&mut *self.pending.lock().unwrap(), vec![])
+    }
+
+    pub fn is_pinned(&self, rating_key: u64) -> bool {
+        self.pinned.lock().unwrap().contains(&rating_key)
+    }
+
+    pub fn pinned(&self) -> Vec<u64> {
+        self.pinned.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use dbus::blocking::LocalConnection;
+use dbus::tree::Factory;
+
+use super::control::{Command, Control, Stats};
+
+pub const BUS_NAME: &str = "org.plexfs";
+
+/// Serves `org.plexfs` on the session bus from a background thread.
+pub fn spawn(control: Arc<Control>) {
+    thread::spawn(move || {
+        if let Err(e) = serve(control) {
+            error!("D-Bus service stopped: {}", e);
+        }
+    });
+}
+
+fn serve(control: Arc<Control>) -> Result<()> {
+    let c = LocalConnection::new_session().map_err(|e| anyhow!("{}", e))?;
+    c.request_name(BUS_NAME, false, true, false).map_err(|e| anyhow!("{}", e))?;
+
+    let f = Factory::new_fn::<()>();
+    let (flush, refresh, stats, pin) = (control.clone(), control.clone(), control.clone(), control.clone());
+    let prefetch = control;
+
+    let interface = f.interface(BUS_NAME, ())
+        .add_m(f.method("FlushCache", (), move |m| {
+            flush.send(Command::FlushCache);
+            Ok(vec![m.msg.method_return()])
+        }))
+        .add_m(f.method("Refresh", (), move |m| {
+            let section: u64 = m.msg.read1()?;
+            refresh.send(Command::Refresh(section, None));
+            Ok(vec![m.msg.method_return()])
+        }).inarg::<u64, _>("section"))
+        .add_m(f.method("Stats", (), move |m| {
+            let s = &stats.stats;
+            let values = vec![
+                ("lookups".to_string(), Stats::get(&s.lookups)),
+                ("getattrs".to_string(), Stats::get(&s.getattrs)),
+                ("readdirs".to_string(), Stats::get(&s.readdirs)),
+                ("reads".to_string(), Stats::get(&s.reads)),
+                ("bytes_read".to_string(), Stats::get(&s.bytes_read)),
+                ("cached_dirs".to_string(), Stats::get(&s.cached_dirs)),
+                ("pinned".to_string(), stats.pinned().len() as u64),
+            ];
+            let dict: HashMap<String, u64> = values.into_iter().collect();
+            Ok(vec![m.msg.method_return().append1(dict)])
+        }).outarg::<HashMap<String, u64>, _>("stats"))
+        .add_m(f.method("PinItem", (), move |m| {
+            let rating_key: u64 = m.msg.read1()?;
+            pin.send(Command::Pin(rating_key));
+            Ok(vec![m.msg.method_return()])
+        }).inarg::<u64, _>("rating_key"))
+        .add_m(f.method("Prefetch", (), move |m| {
+            prefetch.send(Command::Prefetch);
+            Ok(vec![m.msg.method_return()])
+        }));
+
+    let tree = f.tree(()).add(f.object_path("/org/plexfs", ()).introspectable().add(interface));
+    tree.start_receive(&c);
+
+    loop {
+        c.process(Duration::from_millis(1000)).map_err(|e| anyhow!("{}", e))?;
+    }
+}
@@ -0,0 +1,123 @@
+//! Listings built without waiting on the server. A listing loaded in the
+//! background is built on the FUSE session's thread from responses fetched
+//! beforehand on worker threads: a build that asks for something not yet
+//! fetched gives up, the workers fetch what it asked for, and it's built
+//! again once they're done.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Result};
+
+/// A GET as a backend makes it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Request {
+    pub url: String,
+    pub args: String,
+    /// The page asked for, for paged listings.
+    pub start: u64,
+    pub size: u64,
+}
+
+/// A response's body, and the total of a paged listing.
+pub type Body = (Vec<u8>, u64);
+
+/// The error a deferred build gets for a request that hasn't been fetched
+/// yet.
+#[derive(Debug)]
+pub struct Pending(String);
+
+impl fmt::Display for Pending {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} hasn't been fetched yet", self.0)
+    }
+}
+
+impl error::Error for Pending {}
+
+#[derive(Debug, Default)]
+struct State {
+    bodies: HashMap<Request, Result<Body, String>>,
+    /// What builds asked for that isn't in `bodies`.
+    missed: Vec<Request>,
+    /// Requests handed to workers that haven't come back.
+    outstanding: usize,
+}
+
+/// The responses fetched for one listing built in the background.
+#[derive(Debug, Default)]
+pub struct Deferred {
+    state: Mutex<State>,
+}
+
+impl Deferred {
+    /// Takes what builds asked for since the last call, counting it as
+    /// outstanding until it's stored.
+    pub fn take_missed(&self) -> Vec<Request> {
+        let mut state = self.state.lock().unwrap();
+        let missed = mem::take(&mut state.missed);
+        state.outstanding += missed.len();
+        missed
+    }
+
+    /// Whether a build has asked for something not yet fetched since the
+    /// last `take_missed`.
+    pub fn has_missed(&self) -> bool {
+        !self.state.lock().unwrap().missed.is_empty()
+    }
+
+    /// Stores what a worker fetched. True once nothing is outstanding, when
+    /// the listing can be built again.
+    pub fn store(&self, request: Request, body: Result<Body>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.bodies.insert(request, body.map_err(|e| format!("{:#}", e)));
+        state.outstanding -= 1;
+        state.outstanding == 0
+    }
+
+    /// Drops what's been fetched, which the server has since changed, so
+    /// the next build asks for it again.
+    pub fn forget(&self) {
+        self.state.lock().unwrap().bodies.clear();
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Deferred>>> = RefCell::new(None);
+}
+
+/// Runs `f` with requests made on this thread answered by `deferred`, if
+/// given, rather than the server.
+pub fn with<T, F: FnOnce() -> T>(deferred: Option<Arc<Deferred>>, f: F) -> T {
+    let previous = CURRENT.with(|current| current.replace(deferred));
+    let result = f();
+    CURRENT.with(|current| current.replace(previous));
+    result
+}
+
+/// What requests made on this thread are answered by.
+pub fn current() -> Option<Arc<Deferred>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Answers `request` if requests on this thread are deferred: with what was
+/// fetched for it, or else a `Pending` error, noting that it's wanted. None
+/// if it's to be sent to the server as usual.
+pub fn answer(request: Request) -> Option<Result<Body>> {
+    let deferred = current()?;
+    let mut state = deferred.state.lock().unwrap();
+    Some(match state.bodies.get(&request) {
+        Some(Ok(body)) => Ok(body.clone()),
+        Some(Err(e)) => Err(anyhow!("{}", e)),
+        None => {
+            let error = Pending(request.url.clone());
+            if !state.missed.contains(&request) {
+                state.missed.push(request);
+            }
+            Err(error.into())
+        }
+    })
+}
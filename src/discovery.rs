@@ -0,0 +1,174 @@
+//! Finding a server when no host is given: GDM on the local network, then
+//! the servers plex.tv lists for the account.
+
+use std::io::BufReader;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use quick_xml::de::from_reader;
+use serde::Deserialize;
+
+use super::api::{self, ApiOptions};
+
+/// Where servers listen for GDM searches.
+const GDM_PORT: u16 = 32414;
+const GDM_MULTICAST: Ipv4Addr = Ipv4Addr::new(239, 0, 0, 250);
+const GDM_WAIT: Duration = Duration::from_secs(2);
+
+const RESOURCES_URL: &str = "https://plex.tv/api/resources?includeHttps=1";
+
+/// Time allowed for a candidate to answer before trying the next.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Deserialize)]
+struct ResourceContainer {
+    #[serde(rename="Device", default)]
+    devices: Vec<Device>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Device {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    provides: String,
+    /// The token to use with this server. For servers shared by others it
+    /// differs from the account token.
+    #[serde(rename="accessToken", default)]
+    access_token: String,
+    #[serde(rename="Connection", default)]
+    connections: Vec<Connection>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Connection {
+    uri: String,
+    #[serde(default)]
+    local: u8,
+}
+
+/// Base URLs of servers that answer a GDM search within `GDM_WAIT`, in the
+/// order they answered.
+pub fn gdm() -> Result<Vec<String>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    let search = b"M-SEARCH * HTTP/1.0\r\n\r\n";
+    socket.send_to(search, (GDM_MULTICAST, GDM_PORT))?;
+    if let Err(e) = socket.send_to(search, (Ipv4Addr::BROADCAST, GDM_PORT)) {
+        debug!("GDM broadcast: {}", e);
+    }
+
+    let mut found = Vec::new();
+    let mut buf = [0; 2048];
+    let started = Instant::now();
+    loop {
+        let left = match GDM_WAIT.checked_sub(started.elapsed()) {
+            Some(left) if left > Duration::from_secs(0) => left,
+            _ => break
+        };
+        socket.set_read_timeout(Some(left))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(_) => break
+        };
+        if let Some(url) = gdm_url(&String::from_utf8_lossy(&buf[..len]), from) {
+            if !found.contains(&url) {
+                debug!("GDM: found {}", url);
+                found.push(url);
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// The server's base URL from a GDM reply: the address it came from and the
+/// `Port` header. Replies from clients and players are skipped.
+fn gdm_url(reply: &str, from: SocketAddr) -> Option<String> {
+    let mut port = None;
+    let mut server = false;
+    for line in reply.lines() {
+        let mut parts = line.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim().to_lowercase(), value.trim()),
+            _ => continue
+        };
+        match &name[..] {
+            "port" => port = value.parse::<u16>().ok(),
+            "content-type" => server = value == "plex/media-server",
+            _ => ()
+        }
+    }
+    match port {
+        Some(port) if server => Some(format!("http://{}:{}", from.ip(), port)),
+        _ => None
+    }
+}
+
+/// A server the account can use, as plex.tv lists it.
+#[derive(Debug, Clone)]
+pub struct Server {
+    pub name: String,
+    pub token: String,
+    /// Base URLs to try, local ones first.
+    pub urls: Vec<String>,
+}
+
+/// Every server the account owns or has been shared, from plex.tv.
+pub fn servers(token: &str, options: &ApiOptions) -> Result<Vec<Server>> {
+    let resp = api::client_builder(options)?.build()?
+        .get(RESOURCES_URL)
+        .header("X-Plex-Token", token)
+        .send()?
+        .error_for_status()?;
+    let container: ResourceContainer = from_reader(BufReader::new(resp))?;
+
+    Ok(container.devices.into_iter()
+        .filter(|d| d.provides.split(',').any(|p| p == "server"))
+        .map(|d| {
+            let mut connections = d.connections;
+            connections.sort_by_key(|c| c.local == 0);
+            Server {
+                name: d.name,
+                token: if d.access_token.is_empty() { token.to_string() } else { d.access_token },
+                urls: connections.into_iter().map(|c| c.uri).collect(),
+            }
+        })
+        .collect())
+}
+
+/// Connection URLs of the account's servers from plex.tv, each server's
+/// local ones first.
+pub fn plex_tv(token: &str, options: &ApiOptions) -> Result<Vec<String>> {
+    Ok(servers(token, options)?.into_iter().flat_map(|s| s.urls).collect())
+}
+
+/// The first of `candidates` whose `/identity` answers.
+pub fn first_reachable(candidates: &[String], options: &ApiOptions) -> Option<String> {
+    let client = api::client_builder(options).ok()?
+        .timeout(PROBE_TIMEOUT)
+        .build()
+        .ok()?;
+    candidates.iter().find(|url| {
+        let result = client.get(&format!("{}/identity", url)).send()
+            .and_then(|resp| resp.error_for_status());
+        if let Err(e) = &result {
+            debug!("{} isn't reachable: {}", url, e);
+        }
+        result.is_ok()
+    }).cloned()
+}
+
+/// Looks for a server on the local network, then among the account's
+/// servers on plex.tv, and returns the base URL of the first that answers.
+pub fn discover(token: &str, options: &ApiOptions) -> Result<String> {
+    match gdm() {
+        Ok(urls) => if let Some(url) = first_reachable(&urls, options) {
+            return Ok(url);
+        },
+        Err(e) => debug!("GDM search failed: {}", e)
+    }
+    let urls = plex_tv(token, options)
+        .map_err(|e| anyhow!("no server found on the local network, and plex.tv couldn't be asked: {}", e))?;
+    first_reachable(&urls, options)
+        .ok_or_else(|| anyhow!("no reachable server found; pass --host"))
+}
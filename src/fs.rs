@@ -1,42 +1,3157 @@
+use std::cell::Cell;
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error;
 use std::ffi::{OsString, OsStr};
-use std::net::SocketAddr;
-use std::time::{Duration, UNIX_EPOCH};
-use libc::ENOENT;
-use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::path::{Component, Path};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use libc::{c_int, EACCES, EAGAIN, EBADF, EIO, ENODATA, ENOENT, ERANGE};
+use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory, ReplyEmpty, ReplyOpen, ReplyStatfs, ReplyXattr};
+use fuse::consts::FOPEN_DIRECT_IO;
+use reqwest::StatusCode;
+use time::OffsetDateTime;
 
 use super::api;
+use super::backend::MediaBackend;
+use super::cache::{self, ChunkCache};
+use super::control::{Command, Control, Stats};
+use super::deferred::{self, Deferred};
+use super::nfo;
+use super::notifications;
+use super::pool::Pool;
+use super::sanitize::NamePolicy;
+use super::scheduler;
+use super::stream::Stream;
+use super::template::Template;
+use super::times::{self, AtimeMode, TimePolicy};
 
-const TTL: Duration = Duration::from_secs(60 * 60);
+pub const TTL: Duration = Duration::from_secs(60 * 60);
 
-const PAGE_SIZE: u64 = 50;
+/// How long a resolved Part is reused by reads before it's looked up again.
+const PART_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// How many fetched artwork files are kept for an open to follow.
+const MAX_PENDING_ARTWORK: usize = 32;
+
+pub const DEFAULT_PAGE_SIZE: u64 = 500;
+
+pub const DEFAULT_READAHEAD: u64 = 2 * 1024 * 1024;
+
+/// Prefix of the extended attributes an item's metadata is exposed as.
+const XATTR_PREFIX: &str = "user.plex.";
+
+/// Restricts which Media versions of an item are exposed.
+#[derive(Debug, Clone, Default)]
+pub struct MediaFilter {
+    /// Minimum vertical resolution in pixels.
+    pub min_resolution: Option<u64>,
+    /// Maximum bitrate in kbps.
+    pub max_bitrate: Option<u64>,
+}
+
+impl MediaFilter {
+    /// Media with unknown resolution or bitrate is always accepted, so audio
+    /// isn't hidden by video-only criteria. Media without a Part to stream
+    /// never is.
+    pub fn accepts(&self, media: &api::Media) -> bool {
+        if !media.has_part() {
+            return false
+        }
+        if let (Some(min), Some(res)) = (self.min_resolution, media.resolution()) {
+            if res < min {
+                return false
+            }
+        }
+        if let (Some(max), Some(bitrate)) = (self.max_bitrate, media.bitrate) {
+            if bitrate > max {
+                return false
+            }
+        }
+        true
+    }
+
+    /// Picks the first Media version of `item` that passes the filter.
+    pub fn select<'a>(&self, item: &'a api::Item) -> Option<&'a api::Media> {
+        item.media().iter().find(|m| self.accepts(m))
+    }
+}
+
+/// Synthesized top-level directories that can be enabled alongside the
+/// section listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum View {
+    /// Items with several Media versions, or several items sharing a GUID.
+    Duplicates,
+    /// The server's playlists, at the root rather than in each section.
+    Playlists,
+    /// Each of a section's collections, as a directory of its members.
+    Collections,
+    /// Each of a section's genres, as a directory of what's in it.
+    Genres,
+    /// What was last added to the mounted sections, newest first.
+    RecentlyAdded,
+    /// Videos that are partly watched or next up in a show.
+    OnDeck,
+}
+
+impl View {
+    pub const NAMES: &'static [&'static str] = &["duplicates", "playlists", "collections", "genres", "recently-added", "on-deck"];
+
+    pub fn from_name(name: &str) -> Option<View> {
+        match name {
+            "duplicates" => Some(View::Duplicates),
+            "playlists" => Some(View::Playlists),
+            "collections" => Some(View::Collections),
+            "genres" => Some(View::Genres),
+            "recently-added" => Some(View::RecentlyAdded),
+            "on-deck" => Some(View::OnDeck),
+            _ => None
+        }
+    }
+
+    fn dir_name(self) -> &'static str {
+        match self {
+            View::Duplicates => "Duplicates",
+            View::Playlists => "Playlists",
+            View::Collections => "Collections",
+            View::Genres => "Genres",
+            View::RecentlyAdded => "Recently Added",
+            View::OnDeck => "On Deck",
+        }
+    }
+}
+
+/// How the section's items are arranged into directories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layout {
+    /// Plex's metadata hierarchy, e.g. artist/album/track.
+    Metadata,
+    /// The folders the files are in on the server, relative to the section's
+    /// locations.
+    ServerPaths,
+    /// Plex's own folder view, as browsed in the web app.
+    Folders,
+    /// Paths made from each file's metadata.
+    Template(Template),
+}
+
+impl Layout {
+    pub const NAMES: &'static [&'static str] = &["metadata", "server-paths", "folders"];
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        match name {
+            "metadata" => Some(Layout::Metadata),
+            "server-paths" => Some(Layout::ServerPaths),
+            "folders" => Some(Layout::Folders),
+            _ => None
+        }
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Metadata
+    }
+}
+
+/// Something other than a whole section to mount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Root {
+    /// An item's children, e.g. an artist's albums, by rating key.
+    Item(u64),
+    /// A playlist's items in playlist order, by rating key.
+    Playlist(u64),
+}
+
+/// How albums are ordered under an artist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlbumOrder {
+    Title,
+    /// Chronologically, by `originallyAvailableAt` or else the year.
+    ReleaseDate,
+}
+
+impl AlbumOrder {
+    pub const NAMES: &'static [&'static str] = &["title", "date"];
+
+    pub fn from_name(name: &str) -> Option<AlbumOrder> {
+        match name {
+            "title" => Some(AlbumOrder::Title),
+            "date" => Some(AlbumOrder::ReleaseDate),
+            _ => None
+        }
+    }
+}
+
+impl Default for AlbumOrder {
+    fn default() -> Self {
+        AlbumOrder::Title
+    }
+}
+
+/// Where track file names come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Naming {
+    /// The file's basename on the server.
+    File,
+    /// "NN - Title.ext", from Plex's track number and title.
+    Title,
+}
+
+impl Naming {
+    pub const NAMES: &'static [&'static str] = &["file", "title"];
+
+    pub fn from_name(name: &str) -> Option<Naming> {
+        match name {
+            "file" => Some(Naming::File),
+            "title" => Some(Naming::Title),
+            _ => None
+        }
+    }
+}
+
+impl Default for Naming {
+    fn default() -> Self {
+        Naming::File
+    }
+}
+
+pub const DEFAULT_METADATA_CONCURRENCY: usize = 8;
+
+pub const DEFAULT_SUBTITLE_LANGUAGE: &str = "en";
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub filter: MediaFilter,
+    pub layout: Layout,
+    /// What to show at the root instead of the whole section.
+    pub root: Option<Root>,
+    pub views: Vec<View>,
+    pub times: TimePolicy,
+    pub atime: AtimeMode,
+    /// Parallel metadata requests while filling in a directory listing.
+    pub metadata_concurrency: usize,
+    pub names: NamePolicy,
+    pub naming: Naming,
+    /// Name every show's episodes `Show - 0134 - Title.ext` from their
+    /// absolute number, where Plex has one.
+    pub absolute_episodes: bool,
+    /// Shows, by title or rating key, whose episodes are named that way.
+    pub absolute_shows: Vec<String>,
+    pub album_order: AlbumOrder,
+    /// Start album names with their release year, as in
+    /// `1973 - The Dark Side of the Moon`.
+    pub year_prefix: bool,
+    /// Expose `.metadata/<rating_key>.json` for every item.
+    pub metadata_dir: bool,
+    /// Expose artwork as `cover.jpg` in albums and `folder.jpg` in shows
+    /// and seasons.
+    pub covers: bool,
+    /// Width and height `covers` are scaled down to fit, if given.
+    pub artwork_size: Option<(u32, u32)>,
+    /// Expose tracks' lyrics as `.lrc` files beside them. Costs a metadata
+    /// request per track listed.
+    pub lyrics: bool,
+    /// Expose videos' subtitle files as `.srt` and the like beside them.
+    /// Costs a metadata request per video listed.
+    pub subtitles: bool,
+    /// The language `.plexfs/subtitles/` searches for, such as "en".
+    pub subtitle_language: String,
+    /// Expose Kodi NFO files for movies, shows and episodes.
+    pub nfo: bool,
+    /// List the tracks of albums with several discs under `Disc 1/`,
+    /// `Disc 2/` and so on.
+    pub disc_dirs: bool,
+    /// In the metadata layout, list a section's items under a directory for
+    /// the first letter of each, as `A/`, `B/` and `#/`.
+    pub bucket_alpha: bool,
+    /// List items in the order the server sends them, rather than by name.
+    pub server_order: bool,
+    /// Items requested per page when listing a directory.
+    pub page_size: u64,
+    /// Bytes of an open file fetched ahead of sequential reads.
+    pub readahead: u64,
+    /// How long a directory listing is used before it's fetched again, when
+    /// it's next used. None keeps listings until they're flushed.
+    pub dir_ttl: Option<Duration>,
+    /// Bytes a second a prefetch downloads at most. None doesn't limit it.
+    pub prefetch_rate: Option<u64>,
+    /// Cache behavior of sections, by key or title, overriding the above.
+    pub section_policies: Vec<(String, Policy)>,
+    /// Cache behavior of views, overriding that of their section.
+    pub view_policies: Vec<(View, Policy)>,
+}
+
+/// How a section or view is cached and listed, where it differs from the
+/// rest of the mount. Unset fields are left as they are.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// Whether file data is kept in the cache directory.
+    pub cache: Option<bool>,
+    /// How long listings are used; `Some(None)` keeps them until flushed.
+    pub dir_ttl: Option<Option<Duration>>,
+    pub album_order: Option<AlbumOrder>,
+    /// Whether album names start with their release year.
+    pub year_prefix: Option<bool>,
+}
+
+/// The section and view a directory or file is in, for its cache policy.
+#[derive(Debug, Clone, Copy)]
+struct Scope {
+    section: Option<u64>,
+    view: Option<View>,
+}
+
+/// Files whose content is rendered each time they're opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Generated {
+    /// `/status/sessions` as JSON.
+    Sessions,
+    /// Every library section with its ID, type and item count.
+    Sections,
+    /// An item's full metadata as JSON, under `.metadata/`.
+    Metadata(u64),
+    /// An album's artwork as `cover.jpg`, by rating key.
+    Cover(u64),
+    /// A show's or season's artwork as `folder.jpg`, by rating key.
+    Poster(u64),
+    /// A Kodi NFO describing a movie or episode, beside its file, or a show,
+    /// as `tvshow.nfo` in its directory. By rating key.
+    Nfo(u64),
+    /// A playlist as `.m3u8`, by rating key.
+    Playlist(u64),
+    /// A track's lyrics or a video's subtitles, beside its file, by rating
+    /// key and stream ID.
+    Sidecar(u64, u64),
+    /// Searches for subtitles for a video and downloads the best, when
+    /// opened. Under `.plexfs/subtitles/`, by rating key.
+    SubtitleSearch(u64),
+}
+
+impl Generated {
+    const CONTROL: &'static [Generated] = &[Generated::Sessions, Generated::Sections];
+
+    fn file_name(self) -> String {
+        match self {
+            Generated::Sessions => "sessions.json".into(),
+            Generated::Sections => "sections.json".into(),
+            Generated::Metadata(rating_key) => format!("{}.json", rating_key),
+            Generated::Cover(_) => "cover.jpg".into(),
+            Generated::Poster(_) => "folder.jpg".into(),
+            Generated::Nfo(_) => "tvshow.nfo".into(),
+            Generated::Playlist(rating_key) => format!("{}.m3u8", rating_key),
+            // Listed under the name of the file it's beside instead.
+            Generated::Sidecar(_, id) => id.to_string(),
+            Generated::SubtitleSearch(rating_key) => rating_key.to_string(),
+        }
+    }
+}
+
+/// An inode that doesn't correspond directly to a Plex rating key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Node {
+    /// A section's directory when several are mounted, by section key.
+    Section(u64),
+    View(u64, View),
+    /// Duplicates of one item inside `Duplicates/`, keyed by GUID.
+    DuplicateGroup(String),
+    /// A single Media version of an item, by rating key and index.
+    Version(u64, usize),
+    /// The hidden `.plexfs/` directory.
+    Control,
+    /// The hidden `.metadata/` directory.
+    MetadataDir,
+    /// `.plexfs/subtitles/`.
+    SubtitlesDir,
+    /// A folder in the `ServerPaths` layout, by section and its path from
+    /// the section's directory.
+    Folder(u64, String),
+    /// `by-date/` in a photo section.
+    ByDate(u64),
+    /// A year, or a month of a year, under a section's `by-date/`.
+    Period(u64, i32, Option<u8>),
+    /// A folder in the `Folders` layout, by section and Plex's folder ID.
+    SectionFolder(u64, u64),
+    /// `Playlists/` at the root.
+    Playlists,
+    /// A playlist's items under `Playlists/`, by rating key.
+    Playlist(u64),
+    /// A first letter of a section's items with `bucket_alpha`, by section
+    /// and the letter's key.
+    Letter(u64, String),
+    /// A genre under a section's `Genres/`, by section and the genre's key.
+    Genre(u64, String),
+    /// `Recently Added/` at the root.
+    RecentlyAdded,
+    /// `On Deck/` at the root.
+    OnDeck,
+    /// `Disc N/` under an album, by the album's rating key and the disc
+    /// number.
+    Disc(u64, u64),
+    /// `Singles/` under an artist: tracks that aren't on any of the
+    /// artist's albums, by the artist's rating key.
+    Singles(u64),
+    /// `Various Artists/` in a music section, by section key.
+    VariousArtists(u64),
+    Generated(Generated),
+}
 
 struct Entry {
-    rating_key: u64,
+    ino: u64,
     kind: FileType,
     attr: Option<FileAttr>
 }
 
-pub struct PlexFS {
-    api: api::PlexAPI,
-    section: u64,
-    kind: api::MediaKind,
-    entries: HashMap<u64, HashMap<OsString, Entry>>,
+/// A section listing being fetched a page at a time.
+struct Partial {
+    section: Section,
+    /// Offset of the next page.
+    start: u64,
+    /// How many items the section has, once the first page says.
+    total: Option<u64>,
+}
+
+/// What the mounted sections hold, as `statfs` reports it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// Block size `statfs` reports sizes in.
+pub const STATFS_BLOCK_SIZE: u64 = 4096;
+
+/// Longest file name `statfs` reports.
+pub const NAME_MAX: u32 = 255;
+
+/// A media file opened through the mount.
+struct OpenFile {
+    /// The Part the file was resolved to when opened, used for as long as
+    /// it stays open.
+    part: api::Part,
+    stream: Arc<Mutex<Stream>>,
+}
+
+/// A directory opened through the mount. `readdir` continues from what
+/// it listed when opened, so offsets stay put if the listing is replaced
+/// in the meantime.
+struct OpenDir {
+    entries: Vec<(OsString, u64, FileType)>,
+    /// When the listing was made, while more of it is left to fetch.
+    partial: Option<Instant>,
+}
+
+/// A library section in the mount.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub key: u64,
+    pub kind: api::MediaKind,
+    pub title: String,
+}
+
+pub struct PlexFS {
+    /// Shared with the reader threads.
+    api: Arc<dyn MediaBackend>,
+    /// With more than one, each is a directory at the root. With one, its
+    /// contents are the root.
+    sections: Vec<Section>,
+    options: Options,
+    entries: HashMap<u64, HashMap<OsString, Entry>>,
+    /// The directory each inode was last listed in, for `..`.
+    parents: HashMap<u64, u64>,
+    nodes: HashMap<u64, Node>,
+    inos: HashMap<Node, u64>,
+    /// Rendered content of open generated files, by file handle.
+    handles: HashMap<u64, Vec<u8>>,
+    /// Attributes of entries `readdir` or `lookup` has reported, so
+    /// `getattr` doesn't fetch them again. Cleared with `entries`.
+    attrs: HashMap<u64, FileAttr>,
+    /// Extended attributes of items `getxattr` or `listxattr` has fetched,
+    /// without the prefix. Cleared with `entries`, and those of a directory
+    /// and its entries when it's listed again.
+    xattrs: HashMap<u64, Vec<(&'static str, String)>>,
+    /// Open media files, by file handle.
+    open_files: HashMap<u64, OpenFile>,
+    /// Open directories, by file handle.
+    open_dirs: HashMap<u64, OpenDir>,
+    /// When each directory in `entries` was listed, for `dir_ttl`.
+    listed: HashMap<u64, Instant>,
+    /// Directories whose listing failed, with the errno to report for them
+    /// while nothing older is cached. They're listed again on next access.
+    failed: HashMap<u64, c_int>,
+    /// Why the listing being built failed, if it did.
+    listing_error: Cell<Option<c_int>>,
+    /// Section listings with pages left to fetch.
+    partial: HashMap<u64, Partial>,
+    /// Entry names in the order they were listed, for directories whose
+    /// entries are fetched a page at a time. Others are sorted by name.
+    order: HashMap<u64, Vec<OsString>>,
+    /// Names of the items added to the listing being built, in the server's
+    /// order, with the files beside each following it.
+    item_order: Vec<OsString>,
+    /// The name each item directory had before being told apart from
+    /// others with it, and its year, by inode, for renaming those listed
+    /// earlier when another with the name turns up.
+    titles: HashMap<u64, (String, Option<u64>)>,
+    /// The directory whose listing is being built, for its policies.
+    building: u64,
+    /// Albums' release dates, "YYYY-MM-DD" or just the year, by inode.
+    release_dates: HashMap<u64, String>,
+    /// The artwork file of each directory seen in a listing that has one,
+    /// and the path to fetch it from, by rating key, so listing the
+    /// directory itself doesn't have to fetch its metadata for it.
+    covers: HashMap<u64, (Generated, String)>,
+    /// Rating keys of the collections seen in listings, whose members are
+    /// listed as a collection's rather than as an item's children.
+    collection_keys: HashSet<u64>,
+    /// Sizes of artwork files, by inode, once fetched.
+    artwork_sizes: HashMap<u64, u64>,
+    /// Artwork fetched to find its size and not opened since, by inode.
+    artwork: HashMap<u64, Vec<u8>>,
+    /// File data kept on disk, if enabled.
+    cache: Option<Arc<ChunkCache>>,
+    /// Totals over the mounted sections' media for `statfs`, once counted.
+    /// `None` inside while a count is running.
+    usage: Option<Arc<Mutex<Option<Usage>>>>,
+    /// The section and view each entry was last listed in, for those that
+    /// aren't a section or view themselves.
+    scopes: HashMap<u64, Scope>,
+    /// Set while a prefetch into `cache` is running.
+    prefetching: Arc<AtomicBool>,
+    next_fh: u64,
+    control: Arc<Control>,
+    /// Last read through the mount, by inode, in `RelAtime` mode.
+    atimes: HashMap<u64, SystemTime>,
+    /// The Part each file reads from, and when it was resolved.
+    parts: HashMap<u64, (api::Part, Instant)>,
+    /// Fetches file data for `read`, so reads of different files, or of
+    /// different parts of one, proceed in parallel.
+    readers: Pool,
+    /// Pinned listings the server has reported changes in, served until
+    /// they're loaded again.
+    outdated: HashSet<u64>,
+    /// Listings being loaded in the background, by inode, with what's been
+    /// fetched for them so far.
+    refreshing: HashMap<u64, Arc<Deferred>>,
+    /// Fetches what listings loaded in the background ask for, apart from
+    /// `readers` so reads don't wait behind them.
+    fetchers: Pool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            filter: MediaFilter::default(),
+            layout: Layout::default(),
+            root: None,
+            views: vec![],
+            times: TimePolicy::default(),
+            atime: AtimeMode::default(),
+            metadata_concurrency: DEFAULT_METADATA_CONCURRENCY,
+            names: NamePolicy::default(),
+            naming: Naming::default(),
+            absolute_episodes: false,
+            absolute_shows: vec![],
+            album_order: AlbumOrder::default(),
+            year_prefix: false,
+            metadata_dir: false,
+            covers: true,
+            artwork_size: None,
+            lyrics: false,
+            subtitles: false,
+            subtitle_language: DEFAULT_SUBTITLE_LANGUAGE.to_string(),
+            nfo: false,
+            disc_dirs: false,
+            bucket_alpha: false,
+            server_order: false,
+            page_size: DEFAULT_PAGE_SIZE,
+            readahead: DEFAULT_READAHEAD,
+            dir_ttl: None,
+            prefetch_rate: None,
+            section_policies: vec![],
+            view_policies: vec![],
+        }
+    }
+}
+
+impl PlexFS {
+    pub fn new(api: Arc<dyn MediaBackend>, sections: Vec<Section>, options: Options) -> Self {
+        let readers = Pool::new(api.max_connections());
+        let fetchers = Pool::new(api.max_connections());
+        let control = Arc::new(Control::default());
+        PlexFS {
+            api: api,
+            sections: sections,
+            options: options,
+            entries: HashMap::new(),
+            parents: HashMap::new(),
+            nodes: HashMap::new(),
+            inos: HashMap::new(),
+            handles: HashMap::new(),
+            attrs: HashMap::new(),
+            xattrs: HashMap::new(),
+            open_files: HashMap::new(),
+            open_dirs: HashMap::new(),
+            listed: HashMap::new(),
+            failed: HashMap::new(),
+            listing_error: Cell::new(None),
+            partial: HashMap::new(),
+            order: HashMap::new(),
+            item_order: vec![],
+            titles: HashMap::new(),
+            building: INO_ROOT,
+            release_dates: HashMap::new(),
+            covers: HashMap::new(),
+            collection_keys: HashSet::new(),
+            artwork_sizes: HashMap::new(),
+            artwork: HashMap::new(),
+            cache: None,
+            usage: None,
+            scopes: HashMap::new(),
+            prefetching: Arc::new(AtomicBool::new(false)),
+            next_fh: 1,
+            control: control,
+            atimes: HashMap::new(),
+            parts: HashMap::new(),
+            readers: readers,
+            outdated: HashSet::new(),
+            refreshing: HashMap::new(),
+            fetchers: fetchers
+        }
+    }
+
+    /// Reads file data through `cache`. It may be shared with other
+    /// filesystems.
+    pub fn set_cache(&mut self, cache: Arc<ChunkCache>) {
+        self.cache = Some(cache);
+    }
+
+    pub fn has_cache(&self) -> bool {
+        self.cache.is_some()
+    }
+
+    /// Pins the item at `ino` as `PinItem` does, and with a chunk cache,
+    /// stores every file under it there so it can be read offline. Returns
+    /// how many files were stored.
+    pub fn pin(&mut self, ino: u64) -> Result<usize> {
+        let rating_key = self.item_key(ino)
+            .ok_or_else(|| not_found(format!("inode {} is not a Plex item", ino)))?;
+        let files = match self.attr(ino)?.kind {
+            FileType::Directory => {
+                self.control.send(Command::Pin(rating_key));
+                self.apply_commands();
+                self.files_under(ino)
+            }
+            _ => vec![ino]
+        };
+        let mut stored = 0;
+        for file in files {
+            let cache = match self.cache_for(file) {
+                Some(cache) => cache.clone(),
+                None => continue
+            };
+            let part = self.part(file)?;
+            prefetch_part(&*self.api, &cache, &part, None)?;
+            stored += 1;
+        }
+        Ok(stored)
+    }
+
+    /// The files anywhere under directory `ino`, leaving out generated ones.
+    fn files_under(&mut self, ino: u64) -> Vec<u64> {
+        let mut files = vec![];
+        for (name, child, kind) in self.children(ino) {
+            if name.to_string_lossy().starts_with('.') || self.is_generated(child) {
+                continue
+            }
+            match kind {
+                FileType::Directory => files.extend(self.files_under(child)),
+                _ => files.push(child)
+            }
+        }
+        files
+    }
+
+    /// Stores `local`, a copy of the file at `ino`, in the chunk cache, so
+    /// reads of it never go to the server. Returns false, storing nothing,
+    /// if `local` isn't the size the server reports.
+    pub fn seed_file(&mut self, ino: u64, local: &Path) -> Result<bool> {
+        let cache = self.cache.clone().ok_or_else(|| anyhow!("no chunk cache to seed"))?;
+        let part = self.part(ino)?;
+        let mut file = File::open(local)?;
+        if part.size == 0 || file.metadata()?.len() != part.size {
+            return Ok(false);
+        }
+
+        let key = cache_key(&*self.api, &part);
+        let mut index = 0;
+        while index * cache::CHUNK_SIZE < part.size {
+            let mut chunk = vec![0; ChunkCache::chunk_len(part.size, index) as usize];
+            file.read_exact(&mut chunk)?;
+            cache.put(&key, index, &chunk);
+            index += 1;
+        }
+        Ok(true)
+    }
+
+    /// Drops the listings of a section whenever the server reports an item
+    /// in it was added, changed or deleted.
+    pub fn watch_notifications(&self) {
+        let sections = self.sections.iter().map(|s| s.key).collect();
+        notifications::spawn(self.api.clone(), self.control.clone(), sections);
+    }
+
+    pub fn control(&self) -> Arc<Control> {
+        self.control.clone()
+    }
+
+    /// Carries out what's been sent through `control` since the last
+    /// operation.
+    pub(crate) fn apply_commands(&mut self) {
+        for command in self.control.take() {
+            debug!("command {:?}", command);
+            match command {
+                Command::FlushCache => {
+                    let control = self.control.clone();
+                    self.entries.retain(|ino, _| {
+                        *ino != INO_ROOT && *ino < INO_VIRTUAL && control.is_pinned(ino - INO_ROOT)
+                    });
+                    self.clear_listing_state();
+                    self.parts.clear();
+                    self.forget_fetched();
+                }
+                Command::Refresh(section, item) => {
+                    if self.sections.iter().any(|s| s.key == section) {
+                        let listings = item.and_then(|key| self.listings_with(INO_ROOT + key))
+                            .unwrap_or_else(|| self.section_listings(section));
+                        for ino in listings {
+                            self.drop_listing(ino);
+                        }
+                        self.usage = None;
+                        self.forget_fetched();
+                    }
+                }
+                Command::Pin(rating_key) => {
+                    let ino = INO_ROOT + rating_key;
+                    if !self.entries.contains_key(&ino) || self.is_stale(ino) {
+                        self.refresh(ino);
+                    }
+                }
+                Command::Prefetch => self.prefetch(),
+                Command::Relist(ino) => self.refresh_step(ino),
+            }
+        }
+        Stats::set(&self.control.stats.cached_dirs, self.entries.len() as u64);
+    }
+
+    /// The entries of a directory as `readdir` reports them: sorted by
+    /// name, or for a section in the metadata layout, in the server's order.
+    /// With `server_order`, items are in the server's order everywhere,
+    /// after anything else in the directory.
+    pub fn children(&mut self, ino: u64) -> Vec<(OsString, u64, FileType)> {
+        self.listing(ino, usize::MAX)
+    }
+
+    /// The first `wanted` entries of a directory, or all of them if there
+    /// are fewer. Section listings are fetched only as far as needed, so the
+    /// start of a large one can be read without waiting for the rest.
+    pub fn listing(&mut self, ino: u64, wanted: usize) -> Vec<(OsString, u64, FileType)> {
+        self.listing_from(ino, 0, wanted)
+    }
+
+    /// Entries from `start` on of the first `wanted` of a directory, for
+    /// reading more of a section listing without copying what's been read.
+    fn listing_from(&mut self, ino: u64, start: usize, wanted: usize) -> Vec<(OsString, u64, FileType)> {
+        self.ensure_dir(ino);
+        while self.order.get(&ino).map_or(false, |order| order.len() < wanted) && self.fetch_page(ino) {}
+
+        let en = match self.entries.get(&ino) {
+            Some(en) => en,
+            None => return vec![]
+        };
+        let children: Vec<(&OsString, &Entry)> = match self.order.get(&ino) {
+            Some(order) => order[cmp::min(start, order.len())..cmp::min(wanted, order.len())].iter()
+                .filter_map(|name| en.get(name).map(|e| (name, e)))
+                .collect(),
+            None => {
+                let mut children: Vec<_> = en.iter().collect();
+                children.sort_by(|a, b| a.0.cmp(b.0));
+                children.into_iter().skip(start).collect()
+            }
+        };
+        self.attrs.extend(children.iter().filter_map(|(_, e)| e.attr.map(|attr| (e.ino, attr))));
+        children.into_iter().map(|(name, e)| (name.clone(), e.ino, e.kind)).collect()
+    }
+
+    /// Adds the next pages of a partially fetched section listing: the
+    /// first on its own, and after that, once the section's size is known,
+    /// as many at once as there are connections for. Returns false once
+    /// there's nothing left to fetch.
+    fn fetch_page(&mut self, ino: u64) -> bool {
+        let (section, start, total) = match self.partial.get(&ino) {
+            Some(partial) => (partial.section.clone(), partial.start, partial.total),
+            None => return false
+        };
+        let page_size = self.options.page_size;
+        let starts: Vec<u64> = match total {
+            Some(total) => (0..cmp::max(1, self.api.max_connections() as u64))
+                .map(|i| start + i * page_size)
+                .take_while(|start| *start < total)
+                .collect(),
+            None => vec![start]
+        };
+        let results = fetch_pages(&*self.api, &section, &starts, page_size);
+        if let (Some(Err(e)), None) = (results.first(), total) {
+            // Nothing of the section was listed, so the listing failed.
+            warn!("listing section {}: {}", section.key, e);
+            self.failed.insert(ino, errno(e));
+            self.entries.remove(&ino);
+            self.partial.remove(&ino);
+            self.order.remove(&ino);
+            return false;
+        }
+
+        let grouped = self.groups_compilations(&section);
+        let mut en = self.entries.remove(&ino).unwrap_or_default();
+        let mut total = total.unwrap_or(0);
+        for (start, result) in starts.iter().zip(results) {
+            match result {
+                Ok((page, size)) => {
+                    total = size;
+                    self.add_page(ino, &mut en, page, grouped);
+                }
+                // As with full listings, a page that fails is left out.
+                Err(e) => warn!("listing section {} from {}: {}", section.key, start, e)
+            }
+        }
+        self.inherit_scope(ino, &en);
+        self.insert_listing(ino, en);
+
+        let start = start + starts.len() as u64 * page_size;
+        if start < total {
+            if let Some(partial) = self.partial.get_mut(&ino) {
+                partial.start = start;
+                partial.total = Some(total);
+            }
+            true
+        } else {
+            self.partial.remove(&ino);
+            false
+        }
+    }
+
+    /// Adds a page of a section's items to `en`, its listing `ino`, after
+    /// those of the pages before it.
+    fn add_page(&mut self, ino: u64, en: &mut HashMap<OsString, Entry>, mut page: api::MediaContainer, grouped: bool) {
+        self.building = ino;
+        if grouped {
+            // It's listed as `Various Artists/` instead.
+            page.items.retain(|item| !is_various_artists(item));
+        }
+        let known: HashMap<OsString, u64> = en.iter().map(|(name, e)| (name.clone(), e.ino)).collect();
+        self.add_item_entries(en, &page);
+        // The page's order is taken from its items below.
+        self.item_order.clear();
+        let names: HashMap<u64, OsString> = en.iter().map(|(name, e)| (e.ino, name.clone())).collect();
+        let known_inos: HashSet<u64> = known.values().cloned().collect();
+        let added: HashMap<u64, OsString> = names.iter()
+            .filter(|(ino, _)| !known_inos.contains(*ino))
+            .map(|(ino, name)| (*ino, name.clone()))
+            .collect();
+        let order = self.order.entry(ino).or_default();
+        // Items of earlier pages can be renamed to tell them apart from
+        // this page's.
+        for listed in order.iter_mut() {
+            if let Some(name) = known.get(listed).and_then(|ino| names.get(ino)) {
+                *listed = name.clone();
+            }
+        }
+        let mut listed: Vec<OsString> = page.items.iter()
+            .filter_map(|item| added.get(&(INO_ROOT + item.rating_key())).cloned())
+            .collect();
+        // Files beside items, such as subtitles, follow the page's items.
+        let mut others: Vec<OsString> = added.into_iter()
+            .filter(|(ino, _)| *ino >= INO_VIRTUAL)
+            .map(|(_, name)| name)
+            .collect();
+        others.sort();
+        listed.extend(others);
+        order.extend(listed);
+    }
+
+    /// The attributes `getattr` reports for `ino`. Those of entries already
+    /// listed or looked up are reused; others are fetched.
+    pub fn attr(&self, ino: u64) -> Result<FileAttr> {
+        if ino == INO_ROOT {
+            return Ok(self.finish_attr(ROOT_DIR_ATTR));
+        }
+        if let Some(attr) = self.attrs.get(&ino) {
+            return Ok(self.finish_attr(*attr));
+        }
+
+        let (rating_key, index) = match self.nodes.get(&ino) {
+            Some(Node::Version(rating_key, index)) => (*rating_key, Some(*index)),
+            Some(Node::Generated(_)) => return Ok(file_attr(ino, self.artwork_sizes.get(&ino).cloned().unwrap_or(0))),
+            Some(_) => return Ok(self.finish_attr(dir_attr(ino))),
+            None => (ino - INO_ROOT, None)
+        };
+
+        let container = self.api.metadata(rating_key)?;
+        let item = container.items.get(0).ok_or_else(|| not_found(format!("no item {}", rating_key)))?;
+        let media = match index {
+            Some(index) => item.media().get(index),
+            None => self.options.filter.select(item)
+        };
+        to_attr(ino, item, media, &self.options.times)
+            .map(|attr| self.finish_attr(attr))
+            .ok_or_else(|| not_found(format!("no media for inode {}", ino)))
+    }
+
+    /// Whether `ino` is a file rendered by plexfs rather than one on the
+    /// server.
+    pub fn is_generated(&self, ino: u64) -> bool {
+        match self.nodes.get(&ino) {
+            Some(Node::Generated(_)) => true,
+            _ => false
+        }
+    }
+
+    /// The rating key of the Plex item at `ino`, if it is one.
+    fn item_key(&self, ino: u64) -> Option<u64> {
+        match self.nodes.get(&ino) {
+            Some(Node::Version(rating_key, _)) => Some(*rating_key),
+            Some(_) => None,
+            None if ino == INO_ROOT => None,
+            None => Some(ino - INO_ROOT)
+        }
+    }
+
+    /// The full Plex metadata of the item at `ino`, as JSON.
+    pub fn metadata_json(&self, ino: u64) -> Result<String> {
+        let rating_key = self.item_key(ino).ok_or_else(|| not_found("not a Plex item".into()))?;
+        self.api.metadata_json(rating_key)
+    }
+
+    /// The extended attributes of `ino`, from its item's metadata. Inodes
+    /// that aren't items have none.
+    fn item_xattrs(&mut self, ino: u64) -> Result<&[(&'static str, String)]> {
+        let rating_key = match self.item_key(ino) {
+            Some(rating_key) => rating_key,
+            None => return Ok(&[])
+        };
+        if !self.xattrs.contains_key(&ino) {
+            let container = self.api.metadata(rating_key)?;
+            let item = container.items.get(0).ok_or_else(|| not_found(format!("no item {}", rating_key)))?;
+            self.xattrs.insert(ino, xattrs(item));
+        }
+        Ok(&self.xattrs[&ino])
+    }
+
+    /// The names of the extended attributes of `ino`, each followed by a
+    /// NUL, as `listxattr` returns them.
+    pub fn xattr_names(&mut self, ino: u64) -> Result<Vec<u8>> {
+        let mut names = vec![];
+        for (name, _) in self.item_xattrs(ino)? {
+            names.extend_from_slice(XATTR_PREFIX.as_bytes());
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    /// The value of the extended attribute `name` of `ino`, None if it has
+    /// no such attribute.
+    pub fn xattr(&mut self, ino: u64, name: &OsStr) -> Result<Option<Vec<u8>>> {
+        let name = match name.to_str().and_then(|name| name.strip_prefix(XATTR_PREFIX)) {
+            Some(name) => name,
+            None => return Ok(None)
+        };
+        let value = self.item_xattrs(ino)?.iter()
+            .find(|(other, _)| *other == name)
+            .map(|(_, value)| value.clone().into_bytes());
+        Ok(value)
+    }
+
+    /// Returns a file handle and FUSE open flags for `ino`.
+    pub fn open_file(&mut self, ino: u64) -> Result<(u64, u32)> {
+        // Generated files are rendered once per open and read with direct I/O,
+        // since their size isn't known up front.
+        match self.nodes.get(&ino) {
+            Some(Node::Generated(file)) => {
+                let file = *file;
+                let content = match self.artwork.remove(&ino) {
+                    Some(content) => content,
+                    None => self.render(file)?
+                };
+                if let Generated::Cover(_) | Generated::Poster(_) = file {
+                    self.artwork_sizes.insert(ino, content.len() as u64);
+                }
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.handles.insert(fh, content);
+                Ok((fh, FOPEN_DIRECT_IO))
+            }
+            _ => {
+                // Resolved once here, rather than by each read.
+                let part = self.part(ino)?;
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.open_files.insert(fh, OpenFile {
+                    part: part,
+                    stream: Arc::new(Mutex::new(Stream::new(self.options.readahead))),
+                });
+                Ok((fh, 0))
+            }
+        }
+    }
+
+    /// Reads up to `size` bytes at `offset`, returning fewer only at the end
+    /// of the file.
+    pub fn read_file(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+        match self.begin_read(ino, fh, offset, size)? {
+            PendingRead::Ready(body) => Ok(body),
+            PendingRead::Remote(part, offset, size, stream) => {
+                let sources = Sources { stream: stream.as_ref(), cache: self.cache_for(ino).map(|cache| &**cache) };
+                fetch_range(&self.api, &self.control, &part, offset, size, sources)
+            }
+        }
+    }
+
+    /// Like `read_file`, but replies from a reader thread when the data has
+    /// to come from the server, so a slow request doesn't hold up other
+    /// operations.
+    pub fn read_into(&mut self, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
+        match self.begin_read(ino, fh, offset, size) {
+            Ok(PendingRead::Ready(body)) => reply.data(&body),
+            Ok(PendingRead::Remote(part, offset, size, stream)) => {
+                let (api, control, cache) = (self.api.clone(), self.control.clone(), self.cache_for(ino).cloned());
+                self.readers.spawn(move || {
+                    let sources = Sources { stream: stream.as_ref(), cache: cache.as_deref() };
+                    match fetch_range(&api, &control, &part, offset, size, sources) {
+                        Ok(body) => reply.data(&body),
+                        Err(e) => {
+                            warn!("read of {} at {} failed: {}", part.key, offset, e);
+                            reply.error(errno(&e))
+                        }
+                    }
+                });
+            }
+            Err(e) => reply.error(errno(&e))
+        }
+    }
+
+    /// Everything about a read that needs `self`: the bytes, if they don't
+    /// come from the server, or what to request.
+    fn begin_read(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<PendingRead> {
+        if ino == INO_ROOT {
+            return Err(anyhow!("is a directory"));
+        }
+
+        self.touch(ino);
+
+        if let Some(content) = self.handles.get(&fh) {
+            let start = cmp::min(offset as usize, content.len());
+            let end = cmp::min(start + size as usize, content.len());
+            return Ok(PendingRead::Ready(content[start..end].to_vec()));
+        }
+
+        let (part, stream) = match self.open_files.get(&fh) {
+            Some(file) => (file.part.clone(), Some(file.stream.clone())),
+            None => (self.part(ino)?, None)
+        };
+        // Reading past the end would get a 416 from the server.
+        let size = if part.size > 0 {
+            if offset >= part.size {
+                return Ok(PendingRead::Ready(vec![]));
+            }
+            cmp::min(size as u64, part.size - offset) as u32
+        } else {
+            size
+        };
+        Ok(PendingRead::Remote(part, offset, size, stream))
+    }
+
+    pub fn release_file(&mut self, fh: u64) {
+        self.handles.remove(&fh);
+        self.open_files.remove(&fh);
+    }
+
+    /// Makes the item at `spec` the root of the filesystem. It's a path
+    /// from the section's root, such as an artist named "1999", or failing
+    /// that a rating key; `key:` in front makes it a rating key either way.
+    pub fn set_root(&mut self, spec: &str) -> Result<()> {
+        if self.options.layout != Layout::Metadata {
+            return Err(anyhow!("a root item can only be used with the metadata layout"));
+        }
+        let rating_key = match spec.strip_prefix("key:") {
+            Some(key) => key.parse().map_err(|_| anyhow!("\"{}\" isn't a rating key", key))?,
+            None => match self.resolve(Path::new(spec)) {
+                Some((ino, FileType::Directory)) if ino != INO_ROOT && ino < INO_VIRTUAL => ino - INO_ROOT,
+                Some(_) => return Err(anyhow!("\"{}\" isn't an item directory", spec)),
+                None => spec.parse().map_err(|_| anyhow!("\"{}\" not found", spec))?
+            }
+        };
+        self.options.root = Some(Root::Item(rating_key));
+        self.entries.clear();
+        self.clear_listing_state();
+        Ok(())
+    }
+
+    /// Makes the playlist `spec`, a rating key or title, the root of the
+    /// filesystem.
+    pub fn set_playlist(&mut self, spec: &str) -> Result<()> {
+        let rating_key = match spec.parse() {
+            Ok(rating_key) => rating_key,
+            Err(_) => {
+                let playlists = self.api.playlists()?;
+                match playlists.iter().find(|p| p.title == spec) {
+                    Some(playlist) => playlist.rating_key,
+                    None => {
+                        let titles: Vec<&str> = playlists.iter().map(|p| &p.title[..]).collect();
+                        return Err(anyhow!("no playlist named \"{}\" (playlists: {})", spec, titles.join(", ")));
+                    }
+                }
+            }
+        };
+        self.options.root = Some(Root::Playlist(rating_key));
+        self.entries.clear();
+        self.clear_listing_state();
+        Ok(())
+    }
+
+    /// Walks `path` from the root through the same entries `lookup` uses.
+    pub fn resolve(&mut self, path: &Path) -> Option<(u64, FileType)> {
+        let mut ino = INO_ROOT;
+        let mut kind = FileType::Directory;
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => (),
+                Component::Normal(name) => {
+                    let entry = self.find(ino, name)?;
+                    ino = entry.ino;
+                    kind = entry.kind;
+                }
+                _ => return None
+            }
+        }
+        Some((ino, kind))
+    }
+
+    /// The attributes `lookup` reports for `name` in `parent`.
+    pub fn entry_attr(&mut self, parent: u64, name: &OsStr) -> Option<FileAttr> {
+        let mut attr = self.find(parent, name).and_then(|entry| entry.attr)?;
+        if let Some(size) = self.artwork_size(attr.ino) {
+            // Copying tools go by the size, so artwork is fetched to find it.
+            attr.size = size;
+            if let Some(entry) = self.entries.get_mut(&parent).and_then(|en| en.get_mut(name)) {
+                entry.attr = Some(attr);
+            }
+        }
+        self.attrs.insert(attr.ino, attr);
+        Some(self.finish_attr(attr))
+    }
+
+    /// The size of `ino` if it's artwork, fetching it if that isn't known
+    /// yet. It's kept for the open that's likely to follow.
+    fn artwork_size(&mut self, ino: u64) -> Option<u64> {
+        let file = match self.nodes.get(&ino) {
+            Some(Node::Generated(file @ Generated::Cover(_))) | Some(Node::Generated(file @ Generated::Poster(_))) => *file,
+            _ => return None
+        };
+        if let Some(size) = self.artwork_sizes.get(&ino) {
+            return Some(*size);
+        }
+        match self.render(file) {
+            Ok(content) => {
+                // Directories can be listed by the thousand without any of
+                // their artwork being opened.
+                if self.artwork.len() >= MAX_PENDING_ARTWORK {
+                    self.artwork.clear();
+                }
+                self.artwork_sizes.insert(ino, content.len() as u64);
+                self.artwork.insert(ino, content);
+                self.artwork_sizes.get(&ino).cloned()
+            }
+            Err(e) => {
+                warn!("fetching artwork for {}: {}", ino, e);
+                None
+            }
+        }
+    }
+
+    /// Looks up `name` in `parent`, listing the parent first if needed.
+    fn find(&mut self, parent: u64, name: &OsStr) -> Option<&Entry> {
+        // The kernel may look up a name in a directory that was never listed,
+        // e.g. after a cache flush.
+        self.ensure_dir(parent);
+
+        // The name may be on a page of the listing not fetched yet.
+        while !self.entries.get(&parent)?.contains_key(name) && self.fetch_page(parent) {}
+
+        let missing = match self.entries.get(&parent) {
+            Some(en) => !en.contains_key(name),
+            None => return None
+        };
+        // `.metadata/` only lists items seen so far, and `.plexfs/subtitles/`
+        // nothing, but any item can be looked up in them by key.
+        let by_key = match self.nodes.get(&parent) {
+            Some(Node::MetadataDir) => name.to_str()?.strip_suffix(".json")
+                .and_then(|key| key.parse().ok())
+                .map(Generated::Metadata),
+            Some(Node::SubtitlesDir) => name.to_str()?.parse().ok().map(Generated::SubtitleSearch),
+            _ => None
+        };
+        if let (true, Some(file)) = (missing, by_key) {
+            let ino = self.virtual_ino(Node::Generated(file));
+            let entry = Entry { ino: ino, kind: FileType::RegularFile, attr: Some(file_attr(ino, 0)) };
+            self.entries.get_mut(&parent)?.insert(name.to_owned(), entry);
+            self.parents.insert(ino, parent);
+        }
+
+        self.entries.get(&parent)?.get(name)
+    }
+
+    /// The directory `ino` was last listed in, or the root if it hasn't
+    /// been listed anywhere.
+    pub fn parent(&self, ino: u64) -> u64 {
+        self.parents.get(&ino).cloned().unwrap_or(INO_ROOT)
+    }
+
+    /// Caches `en` as the listing of `ino`, which its entries are now in,
+    /// returning the listing it replaces.
+    fn insert_listing(&mut self, ino: u64, en: HashMap<OsString, Entry>) -> Option<HashMap<OsString, Entry>> {
+        for entry in en.values() {
+            self.parents.insert(entry.ino, ino);
+        }
+        self.entries.insert(ino, en)
+    }
+
+    /// Lists `ino` if it hasn't been. A stale listing is still served while
+    /// it's loaded again in the background.
+    fn ensure_dir(&mut self, ino: u64) {
+        if !self.entries.contains_key(&ino) {
+            self.list_dir(ino);
+        } else if self.is_stale(ino) {
+            self.refresh(ino);
+        }
+    }
+
+    /// Loads the listing of `ino` in the background: it's built without
+    /// waiting on the server, from responses fetched on `fetchers`, and
+    /// replaces whatever's cached once nothing it needs is missing.
+    fn refresh(&mut self, ino: u64) {
+        if !self.refreshing.contains_key(&ino) {
+            self.refreshing.insert(ino, Arc::new(Deferred::default()));
+            self.refresh_step(ino);
+        }
+    }
+
+    /// Builds the listing of `ino` from what's been fetched for it, and has
+    /// whatever else the build asked for fetched, to build it again with
+    /// once that's in.
+    fn refresh_step(&mut self, ino: u64) {
+        let job = match self.refreshing.get(&ino) {
+            Some(job) => job.clone(),
+            None => return
+        };
+        if !self.entries.contains_key(&ino) || self.is_stale(ino) {
+            deferred::with(Some(job.clone()), || scheduler::background(|| self.list_dir(ino)));
+        }
+        let missed = job.take_missed();
+        if missed.is_empty() {
+            self.refreshing.remove(&ino);
+            return;
+        }
+        for request in missed {
+            let (api, control, job) = (self.api.clone(), self.control.clone(), job.clone());
+            self.fetchers.spawn(move || {
+                let body = scheduler::background(|| api.fetch_body(&request));
+                if job.store(request, body) {
+                    control.send(Command::Relist(ino));
+                }
+            });
+        }
+    }
+
+    /// Has listings being loaded in the background fetch everything again,
+    /// after the server's reported changes.
+    fn forget_fetched(&self) {
+        for job in self.refreshing.values() {
+            job.forget();
+        }
+    }
+
+    /// Lists `ino` from the server, replacing any listing already cached.
+    /// Inodes are derived from rating keys, so entries that are still there
+    /// keep theirs.
+    fn list_dir(&mut self, ino: u64) {
+        self.listing_error.set(None);
+        self.item_order.clear();
+        self.building = ino;
+        let en = self.build_dir(ino);
+        let items = mem::take(&mut self.item_order);
+        if deferred::current().map_or(false, |job| job.has_missed()) {
+            // Built in the background from what had been fetched, which
+            // wasn't everything; it's built again once the rest is in.
+            return;
+        }
+        if let Some(errno) = self.listing_error.take() {
+            // Whatever was listed before is still better than nothing.
+            self.failed.insert(ino, errno);
+            return;
+        }
+        self.failed.remove(&ino);
+        // Section items are fetched a page at a time, as they're needed; what
+        // was built is only the virtual directories next to them.
+        match self.lazy_section(ino) {
+            Some(section) => {
+                let mut order: Vec<OsString> = en.keys().cloned().collect();
+                order.sort();
+                self.order.insert(ino, order);
+                self.partial.insert(ino, Partial { section: section, start: 0, total: None });
+            }
+            None => {
+                // These are ordered by when things were added or watched.
+                let by_time = match self.nodes.get(&ino) {
+                    Some(Node::RecentlyAdded) | Some(Node::OnDeck) => true,
+                    _ => false
+                };
+                let by_date = self.album_order(ino) == AlbumOrder::ReleaseDate
+                    && en.values().any(|e| self.release_dates.contains_key(&e.ino));
+                if (self.options.server_order || by_time) && !items.is_empty() {
+                    self.order.insert(ino, server_order(&en, &items));
+                } else if by_date {
+                    self.order.insert(ino, date_order(&en, &self.release_dates));
+                } else {
+                    self.order.remove(&ino);
+                }
+                self.partial.remove(&ino);
+            }
+        }
+        self.inherit_scope(ino, &en);
+        // A directory listed again has its own and its entries' extended
+        // attributes fetched again too.
+        self.xattrs.remove(&ino);
+        if let Some(old) = self.insert_listing(ino, en) {
+            for entry in old.values() {
+                self.attrs.remove(&entry.ino);
+                self.xattrs.remove(&entry.ino);
+            }
+        }
+        self.listed.insert(ino, Instant::now());
+        self.outdated.remove(&ino);
+        Stats::set(&self.control.stats.cached_dirs, self.entries.len() as u64);
+    }
+
+    /// Totals over the mounted sections' media. The first call starts
+    /// counting them in the background, since that means listing every file,
+    /// and returns zeros until it's done; later ones return the last count
+    /// until a section is refreshed.
+    pub fn usage(&mut self) -> Usage {
+        if let Some(usage) = &self.usage {
+            return usage.lock().unwrap().unwrap_or_default();
+        }
+        let usage = Arc::new(Mutex::new(None));
+        self.usage = Some(usage.clone());
+
+        let (api, sections) = (self.api.clone(), self.sections.clone());
+        let (filter, page_size) = (self.options.filter.clone(), self.options.page_size);
+        thread::spawn(move || scheduler::background(|| {
+            let mut total = Usage::default();
+            for section in sections.iter() {
+                let (key, type_id) = (section.key, section.kind.leaf_type());
+                // One page at a time, on this thread, so it stays background
+                // work.
+                let result = for_each_page(page_size, 1,
+                                           |start, size| api.all_of_type(key, type_id, start, size),
+                                           |page| for media in page.items.iter().flat_map(|item| item.media()) {
+                                               if filter.accepts(media) {
+                                                   total.bytes += media.part.size;
+                                                   total.files += 1;
+                                               }
+                                           });
+                if let Err(e) = result {
+                    warn!("counting section {}: {}", key, e);
+                }
+            }
+            debug!("usage: {} files, {} bytes", total.files, total.bytes);
+            *usage.lock().unwrap() = Some(total);
+        }));
+        Usage::default()
+    }
+
+    /// Downloads the mounted sections' media into the chunk cache from
+    /// another thread. Its requests are background work, so it waits while
+    /// anything is read through the mount. It stops once the cache would be
+    /// full, since going on would only evict what it fetched. Does nothing
+    /// without a cache, or while a prefetch is already running.
+    pub fn prefetch(&self) {
+        let cache = match &self.cache {
+            Some(cache) => cache.clone(),
+            None => {
+                warn!("nothing to prefetch into without a cache directory");
+                return;
+            }
+        };
+        if self.prefetching.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let sections: Vec<Section> = self.sections.iter()
+            .filter(|section| {
+                let scope = Scope { section: Some(section.key), view: None };
+                self.scope_policies(scope).iter().find_map(|policy| policy.cache) != Some(false)
+            })
+            .cloned()
+            .collect();
+        let (api, prefetching) = (self.api.clone(), self.prefetching.clone());
+        let (filter, page_size, rate) = (self.options.filter.clone(), self.options.page_size, self.options.prefetch_rate);
+        thread::spawn(move || scheduler::background(|| {
+            let mut queued = 0;
+            'sections: for section in sections.iter() {
+                let (key, type_id) = (section.key, section.kind.leaf_type());
+                let mut parts = vec![];
+                let result = for_each_page(page_size, 1,
+                                           |start, size| api.all_of_type(key, type_id, start, size),
+                                           |page| parts.extend(page.items.iter()
+                                                               .filter_map(|item| filter.select(item))
+                                                               .map(|media| media.part.clone())));
+                if let Err(e) = result {
+                    warn!("listing section {} to prefetch: {}", key, e);
+                }
+                for part in parts {
+                    queued += part.size;
+                    if queued > cache.max_size() {
+                        debug!("prefetch: cache full");
+                        break 'sections;
+                    }
+                    if let Err(e) = prefetch_part(&*api, &cache, &part, rate) {
+                        warn!("prefetching {}: {}", part.key, e);
+                    }
+                }
+            }
+            debug!("prefetch: done");
+            prefetching.store(false, Ordering::SeqCst);
+        }));
+    }
+
+    /// The errno to report for `ino`, a directory that couldn't be listed
+    /// and has no older listing to show instead.
+    pub fn failure(&self, ino: u64) -> Option<c_int> {
+        match self.entries.contains_key(&ino) {
+            true => None,
+            false => self.failed.get(&ino).cloned()
+        }
+    }
+
+    /// Records that a listing being built failed, so `list_dir` doesn't
+    /// cache it.
+    fn listing_failed(&self, e: anyhow::Error) {
+        warn!("listing failed: {}", e);
+        self.listing_error.set(Some(errno(&e)));
+    }
+
+    /// Drops the listings that have the item with `rating_key` in them, so
+    /// they're fetched again with what's changed about it.
+    fn forget_item(&mut self, rating_key: u64) {
+        let ino = INO_ROOT + rating_key;
+        let dirs: Vec<u64> = self.entries.iter()
+            .filter(|(_, en)| en.values().any(|e| e.ino == ino))
+            .map(|(dir, _)| *dir)
+            .collect();
+        for dir in dirs {
+            self.entries.remove(&dir);
+        }
+        self.clear_listing_state();
+        self.parts.remove(&ino);
+    }
+
+    /// Drops what's kept alongside `entries` for directories no longer in it.
+    fn clear_listing_state(&mut self) {
+        self.attrs.clear();
+        self.xattrs.clear();
+        let entries = &self.entries;
+        self.order.retain(|ino, _| entries.contains_key(ino));
+        self.failed.clear();
+        self.partial.retain(|ino, _| entries.contains_key(ino));
+    }
+
+    /// The listings `ino` is in, those they're in, and so on up, along with
+    /// its own. None if it isn't in any.
+    fn listings_with(&self, ino: u64) -> Option<HashSet<u64>> {
+        let mut listings = HashSet::new();
+        let mut pending = vec![ino];
+        while let Some(child) = pending.pop() {
+            for (parent, en) in &self.entries {
+                if en.values().any(|e| e.ino == child) && listings.insert(*parent) {
+                    pending.push(*parent);
+                }
+            }
+        }
+        if listings.is_empty() {
+            return None;
+        }
+        if self.entries.contains_key(&ino) {
+            listings.insert(ino);
+        }
+        Some(listings)
+    }
+
+    /// The listings in `section`, and those of views across sections, which
+    /// may have its items in them.
+    fn section_listings(&self, section: u64) -> HashSet<u64> {
+        self.entries.keys()
+            .filter(|ino| match self.scope(**ino) {
+                Some(Scope { section: Some(key), .. }) => key == section,
+                Some(Scope { section: None, view: Some(_) }) => true,
+                _ => false
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Drops the listing of `ino` and what's cached about its entries. A
+    /// pinned listing is kept, and loaded again in the background.
+    fn drop_listing(&mut self, ino: u64) {
+        if ino != INO_ROOT && ino < INO_VIRTUAL && self.control.is_pinned(ino - INO_ROOT) {
+            self.outdated.insert(ino);
+            self.refresh(ino);
+            return;
+        }
+        if let Some(en) = self.entries.remove(&ino) {
+            for entry in en.values() {
+                self.attrs.remove(&entry.ino);
+                self.xattrs.remove(&entry.ino);
+                self.parts.remove(&entry.ino);
+            }
+        }
+        self.xattrs.remove(&ino);
+        self.order.remove(&ino);
+        self.partial.remove(&ino);
+        self.failed.remove(&ino);
+        self.listed.remove(&ino);
+    }
+
+    /// Whether the listing of `ino` is older than `dir_ttl`, or is pinned
+    /// and has changed on the server since it was listed. Listings made along
+    /// with another directory's have no time of their own, and are refreshed
+    /// with that directory.
+    fn is_stale(&self, ino: u64) -> bool {
+        if self.outdated.contains(&ino) {
+            return true;
+        }
+        let ttl = self.policies(ino).iter()
+            .find_map(|policy| policy.dir_ttl)
+            .unwrap_or(self.options.dir_ttl);
+        match (ttl, self.listed.get(&ino)) {
+            (Some(ttl), Some(listed)) => listed.elapsed() >= ttl,
+            _ => false
+        }
+    }
+
+    /// The section and view `ino` is in. Sections and views are their own;
+    /// anything else is in those of the directory it was last listed in.
+    fn scope(&self, ino: u64) -> Option<Scope> {
+        let section = |key| Some(Scope { section: Some(key), view: None });
+        match self.nodes.get(&ino) {
+            None if ino == INO_ROOT => match (&self.options.root, &self.sections[..]) {
+                (None, [only]) => section(only.key),
+                _ => None
+            },
+            Some(Node::Section(key)) | Some(Node::Letter(key, _)) | Some(Node::Folder(key, _))
+            | Some(Node::ByDate(key)) | Some(Node::Period(key, _, _)) | Some(Node::SectionFolder(key, _))
+            | Some(Node::VariousArtists(key)) => section(*key),
+            Some(Node::View(key, view)) => Some(Scope { section: Some(*key), view: Some(*view) }),
+            Some(Node::Genre(key, _)) => Some(Scope { section: Some(*key), view: Some(View::Genres) }),
+            Some(Node::Playlists) | Some(Node::Playlist(_)) => Some(Scope { section: None, view: Some(View::Playlists) }),
+            Some(Node::RecentlyAdded) => Some(Scope { section: None, view: Some(View::RecentlyAdded) }),
+            Some(Node::OnDeck) => Some(Scope { section: None, view: Some(View::OnDeck) }),
+            _ => self.scopes.get(&ino).cloned()
+        }
+    }
+
+    /// Records that the entries of `en`, the listing of `ino`, are in its
+    /// scope, as are those of listings built along with it.
+    fn inherit_scope(&mut self, ino: u64, en: &HashMap<OsString, Entry>) {
+        let scope = match self.scope(ino) {
+            Some(scope) => scope,
+            None => return
+        };
+        let mut pending: Vec<u64> = en.values().map(|e| e.ino).collect();
+        let mut seen = HashSet::new();
+        while let Some(child) = pending.pop() {
+            if !seen.insert(child) {
+                continue;
+            }
+            self.scopes.insert(child, scope);
+            // Listings made on their own have their own scope.
+            if self.listed.contains_key(&child) {
+                continue;
+            }
+            if let Some(en) = self.entries.get(&child) {
+                pending.extend(en.values().map(|e| e.ino));
+            }
+        }
+    }
+
+    /// The configured policies for what's in `scope`, most specific first:
+    /// its view's, then its section's.
+    fn scope_policies(&self, scope: Scope) -> Vec<&Policy> {
+        let view = scope.view
+            .and_then(|view| self.options.view_policies.iter().find(|(v, _)| *v == view))
+            .map(|(_, policy)| policy);
+        let section = scope.section
+            .and_then(|key| self.sections.iter().find(|s| s.key == key))
+            .and_then(|section| self.options.section_policies.iter()
+                      .find(|(spec, _)| *spec == section.key.to_string() || *spec == section.title))
+            .map(|(_, policy)| policy);
+        view.into_iter().chain(section).collect()
+    }
+
+    fn policies(&self, ino: u64) -> Vec<&Policy> {
+        self.scope(ino).map_or(vec![], |scope| self.scope_policies(scope))
+    }
+
+    fn album_order(&self, ino: u64) -> AlbumOrder {
+        self.policies(ino).iter()
+            .find_map(|policy| policy.album_order)
+            .unwrap_or(self.options.album_order)
+    }
+
+    fn year_prefix(&self, ino: u64) -> bool {
+        self.policies(ino).iter()
+            .find_map(|policy| policy.year_prefix)
+            .unwrap_or(self.options.year_prefix)
+    }
+
+    /// The chunk cache reads of `ino` go through, unless its policy keeps
+    /// its data out of the cache.
+    fn cache_for(&self, ino: u64) -> Option<&Arc<ChunkCache>> {
+        match self.policies(ino).iter().find_map(|policy| policy.cache) {
+            Some(false) => None,
+            _ => self.cache.as_ref()
+        }
+    }
+
+    /// A listed directory's mtime is that of its most recently added or
+    /// updated child.
+    fn dir_mtime(&self, ino: u64) -> Option<SystemTime> {
+        self.entries.get(&ino)?.values()
+            .filter_map(|e| e.attr)
+            .map(|a| cmp::max(a.mtime, a.ctime))
+            .max()
+    }
+
+    /// Fills in a directory's mtime, size and link count from its listing.
+    /// Until it's listed, the size comes from Plex's child counts and nlink
+    /// is 1, which tells `find` not to assume it knows the number of
+    /// subdirectories. So does a listing still being fetched a page at a
+    /// time.
+    fn with_dir_stats(&self, mut attr: FileAttr) -> FileAttr {
+        if let FileType::Directory = attr.kind {
+            if let Some(mtime) = self.dir_mtime(attr.ino) {
+                attr.mtime = mtime;
+            }
+            if let Some(en) = self.entries.get(&attr.ino) {
+                if !self.partial.contains_key(&attr.ino) {
+                    let subdirs = en.values().filter(|e| match e.kind {
+                        FileType::Directory => true,
+                        _ => false
+                    }).count();
+                    attr.nlink = 2 + subdirs as u32;
+                }
+                attr.size = en.len() as u64;
+            }
+        }
+        attr
+    }
+
+    fn with_atime(&self, mut attr: FileAttr) -> FileAttr {
+        match self.options.atime {
+            AtimeMode::Plex => (),
+            AtimeMode::NoAtime => attr.atime = attr.mtime,
+            AtimeMode::RelAtime => {
+                if let Some(atime) = self.atimes.get(&attr.ino) {
+                    attr.atime = cmp::max(attr.atime, *atime);
+                }
+            }
+        }
+        attr
+    }
+
+    fn finish_attr(&self, attr: FileAttr) -> FileAttr {
+        self.with_atime(self.with_dir_stats(attr))
+    }
+
+    fn touch(&mut self, ino: u64) {
+        if let AtimeMode::RelAtime = self.options.atime {
+            let now = SystemTime::now();
+            let stale = match self.atimes.get(&ino) {
+                Some(atime) => now.duration_since(*atime).map(|d| d >= times::RELATIME_INTERVAL).unwrap_or(false),
+                None => true
+            };
+            if stale {
+                self.atimes.insert(ino, now);
+            }
+        }
+    }
+
+    /// Returns the inode for `node`, allocating one on first use.
+    fn virtual_ino(&mut self, node: Node) -> u64 {
+        if let Some(ino) = self.inos.get(&node) {
+            return *ino;
+        }
+        let ino = INO_VIRTUAL + self.nodes.len() as u64;
+        self.nodes.insert(ino, node.clone());
+        self.inos.insert(node, ino);
+        ino
+    }
+
+    /// The Part to stream for `ino`, resolved at most once per `PART_TTL` so
+    /// sequential reads don't each cost a metadata request.
+    fn part(&mut self, ino: u64) -> Result<api::Part> {
+        if let Some((part, resolved)) = self.parts.get(&ino) {
+            if resolved.elapsed() < PART_TTL {
+                return Ok(part.clone());
+            }
+        }
+        let part = self.resolve_part(ino)?;
+        self.parts.insert(ino, (part.clone(), Instant::now()));
+        Ok(part)
+    }
+
+    fn resolve_part(&self, ino: u64) -> Result<api::Part> {
+        let (rating_key, index) = match self.nodes.get(&ino) {
+            Some(Node::Version(rating_key, index)) => (*rating_key, Some(*index)),
+            Some(_) => return Err(not_found(format!("inode {} isn't a media file", ino))),
+            None => (ino - INO_ROOT, None)
+        };
+        let container = self.api.metadata(rating_key)?;
+        let item = container.items.get(0).ok_or_else(|| not_found(format!("no item {}", rating_key)))?;
+        let media = match index {
+            Some(index) => item.media().get(index),
+            None => self.options.filter.select(item)
+        };
+        media.map(|m| m.part.clone()).ok_or_else(|| not_found(format!("no media for inode {}", ino)))
+    }
+
+    fn build_dir(&mut self, ino: u64) -> HashMap<OsString, Entry> {
+        if ino == INO_ROOT {
+            let mut en = match (self.options.root, &self.sections[..]) {
+                (Some(Root::Playlist(rating_key)), _) => self.build_playlist(rating_key),
+                (Some(Root::Item(rating_key)), _) => self.build_children(rating_key),
+                (None, [section]) => {
+                    let section = section.clone();
+                    self.build_section(&section)
+                }
+                (None, _) => {
+                    let mut en = HashMap::new();
+                    for section in self.sections.clone() {
+                        let ino = self.virtual_ino(Node::Section(section.key));
+                        let name = unique_name(&en, self.options.names.sanitize(&section.title));
+                        en.insert(name, Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+                    }
+                    en
+                }
+            };
+            let ino = self.virtual_ino(Node::Control);
+            en.insert(OsString::from(".plexfs"), Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+            if self.options.metadata_dir {
+                let ino = self.virtual_ino(Node::MetadataDir);
+                en.insert(OsString::from(".metadata"), Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+            }
+            if self.options.views.contains(&View::Playlists) {
+                let ino = self.virtual_ino(Node::Playlists);
+                // A section or item can have the same name.
+                let name = unique_name(&en, View::Playlists.dir_name().to_string());
+                en.insert(name, Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+            }
+            if self.options.views.contains(&View::RecentlyAdded) {
+                let ino = self.virtual_ino(Node::RecentlyAdded);
+                let name = unique_name(&en, View::RecentlyAdded.dir_name().to_string());
+                en.insert(name, Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+            }
+            if self.options.views.contains(&View::OnDeck) {
+                let ino = self.virtual_ino(Node::OnDeck);
+                let name = unique_name(&en, View::OnDeck.dir_name().to_string());
+                en.insert(name, Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+            }
+            return en;
+        }
+
+        match self.nodes.get(&ino).cloned() {
+            Some(Node::Section(key)) => self.with_section(key, |fs, section| fs.build_section(section)),
+            Some(Node::View(key, View::Duplicates)) => self.with_section(key, |fs, section| fs.build_duplicates(section)),
+            Some(Node::View(key, View::Collections)) => self.with_section(key, |fs, section| fs.build_collections(section)),
+            Some(Node::Letter(key, character)) => self.with_section(key, |fs, section| fs.build_letter(section, &character)),
+            Some(Node::View(key, View::Genres)) => self.build_genres(key),
+            Some(Node::Genre(key, genre)) => self.with_section(key, |fs, section| fs.build_genre(section, &genre)),
+            Some(Node::Control) => {
+                let mut en = HashMap::new();
+                for file in Generated::CONTROL.iter() {
+                    let ino = self.virtual_ino(Node::Generated(*file));
+                    en.insert(OsString::from(file.file_name()), Entry { ino: ino, kind: FileType::RegularFile, attr: Some(file_attr(ino, 0)) });
+                }
+                let ino = self.virtual_ino(Node::SubtitlesDir);
+                en.insert(OsString::from("subtitles"), Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+                en
+            },
+            // Files in it are only looked up.
+            Some(Node::SubtitlesDir) => HashMap::new(),
+            Some(Node::MetadataDir) => {
+                let mut keys: Vec<u64> = self.entries.values()
+                    .flat_map(|en| en.values())
+                    .filter(|e| e.ino != INO_ROOT && e.ino < INO_VIRTUAL)
+                    .map(|e| e.ino - INO_ROOT)
+                    .collect();
+                keys.sort();
+                keys.dedup();
+
+                let mut en = HashMap::new();
+                for rating_key in keys {
+                    let file = Generated::Metadata(rating_key);
+                    let ino = self.virtual_ino(Node::Generated(file));
+                    en.insert(OsString::from(file.file_name()), Entry { ino: ino, kind: FileType::RegularFile, attr: Some(file_attr(ino, 0)) });
+                }
+                en
+            },
+            Some(Node::Playlists) => self.build_playlists(),
+            Some(Node::RecentlyAdded) => self.build_recently_added(),
+            Some(Node::OnDeck) => self.build_on_deck(),
+            Some(Node::Playlist(rating_key)) => self.build_playlist(rating_key),
+            Some(Node::Singles(artist)) => self.build_singles(artist),
+            Some(Node::VariousArtists(key)) => self.with_section(key, |fs, section| fs.build_various_artists(section)),
+            Some(Node::SectionFolder(key, id)) => self.with_section(key, |fs, section| fs.build_folder(section, Some(id))),
+            Some(Node::ByDate(key)) => self.with_section(key, |fs, section| fs.build_by_date(section)),
+            Some(Node::Period(key, ..)) => {
+                // Years and months are all listed along with `by-date/`.
+                let by_date = self.virtual_ino(Node::ByDate(key));
+                let en = self.with_section(key, |fs, section| fs.build_by_date(section));
+                self.insert_listing(by_date, en);
+                self.entries.remove(&ino).unwrap_or_default()
+            },
+            Some(Node::Disc(album, _)) => {
+                // Discs are all listed along with their album.
+                let album_ino = INO_ROOT + album;
+                let en = self.build_dir(album_ino);
+                self.insert_listing(album_ino, en);
+                self.entries.remove(&ino).unwrap_or_default()
+            },
+            Some(Node::Folder(key, _)) => {
+                // Folders are all listed along with the section's directory,
+                // which is listed as it would be on its own, failure and all.
+                let section_ino = self.section_ino(key);
+                let (building, items) = (self.building, mem::take(&mut self.item_order));
+                self.list_dir(section_ino);
+                self.building = building;
+                self.item_order = items;
+                if let Some(errno) = self.failed.get(&section_ino) {
+                    self.listing_error.set(Some(*errno));
+                }
+                self.entries.remove(&ino).unwrap_or_default()
+            },
+            Some(_) => HashMap::new(),
+            None => self.build_children(ino - INO_ROOT)
+        }
+    }
+
+    /// The section whose items `ino` lists a page at a time, if any: a
+    /// section's directory in the metadata layout, unless it's split by
+    /// letter.
+    fn lazy_section(&self, ino: u64) -> Option<Section> {
+        if self.options.layout != Layout::Metadata || self.options.root.is_some() {
+            return None;
+        }
+        let section = match (ino, self.nodes.get(&ino), &self.sections[..]) {
+            (INO_ROOT, _, [section]) => section.clone(),
+            (_, Some(Node::Section(key)), _) => self.section(*key)?,
+            _ => return None
+        };
+        match self.bucketed(&section) {
+            true => None,
+            false => Some(section)
+        }
+    }
+
+    /// Whether a section's items are listed under their first letters.
+    /// Photo sections are left alone, since their albums are listed with
+    /// their photos.
+    fn bucketed(&self, section: &Section) -> bool {
+        match section.kind {
+            api::MediaKind::Photo => false,
+            _ => self.options.bucket_alpha
+        }
+    }
+
+    /// Whether `item` is an episode to name by its absolute number.
+    fn absolute(&self, item: &api::Item) -> bool {
+        match item {
+            api::Item::Video { kind, grandparent_title, grandparent_rating_key, .. } if kind == "episode" => {
+                self.options.absolute_episodes || self.options.absolute_shows.iter()
+                    .any(|show| *show == *grandparent_title || *show == grandparent_rating_key.to_string())
+            }
+            _ => false
+        }
+    }
+
+    /// The mounted section with key `key`, if it's still mounted.
+    fn section(&self, key: u64) -> Option<Section> {
+        self.sections.iter().find(|s| s.key == key).cloned()
+    }
+
+    /// Builds a listing of section `key` with `build`, or fails it with
+    /// ENOENT if the section is no longer mounted.
+    fn with_section<F>(&mut self, key: u64, build: F) -> HashMap<OsString, Entry>
+        where F: FnOnce(&mut Self, &Section) -> HashMap<OsString, Entry>
+    {
+        match self.section(key) {
+            Some(section) => build(self, &section),
+            None => {
+                self.listing_failed(not_found(format!("section {} isn't mounted", key)));
+                HashMap::new()
+            }
+        }
+    }
+
+    /// The directory a section's contents are listed in.
+    fn section_ino(&mut self, key: u64) -> u64 {
+        match self.sections.len() {
+            1 => INO_ROOT,
+            _ => self.virtual_ino(Node::Section(key))
+        }
+    }
+
+    /// Lists a section's contents in the configured layout, with its views.
+    fn build_section(&mut self, section: &Section) -> HashMap<OsString, Entry> {
+        let mut en = match self.options.layout {
+            Layout::Metadata if self.bucketed(section) => self.build_letters(section),
+            // Added by `fetch_page` as the listing is read.
+            Layout::Metadata => HashMap::new(),
+            Layout::ServerPaths | Layout::Template(_) => self.build_paths(section),
+            Layout::Folders => self.build_folder(section, None)
+        };
+        for view in self.options.views.clone() {
+            // These are at the root, covering every section.
+            if let View::Playlists | View::RecentlyAdded | View::OnDeck = view {
+                continue;
+            }
+            let ino = self.virtual_ino(Node::View(section.key, view));
+            en.insert(OsString::from(view.dir_name()), Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+        }
+        if let api::MediaKind::Photo = section.kind {
+            let ino = self.virtual_ino(Node::ByDate(section.key));
+            en.insert(OsString::from("by-date"), Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+        }
+        if self.groups_compilations(section) {
+            let ino = self.virtual_ino(Node::VariousArtists(section.key));
+            let name = unique_name(&en, api::VARIOUS_ARTISTS.to_string());
+            en.insert(name, Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+        }
+        en
+    }
+
+    /// Whether a section's compilations are listed under `Various Artists/`
+    /// rather than under their artists: music sections listed by artist.
+    fn groups_compilations(&self, section: &Section) -> bool {
+        match (section.kind, self.options.layout) {
+            (api::MediaKind::Music, Layout::Metadata) => true,
+            _ => false
+        }
+    }
+
+    /// Whether `pages`, the children of `artist`, are albums to leave the
+    /// section's compilations out of. The Various Artists artist's are
+    /// its compilations, so they're all left.
+    fn lists_compilations_apart(&self, artist: u64, pages: &[api::MediaContainer]) -> bool {
+        if self.options.layout != Layout::Metadata || self.options.root.is_some() {
+            return false;
+        }
+        pages.iter().flat_map(|page| page.items.iter()).any(|item| match item {
+            api::Item::Directory { kind, parent_rating_key, parent_title, .. } if kind == "album" => {
+                *parent_rating_key == artist && !parent_title.eq_ignore_ascii_case(api::VARIOUS_ARTISTS)
+            }
+            _ => false
+        })
+    }
+
+    /// Lists a music section's compilations: albums tagged as one or
+    /// credited to Various Artists, and albums most of whose tracks are by
+    /// other artists than the album's, with no one artist among them.
+    fn build_various_artists(&mut self, section: &Section) -> HashMap<OsString, Entry> {
+        let (key, type_id, api) = (section.key, section.kind.leaf_type(), self.api.clone());
+        let mut credits = HashMap::new();
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| api.all_of_type(key, type_id, start, size),
+                      |page| count_credits(&mut credits, &page))
+        .unwrap_or_else(|e| self.listing_failed(e));
+        let mixed = mixed_albums(&credits);
+
+        let mut en = HashMap::new();
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| api.all_of_type(key, api::ALBUM_TYPE, start, size),
+                      |mut page| {
+                          page.items.retain(|item| item.is_compilation() || mixed.contains(&item.rating_key()));
+                          self.add_item_entries(&mut en, &page)
+                      })
+        .unwrap_or_else(|e| self.listing_failed(e));
+        en
+    }
+
+    /// Lists an item's children, e.g. an artist's albums.
+    fn build_children(&mut self, rating_key: u64) -> HashMap<OsString, Entry> {
+        let mut en = HashMap::new();
+        let mut leaves = 0;
+        let mut has_tracks = false;
+        let mut discs = HashSet::new();
+        let mut pages = vec![];
+        let api = self.api.clone();
+        let collection = self.collection_keys.contains(&rating_key);
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| match collection {
+                          true => api.collection_children(rating_key, start, size),
+                          false => api.metadata_children(rating_key, start, size)
+                      },
+                      |mut page| {
+                          // A collection can't hold itself, which would make
+                          // a directory its own child.
+                          page.items.retain(|item| item.rating_key() != rating_key);
+                          for item in page.items.iter() {
+                              match item {
+                                  api::Item::Directory { leaf_count, .. } => leaves += leaf_count,
+                                  api::Item::Track { parent_index, .. } => {
+                                      has_tracks = true;
+                                      discs.extend(*parent_index);
+                                  }
+                                  _ => has_tracks = true
+                              }
+                          }
+                          pages.push(page);
+                      })
+        .unwrap_or_else(|e| self.listing_failed(e));
+        for page in pages.iter() {
+            self.remember_collections(page);
+        }
+        if self.lists_compilations_apart(rating_key, &pages) {
+            // Those are under the section's `Various Artists/`.
+            let mut credits = HashMap::new();
+            for_each_page(self.options.page_size, self.api.max_connections(),
+                          |start, size| api.metadata_leaves(rating_key, start, size),
+                          |page| count_credits(&mut credits, &page))
+            .unwrap_or_else(|e| self.listing_failed(e));
+            let mixed = mixed_albums(&credits);
+            for page in pages.iter_mut() {
+                page.items.retain(|item| !item.is_compilation() && !mixed.contains(&item.rating_key()));
+            }
+        }
+        match self.options.disc_dirs && discs.len() > 1 {
+            true => self.add_discs(&mut en, rating_key, pages),
+            false => for page in pages {
+                self.add_item_entries(&mut en, &page);
+            }
+        }
+
+        // The item itself, for its total track count, artwork and NFO. An
+        // album's or season's artwork is known from its parent's listing.
+        let cover_known = self.covers.contains_key(&rating_key);
+        let item = match !has_tracks || (self.options.covers && !cover_known) {
+            true => self.api.metadata(rating_key).ok().and_then(|container| container.items.into_iter().next()),
+            false => None
+        };
+
+        // An artist whose albums hold fewer tracks than it has in total also
+        // has loose tracks. Collections of albums don't.
+        let total_leaves = match &item {
+            Some(api::Item::Directory { kind, leaf_count, .. }) if kind == "artist" => *leaf_count,
+            _ => 0
+        };
+        if !has_tracks && total_leaves > leaves {
+            let singles = self.virtual_ino(Node::Singles(rating_key));
+            let name = unique_name(&en, "Singles".into());
+            en.insert(name, Entry { ino: singles, kind: FileType::Directory, attr: Some(dir_attr(singles)) });
+        }
+        if let Some(item) = &item {
+            self.remember_cover(item);
+        }
+        if self.options.covers {
+            self.add_cover(&mut en, rating_key);
+        }
+        match &item {
+            Some(api::Item::Directory { kind, .. }) if self.options.nfo && kind == "show" => {
+                let ino = self.virtual_ino(Node::Generated(Generated::Nfo(rating_key)));
+                en.insert(OsString::from(Generated::Nfo(rating_key).file_name()), Entry { ino: ino, kind: FileType::RegularFile, attr: Some(file_attr(ino, 0)) });
+            }
+            _ => ()
+        }
+        en
+    }
+
+    /// Adds a directory for each disc of an album's tracks, storing each
+    /// disc's listing. Tracks without a disc number stay in the album's.
+    fn add_discs(&mut self, en: &mut HashMap<OsString, Entry>, album: u64, pages: Vec<api::MediaContainer>) {
+        let mut discs: HashMap<u64, Vec<api::Item>> = HashMap::new();
+        let mut rest = vec![];
+        for item in pages.into_iter().flat_map(|page| page.items) {
+            let disc = match &item {
+                api::Item::Track { parent_index, .. } => *parent_index,
+                _ => None
+            };
+            match disc {
+                Some(disc) => discs.entry(disc).or_insert_with(Vec::new).push(item),
+                None => rest.push(item)
+            }
+        }
+        self.add_item_entries(en, &api::MediaContainer { items: rest });
+        for (disc, tracks) in discs {
+            let ino = self.virtual_ino(Node::Disc(album, disc));
+            let mut disc_en = HashMap::new();
+            self.add_item_entries(&mut disc_en, &api::MediaContainer { items: tracks });
+            self.insert_listing(ino, disc_en);
+            en.insert(OsString::from(format!("Disc {}", disc)), Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+        }
+    }
+
+    /// Records the artwork file `item` has, if any, for when its directory
+    /// is listed.
+    fn remember_cover(&mut self, item: &api::Item) {
+        let (kind, thumb) = match (item, item.thumb()) {
+            (api::Item::Directory { kind, .. }, Some(thumb)) => (kind, thumb),
+            _ => return
+        };
+        let file = match &kind[..] {
+            "album" => Generated::Cover(item.rating_key()),
+            "show" | "season" => Generated::Poster(item.rating_key()),
+            _ => return
+        };
+        self.covers.insert(item.rating_key(), (file, thumb.to_string()));
+    }
+
+    /// Adds the artwork of `rating_key`, an album, show or season, to its
+    /// directory, for players that look for it in a file.
+    fn add_cover(&mut self, en: &mut HashMap<OsString, Entry>, rating_key: u64) {
+        let file = match self.covers.get(&rating_key) {
+            Some((file, _)) => *file,
+            None => return
+        };
+        let ino = self.virtual_ino(Node::Generated(file));
+        let size = self.artwork_sizes.get(&ino).cloned().unwrap_or(0);
+        en.insert(OsString::from(file.file_name()), Entry { ino: ino, kind: FileType::RegularFile, attr: Some(file_attr(ino, size)) });
+    }
+
+    /// Lists an artist's tracks whose album isn't one of the artist's
+    /// children. Their tracks on compilations are left out, since those are
+    /// listed once under the compilation's album artist, such as "Various
+    /// Artists", rather than under each artist on them.
+    fn build_singles(&mut self, artist: u64) -> HashMap<OsString, Entry> {
+        let api = self.api.clone();
+        let mut albums = HashSet::new();
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| api.metadata_children(artist, start, size),
+                      |page| albums.extend(page.items.iter().map(|item| item.rating_key())))
+        .unwrap_or_else(|e| self.listing_failed(e));
+
+        let mut en = HashMap::new();
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| api.metadata_leaves(artist, start, size),
+                      |mut page| {
+                          page.items.retain(|item| match item {
+                              api::Item::Track { parent_rating_key, grandparent_rating_key, .. } => {
+                                  !albums.contains(parent_rating_key) && (*grandparent_rating_key == 0 || *grandparent_rating_key == artist)
+                              }
+                              _ => false
+                          });
+                          self.add_item_entries(&mut en, &page)
+                      })
+        .unwrap_or_else(|e| self.listing_failed(e));
+        en
+    }
+
+    fn render(&mut self, file: Generated) -> Result<Vec<u8>> {
+        match file {
+            Generated::Sessions => self.api.sessions_json().map(String::into_bytes),
+            Generated::Metadata(rating_key) => self.api.metadata_json(rating_key).map(String::into_bytes),
+            Generated::Cover(rating_key) | Generated::Poster(rating_key) => {
+                let thumb = match self.covers.get(&rating_key) {
+                    Some((_, thumb)) => thumb.clone(),
+                    None => {
+                        let container = self.api.metadata(rating_key)?;
+                        container.items.get(0).and_then(|item| item.thumb()).map(String::from)
+                            .ok_or_else(|| not_found(format!("item {} has no artwork", rating_key)))?
+                    }
+                };
+                self.api.artwork(&thumb, self.options.artwork_size)
+            }
+            Generated::Nfo(rating_key) => {
+                let container = self.api.metadata(rating_key)?;
+                container.items.get(0).and_then(nfo::render)
+                    .map(String::into_bytes)
+                    .ok_or_else(|| not_found(format!("item {} has no NFO", rating_key)))
+            }
+            Generated::Playlist(rating_key) => {
+                // Paths are relative to `Playlists/`, where the file is.
+                let (playlists, dir) = (self.virtual_ino(Node::Playlists), self.virtual_ino(Node::Playlist(rating_key)));
+                self.ensure_dir(playlists);
+                let dir_name = self.entries.get(&playlists)
+                    .and_then(|en| en.iter().find(|(_, e)| e.ino == dir))
+                    .map(|(name, _)| name.to_string_lossy().into_owned())
+                    .ok_or_else(|| not_found(format!("no playlist {}", rating_key)))?;
+                let mut m3u = String::from("#EXTM3U\n");
+                // Sorted by name is playlist order, since names start with
+                // the position.
+                for (name, _, _) in self.listing(dir, usize::MAX) {
+                    m3u.push_str(&format!("{}/{}\n", dir_name, name.to_string_lossy()));
+                }
+                Ok(m3u.into_bytes())
+            }
+            Generated::Sidecar(rating_key, id) => {
+                let container = self.api.metadata(rating_key)?;
+                let key = container.items.get(0).into_iter()
+                    .flat_map(|item| item.media())
+                    .flat_map(|media| media.part.streams.iter())
+                    .find(|stream| stream.id == id)
+                    .and_then(|stream| stream.key.as_ref())
+                    .ok_or_else(|| not_found(format!("item {} has no stream {}", rating_key, id)))?;
+                self.api.resource(key)
+            }
+            Generated::SubtitleSearch(rating_key) => {
+                let language = self.options.subtitle_language.clone();
+                let results = self.api.search_subtitles(rating_key, &language)?;
+                let best = match results.iter().max_by(|a, b| {
+                    a.score.unwrap_or(0.0).partial_cmp(&b.score.unwrap_or(0.0)).unwrap_or(cmp::Ordering::Equal)
+                }) {
+                    Some(best) => best,
+                    None => return Ok(format!("no \"{}\" subtitles found\n", language).into_bytes())
+                };
+                self.api.download_subtitle(rating_key, best)?;
+                self.forget_item(rating_key);
+                let title = best.display_title.as_ref().unwrap_or(&best.key);
+                let provider = best.provider_title.as_deref().unwrap_or("unknown provider");
+                Ok(format!("downloaded {} from {}\n", title, provider).into_bytes())
+            }
+            Generated::Sections => {
+                let sections: Vec<_> = self.api.sections()?.into_iter().map(|section| {
+                    json!({
+                        "id": section.key,
+                        "title": section.title,
+                        "type": section.kind,
+                        "count": self.api.section_size(section.key).ok(),
+                    })
+                }).collect();
+                Ok(serde_json::to_vec_pretty(&sections)?)
+            }
+        }
+    }
+
+    fn add_item_entries(&mut self, en: &mut HashMap<OsString, Entry>, container: &api::MediaContainer) {
+        // Some listings leave out Media, without which there's no file name
+        // or size, and all leave out the streams of lyrics and subtitles.
+        let (lyrics, subtitles) = (self.options.lyrics, self.options.subtitles);
+        let incomplete: Vec<u64> = container.items.iter()
+            .filter(|item| item.is_playable() && (item.media().is_empty() || match item {
+                api::Item::Track { .. } => lyrics,
+                api::Item::Video { .. } => subtitles,
+                _ => false
+            }))
+            .map(|item| item.rating_key())
+            .collect();
+        let full = fetch_metadata(&self.api, &incomplete, self.options.metadata_concurrency);
+
+        for item in container.items.iter() {
+            let item = full.get(&item.rating_key()).unwrap_or(item);
+            let ino = INO_ROOT + item.rating_key();
+            let media = self.options.filter.select(item);
+            let attr = to_attr(ino, item, media, &self.options.times);
+
+            match item {
+                api::Item::Directory { kind, title, index, originally_available_at, .. } => {
+                    let year_prefix = self.year_prefix(self.building);
+                    let name = match (&kind[..], item.year()) {
+                        ("season", _) => season_name(*index, title),
+                        ("album", Some(year)) if year_prefix => format!("{} - {}", year, title),
+                        _ => title.clone()
+                    };
+                    if kind == "album" {
+                        let date = originally_available_at.clone().or(item.year().map(|year| format!("{:04}", year)));
+                        if let Some(date) = date {
+                            self.release_dates.insert(ino, date);
+                        }
+                    }
+                    let name = self.distinct_name(en, &name, ino, item);
+                    if self.options.covers {
+                        self.remember_cover(item);
+                    }
+                    self.item_order.push(name.clone());
+                    en.insert(name, Entry {ino: ino, kind: FileType::Directory, attr: attr});
+                },
+                api::Item::Track { .. } | api::Item::Video { .. } | api::Item::Photo { .. } => {
+                    let media = match media {
+                        Some(media) => media,
+                        None => {
+                            if !item.media().iter().any(|m| m.has_part()) {
+                                warn!("skipping {} \"{}\": no media part to read", item.rating_key(), item.title());
+                            }
+                            continue
+                        }
+                    };
+                    // Remakes can share a title and year.
+                    let name = unique_name(en, self.options.names.sanitize(&file_name(item, media, self.options.naming, self.absolute(item))));
+                    self.item_order.push(name.clone());
+                    for (stream, suffix) in self.sidecars(media) {
+                        self.add_beside(en, &name, &suffix, Generated::Sidecar(item.rating_key(), stream.id));
+                    }
+                    match item {
+                        api::Item::Video { kind, .. } if self.options.nfo && (kind == "movie" || kind == "episode") => {
+                            self.add_beside(en, &name, "nfo", Generated::Nfo(item.rating_key()));
+                        }
+                        _ => ()
+                    }
+                    en.insert(name, Entry {ino: ino, kind: FileType::RegularFile, attr: attr});
+                }
+            }
+        }
+    }
+
+    /// A name for directory `ino` that no other entry in `en` has, as two
+    /// albums titled "Greatest Hits" by one artist would. All of those get
+    /// their year, as in `Greatest Hits (1998)`, and any that share a year
+    /// or have none their rating key as well; those already in `en` are
+    /// renamed to match, so the names don't depend on listing order.
+    fn distinct_name(&mut self, en: &mut HashMap<OsString, Entry>, name: &str, ino: u64, item: &api::Item) -> OsString {
+        self.titles.insert(ino, (name.to_string(), item.year()));
+        let mut group: Vec<(OsString, u64, Option<u64>)> = en.iter()
+            .filter(|(_, e)| e.ino != ino)
+            .filter_map(|(other, e)| match self.titles.get(&e.ino) {
+                Some((title, year)) if title == name => Some((other.clone(), e.ino, *year)),
+                _ => None
+            })
+            .collect();
+        let candidate = OsString::from(self.options.names.sanitize(name));
+        if group.is_empty() && !en.get(&candidate).map_or(false, |e| e.ino != ino) {
+            return candidate;
+        }
+
+        let years: Vec<Option<u64>> = group.iter().map(|(_, _, year)| *year)
+            .chain(Some(item.year()))
+            .collect();
+        group.sort_by_key(|(_, other, _)| *other);
+        let renamed: Vec<(OsString, u64, Option<u64>, Entry)> = group.into_iter()
+            .map(|(old, other, year)| {
+                let entry = en.remove(&old).unwrap();
+                (old, other, year, entry)
+            })
+            .collect();
+        for (old, other, year, entry) in renamed {
+            let new = self.disambiguated(en, name, other, year, &years);
+            debug!("renaming \"{}\" to \"{}\" to tell it apart from another", old.to_string_lossy(), new.to_string_lossy());
+            for listed in self.item_order.iter_mut().filter(|listed| **listed == old) {
+                *listed = new.clone();
+            }
+            en.insert(new, entry);
+        }
+        let new = self.disambiguated(en, name, ino, item.year(), &years);
+        debug!("naming {} \"{}\" to tell it apart from another", item.rating_key(), new.to_string_lossy());
+        new
+    }
+
+    /// `name` with `year` if no other of `years` is the same, and otherwise
+    /// the rating key of `ino` too, numbered if something else in `en`
+    /// already has that.
+    fn disambiguated(&self, en: &HashMap<OsString, Entry>, name: &str, ino: u64, year: Option<u64>, years: &[Option<u64>]) -> OsString {
+        let shared = years.iter().filter(|other| **other == year).count() > 1;
+        let name = match (year, shared) {
+            (Some(year), false) => format!("{} ({})", name, year),
+            (Some(year), true) => format!("{} ({}) ({})", name, year, ino - INO_ROOT),
+            (None, _) => format!("{} ({})", name, ino - INO_ROOT)
+        };
+        let name = self.options.names.sanitize(&name);
+        let mut candidate = OsString::from(&name);
+        let mut n = 2;
+        while en.get(&candidate).map_or(false, |e| e.ino != ino) {
+            candidate = OsString::from(format!("{} ({})", name, n));
+            n += 1;
+        }
+        candidate
+    }
+
+    /// The streams of `media` to serve as files beside it, each with what
+    /// follows the stem of the media's file name: "lrc" for lyrics, or the
+    /// language and format of subtitles as in "eng.srt".
+    fn sidecars<'a>(&self, media: &'a api::Media) -> Vec<(&'a api::Stream, String)> {
+        let mut sidecars = vec![];
+        if let (true, Some(stream)) = (self.options.lyrics, media.part.lyrics()) {
+            let extension = match stream.codec.as_ref().map(|c| &c[..]) {
+                Some("txt") => "txt",
+                _ => "lrc"
+            };
+            sidecars.push((stream, extension.to_string()));
+        }
+        if self.options.subtitles {
+            for stream in media.part.subtitles() {
+                let extension = match stream.subtitle_extension() {
+                    Some(extension) => extension,
+                    None => continue
+                };
+                let suffix = match &stream.language_code {
+                    Some(language) => format!("{}.{}", language, extension),
+                    None => extension.to_string()
+                };
+                sidecars.push((stream, suffix));
+            }
+        }
+        sidecars
+    }
+
+    /// Adds `file` beside an item's file `name`, as `Track.lrc` beside
+    /// `Track.flac`.
+    fn add_beside(&mut self, en: &mut HashMap<OsString, Entry>, name: &OsStr, suffix: &str, file: Generated) {
+        let stem = Path::new(name).file_stem().unwrap_or(name).to_string_lossy();
+        // A second subtitle stream in the same language becomes
+        // `Movie.eng (2).srt`.
+        let sidecar = unique_name(en, format!("{}.{}", stem, suffix));
+        self.item_order.push(sidecar.clone());
+        let ino = self.virtual_ino(Node::Generated(file));
+        en.insert(sidecar, Entry { ino: ino, kind: FileType::RegularFile, attr: Some(file_attr(ino, 0)) });
+    }
+
+    /// Lists every playable item in the section at its path: on the server,
+    /// relative to the section location it's under, or from the layout's
+    /// template. The listings of every folder below the root are stored as
+    /// they're built.
+    fn build_paths(&mut self, section: &Section) -> HashMap<OsString, Entry> {
+        let template = match &self.options.layout {
+            Layout::Template(template) => Some(template.clone()),
+            _ => None
+        };
+        let locations: Vec<String> = match (&template, self.api.sections()) {
+            (Some(_), _) => vec![],
+            (None, Ok(sections)) => sections.into_iter()
+                .filter(|s| s.key == section.key)
+                .flat_map(|s| s.locations)
+                .map(|l| l.path)
+                .collect(),
+            (None, Err(e)) => {
+                warn!("fetching the locations of section {}: {}", section.key, e);
+                vec![]
+            }
+        };
+
+        let (key, type_id, api) = (section.key, section.kind.leaf_type(), &self.api);
+        let mut items = vec![];
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| api.all_of_type(key, type_id, start, size),
+                      |page| items.extend(page.items))
+        .unwrap_or_else(|e| self.listing_failed(e));
+
+        let section_ino = self.section_ino(key);
+        let mut dirs: HashMap<u64, HashMap<OsString, Entry>> = HashMap::new();
+        dirs.insert(section_ino, HashMap::new());
+
+        for item in items.iter() {
+            for (index, media) in item.media().iter().enumerate() {
+                if !self.options.filter.accepts(media) {
+                    continue
+                }
+                let components = match &template {
+                    Some(template) => template.render(item, media),
+                    None => server_path(&media.part.file, &locations)
+                };
+                let (file, folders) = match components.split_last() {
+                    Some(split) => split,
+                    None => continue
+                };
+
+                let mut parent = section_ino;
+                let mut path = String::new();
+                for folder in folders {
+                    // Keyed by the names as they're shown, so folders that
+                    // only differ in what's sanitized away are one folder.
+                    let name = self.options.names.sanitize(folder);
+                    path.push('/');
+                    path.push_str(&name);
+                    let name = OsString::from(name);
+                    let ino = self.virtual_ino(Node::Folder(key, path.clone()));
+                    dirs.entry(parent).or_insert_with(HashMap::new)
+                        .entry(name)
+                        .or_insert(Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+                    dirs.entry(ino).or_insert_with(HashMap::new);
+                    parent = ino;
+                }
+
+                let ino = self.virtual_ino(Node::Version(item.rating_key(), index));
+                let en = dirs.entry(parent).or_insert_with(HashMap::new);
+                let name = unique_name(en, self.options.names.sanitize(file));
+                en.insert(name, Entry { ino: ino, kind: FileType::RegularFile, attr: to_attr(ino, item, Some(media), &self.options.times) });
+            }
+        }
+
+        let root = dirs.remove(&section_ino).unwrap_or_default();
+        self.entries.extend(dirs);
+        root
+    }
+
+    /// Lists a section's collections, each a directory of its members.
+    fn build_collections(&mut self, section: &Section) -> HashMap<OsString, Entry> {
+        let (key, api) = (section.key, self.api.clone());
+        let mut en = HashMap::new();
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| api.collections(key, start, size),
+                      |page| {
+                          self.remember_collections(&page);
+                          self.add_item_entries(&mut en, &page)
+                      })
+        .unwrap_or_else(|e| self.listing_failed(e));
+        en
+    }
+
+    /// Notes which items of a listing are collections, including those
+    /// nested in another.
+    fn remember_collections(&mut self, container: &api::MediaContainer) {
+        for item in container.items.iter() {
+            if let api::Item::Directory { kind, rating_key, .. } = item {
+                if kind == "collection" {
+                    self.collection_keys.insert(*rating_key);
+                }
+            }
+        }
+    }
+
+    /// Lists a directory for each letter a section's items start with.
+    fn build_letters(&mut self, section: &Section) -> HashMap<OsString, Entry> {
+        let characters = match self.api.first_characters(section.key, section.kind) {
+            Ok(characters) => characters,
+            Err(e) => {
+                self.listing_failed(e);
+                return HashMap::new();
+            }
+        };
+        let mut en = HashMap::new();
+        for character in characters {
+            let name = unique_name(&en, self.options.names.sanitize(&character.title));
+            let ino = self.virtual_ino(Node::Letter(section.key, character.key));
+            en.insert(name, Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+        }
+        en
+    }
+
+    /// Lists the items at the top of a section that start with a letter.
+    fn build_letter(&mut self, section: &Section, character: &str) -> HashMap<OsString, Entry> {
+        let (key, kind, api) = (section.key, section.kind, self.api.clone());
+        let grouped = self.groups_compilations(section);
+        let mut en = HashMap::new();
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| api.first_character_items(key, kind, character, start, size),
+                      |mut page| {
+                          if grouped {
+                              page.items.retain(|item| !is_various_artists(item));
+                          }
+                          self.add_item_entries(&mut en, &page)
+                      })
+        .unwrap_or_else(|e| self.listing_failed(e));
+        en
+    }
+
+    /// Lists a section's genres, each a directory.
+    fn build_genres(&mut self, key: u64) -> HashMap<OsString, Entry> {
+        let genres = match self.api.genres(key) {
+            Ok(genres) => genres,
+            Err(e) => {
+                self.listing_failed(e);
+                return HashMap::new();
+            }
+        };
+        let mut en = HashMap::new();
+        for genre in genres {
+            let name = unique_name(&en, self.options.names.sanitize(&genre.title));
+            let ino = self.virtual_ino(Node::Genre(key, genre.key));
+            en.insert(name, Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+        }
+        en
+    }
+
+    /// Lists the items at the top of a section that are in a genre, such as
+    /// its artists or shows.
+    fn build_genre(&mut self, section: &Section, genre: &str) -> HashMap<OsString, Entry> {
+        let (key, kind, api) = (section.key, section.kind, self.api.clone());
+        let mut en = HashMap::new();
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| api.genre_items(key, kind, genre, start, size),
+                      |page| self.add_item_entries(&mut en, &page))
+        .unwrap_or_else(|e| self.listing_failed(e));
+        en
+    }
+
+    /// Lists each playlist as a directory of its items, and as an `.m3u8`
+    /// of the paths in that directory for players that want a file.
+    fn build_playlists(&mut self) -> HashMap<OsString, Entry> {
+        let playlists = match self.api.playlists() {
+            Ok(playlists) => playlists,
+            Err(e) => {
+                self.listing_failed(e);
+                return HashMap::new();
+            }
+        };
+        let mut en = HashMap::new();
+        for playlist in playlists {
+            let name = unique_name(&en, self.options.names.sanitize(&playlist.title));
+            let mut file_name = name.clone();
+            file_name.push(".m3u8");
+            let ino = self.virtual_ino(Node::Playlist(playlist.rating_key));
+            en.insert(name, Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+            let ino = self.virtual_ino(Node::Generated(Generated::Playlist(playlist.rating_key)));
+            en.insert(file_name, Entry { ino: ino, kind: FileType::RegularFile, attr: Some(file_attr(ino, 0)) });
+        }
+        en
+    }
+
+    /// Lists what was last added to sections of each kind mounted. The
+    /// server lists an episode or track added along with others of its
+    /// season or album as that season or album.
+    fn build_recently_added(&mut self) -> HashMap<OsString, Entry> {
+        let mut kinds: Vec<api::MediaKind> = vec![];
+        for section in self.sections.iter() {
+            if !kinds.contains(&section.kind) {
+                kinds.push(section.kind);
+            }
+        }
+        let mut items = vec![];
+        for kind in kinds {
+            match self.api.recently_added(kind) {
+                Ok(container) => items.extend(container.items),
+                Err(e) => self.listing_failed(e)
+            }
+        }
+        // Newest first, whatever kind it is.
+        items.sort_by_key(|item| cmp::Reverse(item.timestamps().2));
+        let mut en = HashMap::new();
+        self.add_item_entries(&mut en, &api::MediaContainer { items: items });
+        en
+    }
+
+    /// Lists what's on deck in each video section mounted, most recently
+    /// watched first across them.
+    fn build_on_deck(&mut self) -> HashMap<OsString, Entry> {
+        let mut items = vec![];
+        for section in self.sections.clone() {
+            if let api::MediaKind::Music | api::MediaKind::Photo = section.kind {
+                continue;
+            }
+            match self.api.on_deck(section.key) {
+                Ok(container) => items.extend(container.items),
+                Err(e) => self.listing_failed(e)
+            }
+        }
+        items.sort_by_key(|item| cmp::Reverse(item.timestamps().0));
+        let mut en = HashMap::new();
+        self.add_item_entries(&mut en, &api::MediaContainer { items: items });
+        en
+    }
+
+    /// Lists a playlist's tracks, numbered so they sort in playlist order.
+    /// A track that's in the playlist twice is listed twice.
+    fn build_playlist(&self, rating_key: u64) -> HashMap<OsString, Entry> {
+        let api = &self.api;
+        let mut items = vec![];
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| api.playlist_items(rating_key, start, size),
+                      |page| items.extend(page.items))
+        .unwrap_or_else(|e| self.listing_failed(e));
+
+        let width = cmp::max(2, items.len().to_string().len());
+        let mut en = HashMap::new();
+        for (position, item) in items.iter().enumerate() {
+            let media = match self.options.filter.select(item) {
+                Some(media) => media,
+                None => continue
+            };
+            let ino = INO_ROOT + item.rating_key();
+            let name = format!("{:0width$} - {}", position + 1, file_name(item, media, self.options.naming, self.absolute(item)), width = width);
+            en.insert(OsString::from(self.options.names.sanitize(&name)), Entry { ino: ino, kind: FileType::RegularFile, attr: to_attr(ino, item, Some(media), &self.options.times) });
+        }
+        en
+    }
+
+    /// Lists every photo in the section under `YYYY/MM/` by when it was
+    /// taken, or added if that's unknown. The year and month listings are
+    /// stored as they're built.
+    fn build_by_date(&mut self, section: &Section) -> HashMap<OsString, Entry> {
+        let (key, type_id, api) = (section.key, section.kind.leaf_type(), &self.api);
+        let mut items = vec![];
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| api.all_of_type(key, type_id, start, size),
+                      |page| items.extend(page.items))
+        .unwrap_or_else(|e| self.listing_failed(e));
+
+        let mut en = HashMap::new();
+        for item in items.iter() {
+            let media = match self.options.filter.select(item) {
+                Some(media) => media,
+                None => continue
+            };
+            let (year, month) = match taken(item) {
+                Some(date) => date,
+                None => continue
+            };
+
+            let year_ino = self.virtual_ino(Node::Period(key, year, None));
+            en.insert(OsString::from(year.to_string()), Entry { ino: year_ino, kind: FileType::Directory, attr: Some(dir_attr(year_ino)) });
+            let month_ino = self.virtual_ino(Node::Period(key, year, Some(month)));
+            self.parents.insert(month_ino, year_ino);
+            self.entries.entry(year_ino).or_insert_with(HashMap::new)
+                .insert(OsString::from(format!("{:02}", month)), Entry { ino: month_ino, kind: FileType::Directory, attr: Some(dir_attr(month_ino)) });
+
+            let ino = INO_ROOT + item.rating_key();
+            let attr = to_attr(ino, item, Some(media), &self.options.times);
+            let name = self.options.names.sanitize(&file_name(item, media, self.options.naming, self.absolute(item)));
+            self.parents.insert(ino, month_ino);
+            let photos = self.entries.entry(month_ino).or_insert_with(HashMap::new);
+            let name = unique_name(photos, name);
+            photos.insert(name, Entry { ino: ino, kind: FileType::RegularFile, attr: attr });
+        }
+        en
+    }
+
+    /// Lists a level of Plex's folder view: subfolders, and the items
+    /// directly inside.
+    fn build_folder(&mut self, section: &Section, parent: Option<u64>) -> HashMap<OsString, Entry> {
+        let mut en = HashMap::new();
+        let mut folders = vec![];
+        let (section_key, api) = (section.key, self.api.clone());
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| api.folder(section_key, parent, start, size),
+                      |mut page| {
+                          page.items.retain(|item| match item {
+                              api::Item::Directory { key, title, .. } => {
+                                  match folder_id(key) {
+                                      Some(id) => folders.push((id, title.clone())),
+                                      None => warn!("folder \"{}\" has no ID in its key \"{}\"", title, key)
+                                  }
+                                  false
+                              }
+                              _ => true
+                          });
+                          self.add_item_entries(&mut en, &page)
+                      })
+        .unwrap_or_else(|e| self.listing_failed(e));
+
+        for (id, title) in folders {
+            let ino = self.virtual_ino(Node::SectionFolder(section.key, id));
+            let name = unique_name(&en, self.options.names.sanitize(&title));
+            en.insert(name, Entry { ino: ino, kind: FileType::Directory, attr: Some(dir_attr(ino)) });
+        }
+        en
+    }
+
+    /// Groups every playable item in the section by GUID and lists the groups
+    /// with more than one version. Each group's own listing is stored as it's
+    /// built.
+    fn build_duplicates(&mut self, section: &Section) -> HashMap<OsString, Entry> {
+        let (key, type_id, api) = (section.key, section.kind.leaf_type(), &self.api);
+        let mut items = vec![];
+        for_each_page(self.options.page_size, self.api.max_connections(),
+                      |start, size| api.all_of_type(key, type_id, start, size),
+                      |page| items.extend(page.items))
+        .unwrap_or_else(|e| self.listing_failed(e));
+
+        let mut groups: HashMap<String, Vec<&api::Item>> = HashMap::new();
+        for item in items.iter() {
+            let guid = match item.guid() {
+                "" => item.rating_key().to_string(),
+                guid => guid.to_string()
+            };
+            groups.entry(guid).or_insert_with(Vec::new).push(item);
+        }
+
+        let mut en = HashMap::new();
+
+        for (guid, items) in groups.into_iter() {
+            let mut children = HashMap::new();
+            for item in items.iter() {
+                for (index, media) in item.media().iter().enumerate() {
+                    if !self.options.filter.accepts(media) {
+                        continue
+                    }
+                    let ino = self.virtual_ino(Node::Version(item.rating_key(), index));
+                    let name = unique_name(&children, self.options.names.sanitize(&file_name(item, media, self.options.naming, self.absolute(item))));
+                    children.insert(name, Entry { ino: ino, kind: FileType::RegularFile, attr: to_attr(ino, item, Some(media), &self.options.times) });
+                }
+            }
+            // Counted after filtering, since a group with one version left
+            // isn't a duplicate in this mount.
+            if children.len() < 2 {
+                continue
+            }
+
+            let group_ino = self.virtual_ino(Node::DuplicateGroup(guid));
+            let name = unique_name(&en, self.options.names.sanitize(items[0].title()));
+            en.insert(name, Entry { ino: group_ino, kind: FileType::Directory, attr: Some(dir_attr(group_ino)) });
+            self.insert_listing(group_ino, children);
+        }
+
+        en
+    }
+}
+
+/// Splits a Part's `file` into path components relative to the longest of
+/// `locations` it's under. Files outside every location keep their full
+/// path.
+fn server_path<'a>(file: &'a str, locations: &[String]) -> Vec<&'a str> {
+    let prefix = locations.iter()
+        .map(|l| l.trim_end_matches(|c: char| c == '/' || c == '\\'))
+        .filter(|l| file.starts_with(l) && file[l.len()..].starts_with(|c: char| c == '/' || c == '\\'))
+        .map(|l| l.len())
+        .max()
+        .unwrap_or(0);
+    file[prefix..].split(|c: char| c == '/' || c == '\\')
+        .filter(|c| !c.is_empty())
+        .collect()
 }
 
-impl PlexFS {
-    pub fn new(host: SocketAddr, token: String, section: u64, kind: api::MediaKind) -> Self {
-        PlexFS {
-            api: api::PlexAPI::new(host, token),
-            section: section,
-            kind: kind,
-            entries: HashMap::new()
+/// The year and month a photo was taken, or added if that's unknown.
+fn taken(item: &api::Item) -> Option<(i32, u8)> {
+    if let api::Item::Photo { originally_available_at: Some(date), .. } = item {
+        let mut parts = date.splitn(3, '-');
+        if let (Some(Ok(year)), Some(Ok(month))) = (parts.next().map(str::parse), parts.next().map(str::parse)) {
+            return Some((year, month));
+        }
+    }
+    match item.timestamps() {
+        (_, _, 0) => None,
+        (_, _, added) => {
+            let date = OffsetDateTime::from_unix_timestamp(added as i64);
+            Some((date.year(), date.month()))
+        }
+    }
+}
+
+/// The ID in a folder's key, `/library/sections/<id>/folder?parent=<ID>`.
+fn folder_id(key: &str) -> Option<u64> {
+    key.split(|c| c == '?' || c == '&')
+        .find_map(|arg| arg.strip_prefix("parent="))?
+        .parse()
+        .ok()
+}
+
+enum PendingRead {
+    Ready(Vec<u8>),
+    /// `size` bytes of the part at `offset`, through the handle's stream if
+    /// it has one.
+    Remote(api::Part, u64, u32, Option<Arc<Mutex<Stream>>>),
+}
+
+/// Where a read's data can come from besides the server.
+struct Sources<'a> {
+    stream: Option<&'a Arc<Mutex<Stream>>>,
+    cache: Option<&'a ChunkCache>,
+}
+
+fn fetch_range(api: &dyn MediaBackend, control: &Control, part: &api::Part, offset: u64, size: u32,
+               sources: Sources) -> Result<Vec<u8>> {
+    let mut body = match sources.cache {
+        Some(cache) => fetch_cached(api, cache, part, offset, size, sources.stream)?,
+        None => fetch_uncached(api, part, offset, size, sources.stream)?
+    };
+    body.truncate(size as usize);
+    Stats::incr(&control.stats.bytes_read, body.len() as u64);
+    Ok(body)
+}
+
+fn fetch_uncached(api: &dyn MediaBackend, part: &api::Part, offset: u64, size: u32,
+                  stream: Option<&Arc<Mutex<Stream>>>) -> Result<Vec<u8>> {
+    match stream {
+        Some(stream) => stream.lock().unwrap().read(api, part, offset, size),
+        None => api.file(part, offset as i64, size)
+    }
+}
+
+/// What the chunks of `part` are kept under in the chunk cache. The part
+/// key changes when the file is replaced; the size is a further guard
+/// against serving stale chunks.
+fn cache_key(api: &dyn MediaBackend, part: &api::Part) -> String {
+    format!("{}{}:{}", api.base_url(), part.key, part.size)
+}
+
+/// Reads through the chunk cache, fetching and storing the chunks covering
+/// the range that aren't there.
+fn fetch_cached(api: &dyn MediaBackend, cache: &ChunkCache, part: &api::Part, offset: u64, size: u32,
+                stream: Option<&Arc<Mutex<Stream>>>) -> Result<Vec<u8>> {
+    let file = cache_key(api, part);
+    let end = offset + size as u64;
+    let mut body = Vec::with_capacity(size as usize);
+
+    let mut index = offset / cache::CHUNK_SIZE;
+    while index * cache::CHUNK_SIZE < end {
+        let start = index * cache::CHUNK_SIZE;
+        let chunk = match cache.get(&file, index, part.size) {
+            Some(chunk) => chunk,
+            None => {
+                let chunk = fetch_uncached(api, part, start, cache::CHUNK_SIZE as u32, stream)?;
+                // A short chunk is only complete if it's the end of the file.
+                let complete = chunk.len() as u64 == cache::CHUNK_SIZE
+                    || (part.size > 0 && start + chunk.len() as u64 == part.size);
+                if complete {
+                    cache.put(&file, index, &chunk);
+                }
+                chunk
+            }
+        };
+
+        let from = (cmp::max(offset, start) - start) as usize;
+        if from >= chunk.len() {
+            break;
+        }
+        let to = cmp::min(chunk.len(), (end - start) as usize);
+        body.extend_from_slice(&chunk[from..to]);
+        if (chunk.len() as u64) < cache::CHUNK_SIZE {
+            break;
+        }
+        index += 1;
+    }
+    Ok(body)
+}
+
+/// Stores every chunk of `part` that isn't in `cache` yet, downloading at
+/// most `rate` bytes a second.
+fn prefetch_part(api: &dyn MediaBackend, cache: &ChunkCache, part: &api::Part, rate: Option<u64>) -> Result<()> {
+    let file = cache_key(api, part);
+    let mut index = 0;
+    while part.size == 0 || index * cache::CHUNK_SIZE < part.size {
+        if cache.contains(&file, index) {
+            index += 1;
+            continue;
+        }
+        let started = Instant::now();
+        let chunk = api.file(part, (index * cache::CHUNK_SIZE) as i64, cache::CHUNK_SIZE as u32)?;
+        let complete = chunk.len() as u64 == cache::CHUNK_SIZE
+            || (part.size > 0 && index * cache::CHUNK_SIZE + chunk.len() as u64 == part.size);
+        if complete {
+            cache.put(&file, index, &chunk);
+        }
+        if let Some(rate) = rate.filter(|&rate| rate > 0) {
+            let wanted = Duration::from_secs_f64(chunk.len() as f64 / rate as f64);
+            if let Some(rest) = wanted.checked_sub(started.elapsed()) {
+                thread::sleep(rest);
+            }
+        }
+        if (chunk.len() as u64) < cache::CHUNK_SIZE {
+            break;
+        }
+        index += 1;
+    }
+    Ok(())
+}
+
+/// What a worker makes its caller's requests with: the caller's priority,
+/// and the responses it's building from, if it's building in the background.
+struct Caller(scheduler::Priority, Option<Arc<Deferred>>);
+
+impl Caller {
+    fn current() -> Self {
+        Caller(scheduler::current(), deferred::current())
+    }
+
+    fn run<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        scheduler::with_priority(self.0, || deferred::with(self.1.clone(), f))
+    }
+}
+
+/// Fetches full metadata for `keys`, with at most `concurrency` requests in
+/// flight. Items that fail to load are left out.
+fn fetch_metadata(api: &dyn MediaBackend, keys: &[u64], concurrency: usize) -> HashMap<u64, api::Item> {
+    if keys.is_empty() {
+        return HashMap::new();
+    }
+
+    let queue = Mutex::new(keys.iter());
+    let results = Mutex::new(HashMap::new());
+    let caller = Caller::current();
+    thread::scope(|scope| {
+        let workers = cmp::min(concurrency, cmp::min(api.max_connections(), keys.len()));
+        for _ in 0..cmp::max(1, workers) {
+            scope.spawn(|| caller.run(|| loop {
+                let key = match queue.lock().unwrap().next() {
+                    Some(key) => *key,
+                    None => break
+                };
+                match api.metadata(key) {
+                    Ok(container) => {
+                        if let Some(item) = container.items.into_iter().next() {
+                            results.lock().unwrap().insert(key, item);
+                        }
+                    }
+                    // Asked for again once fetched, when building in the
+                    // background.
+                    Err(e) if e.is::<deferred::Pending>() => (),
+                    Err(e) => warn!("fetching metadata for {}: {}", key, e)
+                }
+            }));
+        }
+    });
+    results.into_inner().unwrap()
+}
+
+/// The names in `en` with the items in `items` order last, after whatever
+/// else is in the directory sorted by name. Names are listed once, even if an
+/// item turned up on more than one page.
+fn server_order(en: &HashMap<OsString, Entry>, items: &[OsString]) -> Vec<OsString> {
+    let mut seen = HashSet::new();
+    let items: Vec<OsString> = items.iter()
+        .filter(|name| en.contains_key(*name) && seen.insert(*name))
+        .cloned()
+        .collect();
+    let mut order: Vec<OsString> = en.keys().filter(|name| !seen.contains(name)).cloned().collect();
+    order.sort();
+    order.extend(items);
+    order
+}
+
+/// The names in `en` with albums in order of release, after anything else
+/// in the directory, which includes albums without a date.
+fn date_order(en: &HashMap<OsString, Entry>, dates: &HashMap<u64, String>) -> Vec<OsString> {
+    let mut others: Vec<&OsString> = en.iter()
+        .filter(|(_, e)| !dates.contains_key(&e.ino))
+        .map(|(name, _)| name)
+        .collect();
+    others.sort();
+    let mut albums: Vec<(&String, &OsString)> = en.iter()
+        .filter_map(|(name, e)| dates.get(&e.ino).map(|date| (date, name)))
+        .collect();
+    albums.sort();
+    others.into_iter().chain(albums.into_iter().map(|(_, name)| name)).cloned().collect()
+}
+
+/// Fetches every page of a paged listing, handing each to `each` in order.
+/// Once the first page gives the total, the rest are fetched with at most
+/// `concurrency` requests in flight; pages that arrive early are held until
+/// those before them have been handed on.
+///
+/// Fails only if the first page does; later pages that fail are skipped.
+fn for_each_page<F, G>(page_size: u64, concurrency: usize, fetch: F, mut each: G) -> Result<()>
+    where F: Fn(u64, u64) -> Result<(api::MediaContainer, u64)> + Sync,
+          G: FnMut(api::MediaContainer)
+{
+    let (first, size) = fetch(0, page_size)?;
+    each(first);
+    let starts: Vec<u64> = (1..).map(|i| i * page_size).take_while(|start| *start < size).collect();
+    if starts.is_empty() {
+        return Ok(());
+    }
+    if concurrency <= 1 {
+        for start in starts {
+            if let Ok((container, _)) = fetch(start, page_size) {
+                each(container);
+            }
+        }
+        return Ok(());
+    }
+
+    let queue = Mutex::new(starts.iter());
+    let (sender, receiver) = mpsc::channel();
+    let caller = Caller::current();
+    thread::scope(|scope| {
+        for _ in 0..cmp::min(concurrency, starts.len()) {
+            let sender = sender.clone();
+            let (queue, fetch, caller) = (&queue, &fetch, &caller);
+            scope.spawn(move || caller.run(|| loop {
+                let start = match queue.lock().unwrap().next() {
+                    Some(start) => *start,
+                    None => break
+                };
+                let page = fetch(start, page_size).ok().map(|(container, _)| container);
+                if sender.send((start, page)).is_err() {
+                    break;
+                }
+            }));
+        }
+        drop(sender);
+
+        let mut early = HashMap::new();
+        let mut next = starts.iter().peekable();
+        for (start, page) in receiver {
+            early.insert(start, page);
+            while let Some(page) = next.peek().and_then(|start| early.remove(*start)) {
+                next.next();
+                if let Some(container) = page {
+                    each(container);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Fetches the pages of `section`'s items at `starts` at once, returning
+/// them in the same order.
+fn fetch_pages(api: &dyn MediaBackend, section: &Section, starts: &[u64], page_size: u64) -> Vec<Result<(api::MediaContainer, u64)>> {
+    if let [start] = starts {
+        return vec![api.all(section.key, section.kind, *start, page_size)];
+    }
+    let caller = &Caller::current();
+    thread::scope(|scope| {
+        let fetches: Vec<_> = starts.iter()
+            .map(|start| scope.spawn(move || caller.run(|| api.all(section.key, section.kind, *start, page_size))))
+            .collect();
+        fetches.into_iter()
+            .map(|fetch| fetch.join().unwrap_or_else(|_| Err(anyhow!("fetching a page panicked"))))
+            .collect()
+    })
+}
+
+/// Something looked for that isn't there, such as an item the server no
+/// longer has or an inode without media.
+#[derive(Debug)]
+struct NotFound(String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for NotFound {}
+
+pub(crate) fn not_found(what: String) -> anyhow::Error {
+    anyhow::Error::new(NotFound(what))
+}
+
+/// The errno to report for a failed operation: EACCES when the server
+/// refused the token, EAGAIN when it didn't answer in time, ENOENT when it
+/// or plexfs has no such item, and EIO for anything else, such as a server
+/// or network failure or a response that doesn't parse.
+pub fn errno(e: &anyhow::Error) -> c_int {
+    for cause in e.chain() {
+        if cause.is::<NotFound>() {
+            return ENOENT;
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return match e.status() {
+                Some(StatusCode::UNAUTHORIZED) | Some(StatusCode::FORBIDDEN) => EACCES,
+                Some(StatusCode::NOT_FOUND) => ENOENT,
+                _ if e.is_timeout() => EAGAIN,
+                _ => EIO
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => EAGAIN,
+                _ => EIO
+            };
         }
     }
+    EIO
 }
 
-const INO_ROOT: u64 = 1;
+pub const INO_ROOT: u64 = 1;
+
+/// Inodes from here up are allocated to `Node`s. Below it, an inode is
+/// `INO_ROOT + rating_key`.
+const INO_VIRTUAL: u64 = 1 << 62;
 
 const ROOT_DIR_ATTR: FileAttr = FileAttr {
     ino: INO_ROOT,
@@ -48,30 +3163,78 @@ const ROOT_DIR_ATTR: FileAttr = FileAttr {
     crtime: UNIX_EPOCH,
     kind: FileType::Directory,
     perm: 0o444,
-    nlink: 2,
+    nlink: 1,
     uid: 501,
     gid: 20,
     rdev: 0,
     flags: 0,
 };
 
-fn to_attr(item: &api::Item) -> Option<FileAttr> {
+/// An item's metadata as extended attributes, leaving out what it lacks.
+fn xattrs(item: &api::Item) -> Vec<(&'static str, String)> {
+    let (rating, user_rating) = item.ratings();
+    let genres = item.genres().iter().map(|genre| &genre.tag[..]).collect::<Vec<_>>();
+    let attrs = vec![
+        ("title", item.title().to_string()),
+        ("guid", item.guid().to_string()),
+        ("rating_key", item.rating_key().to_string()),
+        ("summary", item.summary().to_string()),
+        ("rating", rating.map(|rating| rating.to_string()).unwrap_or_default()),
+        ("user_rating", user_rating.map(|rating| rating.to_string()).unwrap_or_default()),
+        ("genre", genres.join(", ")),
+        ("year", item.year().map(|year| year.to_string()).unwrap_or_default()),
+    ];
+    attrs.into_iter().filter(|(_, value)| !value.is_empty()).collect()
+}
+
+/// Replies to `getxattr` or `listxattr` with `data`, or only its length if
+/// the caller passed a size of 0 to ask for it.
+pub fn reply_xattr(reply: ReplyXattr, data: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+fn dir_attr(ino: u64) -> FileAttr {
+    FileAttr { ino: ino, ..ROOT_DIR_ATTR }
+}
+
+fn file_attr(ino: u64, size: u64) -> FileAttr {
+    FileAttr {
+        ino: ino,
+        size: size,
+        blocks: 1,
+        kind: FileType::RegularFile,
+        nlink: 1,
+        ..ROOT_DIR_ATTR
+    }
+}
+
+fn to_attr(ino: u64, item: &api::Item, media: Option<&api::Media>, times: &TimePolicy) -> Option<FileAttr> {
     match item {
         api::Item::Directory {
-            rating_key,
+            child_count,
+            leaf_count,
             last_viewed_at,
             updated_at,
             added_at,
             ..
         } => {
-            let atime = UNIX_EPOCH + Duration::from_secs(*last_viewed_at);
-            let mtime = UNIX_EPOCH + Duration::from_secs(*updated_at);
-            let ctime = UNIX_EPOCH + Duration::from_secs(*added_at);
+            let atime = times.resolve(*last_viewed_at, item);
+            let mtime = times.resolve(*updated_at, item);
+            let ctime = times.resolve(*added_at, item);
             let crtime = ctime;
+            // Size is the number of entries, so tools can tell how big a
+            // directory is before listing it.
+            let size = if *child_count > 0 { *child_count } else { *leaf_count };
 
             Some(FileAttr {
-                ino: INO_ROOT + rating_key,
-                size: 0,
+                ino: ino,
+                size: size,
                 blocks: 0,
                 atime: atime,
                 mtime: mtime,
@@ -79,6 +3242,8 @@ fn to_attr(item: &api::Item) -> Option<FileAttr> {
                 crtime: crtime,
                 kind: FileType::Directory,
                 perm: 0o444,
+                // Plex's counts leave out Singles/, Disc N/ and the views
+                // plexfs adds, so the link count waits for the listing.
                 nlink: 1,
                 uid: 501,
                 gid: 20,
@@ -86,22 +3251,16 @@ fn to_attr(item: &api::Item) -> Option<FileAttr> {
                 flags: 0,
             })
         },
-        api::Item::Track {
-            rating_key,
-            last_viewed_at,
-            updated_at,
-            added_at,
-            media,
-            ..
-        } => {
-            let atime = UNIX_EPOCH + Duration::from_secs(*last_viewed_at);
-            let mtime = UNIX_EPOCH + Duration::from_secs(*updated_at);
-            let ctime = UNIX_EPOCH + Duration::from_secs(*added_at);
+        api::Item::Track { .. } | api::Item::Video { .. } | api::Item::Photo { .. } => {
+            let (last_viewed_at, updated_at, added_at) = item.timestamps();
+            let atime = times.resolve(last_viewed_at, item);
+            let mtime = times.resolve(updated_at, item);
+            let ctime = times.resolve(added_at, item);
             let crtime = ctime;
-            let size = media.part.size;
+            let size = media?.part.size;
 
             Some(FileAttr {
-                ino: INO_ROOT + rating_key,
+                ino: ino,
                 size: size,
                 blocks: 1,
                 atime: atime,
@@ -116,148 +3275,434 @@ fn to_attr(item: &api::Item) -> Option<FileAttr> {
                 rdev: 0,
                 flags: 0,
             })
-        },
-        _ => None
+        }
+    }
+}
+
+/// "Season NN", or "Specials" for season 0.
+fn season_name(index: Option<u64>, title: &str) -> String {
+    match index {
+        Some(0) => "Specials".into(),
+        Some(index) => format!("Season {:02}", index),
+        None => title.into()
+    }
+}
+
+/// A track's file name under `naming`. Episodes are always named
+/// "SxxEyy - Title" and movies "Title (Year)". The extension is the server file's, or derived from
+/// the container if that has none.
+fn file_name(item: &api::Item, media: &api::Media, naming: Naming, absolute: bool) -> String {
+    // Servers on Windows report paths with backslashes.
+    let base = media.part.file.rsplit(|c| c == '/' || c == '\\').next().unwrap_or("");
+    let (stem, ext) = match base.rfind('.') {
+        Some(i) if i > 0 && i + 1 < base.len() => (&base[..i], Some(&base[i + 1..])),
+        _ => (base, media.extension())
+    };
+
+    let stem = match (naming, item) {
+        (_, api::Item::Video { grandparent_title, absolute_index: Some(number), title, .. }) if absolute => {
+            format!("{} - {:04} - {}", grandparent_title, number, title)
+        }
+        (_, api::Item::Video { parent_index: Some(season), index: Some(episode), title, .. }) => {
+            format!("S{:02}E{:02} - {}", season, episode, title)
+        }
+        (_, api::Item::Video { kind, title, year: Some(year), .. }) if kind == "movie" => {
+            format!("{} ({})", title, year)
+        }
+        (_, api::Item::Video { kind, title, .. }) if kind == "movie" => title.clone(),
+        (Naming::File, _) if !stem.is_empty() => stem.to_string(),
+        (Naming::File, _) => item.title().to_string(),
+        (Naming::Title, api::Item::Track { index: Some(index), title, .. }) => format!("{:02} - {}", index, title),
+        (Naming::Title, _) => item.title().to_string()
+    };
+    match ext {
+        Some(ext) => format!("{}.{}", stem, ext),
+        None => stem
+    }
+}
+
+/// Whether `item` is the artist compilations are credited to, which a
+/// section grouping compilations lists as `Various Artists/` instead.
+fn is_various_artists(item: &api::Item) -> bool {
+    match item {
+        api::Item::Directory { kind, title, .. } if kind == "artist" => title.eq_ignore_ascii_case(api::VARIOUS_ARTISTS),
+        _ => false
+    }
+}
+
+/// The tracks on an album, and how many of them are credited to each
+/// artist other than the album's.
+#[derive(Default)]
+struct Credits {
+    tracks: u64,
+    others: HashMap<String, u64>,
+}
+
+fn count_credits(credits: &mut HashMap<u64, Credits>, page: &api::MediaContainer) {
+    for item in page.items.iter() {
+        if let api::Item::Track { parent_rating_key, original_title, .. } = item {
+            let album = credits.entry(*parent_rating_key).or_default();
+            album.tracks += 1;
+            if let Some(artist) = original_title {
+                *album.others.entry(artist.clone()).or_insert(0) += 1;
+            }
+        }
     }
 }
 
-fn escape_name(s: &str) -> String {
-    str::replace(s, "/", "_")
+/// The albums most of whose tracks are by other artists than the album's,
+/// with no one of them on most tracks: compilations that aren't tagged as
+/// such.
+fn mixed_albums(credits: &HashMap<u64, Credits>) -> HashSet<u64> {
+    credits.iter()
+        .filter(|(_, album)| {
+            let others: u64 = album.others.values().sum();
+            let most = album.others.values().cloned().max().unwrap_or(0);
+            others * 2 > album.tracks && most * 2 <= album.tracks
+        })
+        .map(|(key, _)| *key)
+        .collect()
+}
+
+/// Numbers `name` until it doesn't collide with an existing entry, keeping
+/// any extension last.
+fn unique_name(entries: &HashMap<OsString, Entry>, name: String) -> OsString {
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (&name[..], "")
+    };
+    let mut candidate = OsString::from(&name);
+    let mut n = 2;
+    while entries.contains_key(&candidate) {
+        candidate = OsString::from(format!("{} ({}){}", stem, n, ext));
+        n += 1;
+    }
+    candidate
 }
 
 impl Filesystem for PlexFS {
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         debug!("lookup {} {:?}", parent, name);
+        self.apply_commands();
+        Stats::incr(&self.control.stats.lookups, 1);
 
-        match self.entries.get(&parent) {
-            Some(names) => {
-                match names.get(name) {
-                    Some(entry) => match entry.attr {
-                        Some(attr) => reply.entry(&TTL, &attr, 0),
-                        None => reply.error(ENOENT)
-                    }
-                    _ => reply.error(ENOENT)
-                }
-            }
-            None => reply.error(ENOENT)
+        match self.entry_attr(parent, name) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(self.failure(parent).unwrap_or(ENOENT))
         }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         debug!("getattr {}", ino);
+        self.apply_commands();
+        Stats::incr(&self.control.stats.getattrs, 1);
 
-        if ino == INO_ROOT {
-            reply.attr(&TTL, &ROOT_DIR_ATTR);
-            return
+        match self.attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => {
+                warn!("getattr of {} failed: {}", ino, e);
+                reply.error(errno(&e))
+            }
         }
+    }
 
-        match self.api.metadata(ino - INO_ROOT) {
-            Ok(container) => {
-                match container.items.get(0) {
-                    Some(item) => {
-                        match to_attr(item) {
-                            Some(attr) => reply.attr(&TTL, &attr),
-                            None => reply.error(ENOENT)
-                        }
-                    }
-                    None => reply.error(ENOENT)
+    fn open(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        debug!("open {}", ino);
 
-                }
-            },
-            Err(_) => reply.error(ENOENT)
+        match self.open_file(ino) {
+            Ok((fh, flags)) => reply.opened(fh, flags),
+            Err(e) => {
+                warn!("open of {} failed: {}", ino, e);
+                reply.error(errno(&e))
+            }
         }
     }
 
-    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
+    fn release(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        self.release_file(fh);
+        reply.ok();
+    }
+
+    fn read(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
         debug!("read {} {} {}", ino, offset, size);
+        self.apply_commands();
+        Stats::incr(&self.control.stats.reads, 1);
 
-        if ino == INO_ROOT {
-            reply.error(ENOENT);
-            return
-        }
-
-        match self.api.metadata(ino - INO_ROOT) {
-            Ok(container) => {
-                match container.items.get(0) {
-                    Some(item) => {
-                        match item {
-                            api::Item::Track { media, .. } => {
-                                match self.api.file(&media.part, offset, size) {
-                                    Ok(body) => reply.data(&body[0..cmp::min(size as usize, body.len())]),
-                                    Err(_) => reply.error(ENOENT)
-                                }
-                            }
-                            _ => reply.error(ENOENT)
-                        }
-                    }
-                    None => reply.error(ENOENT)
-                }
-            },
-            Err(_) => reply.error(ENOENT)
+        self.read_into(ino, fh, offset as u64, size, reply);
+    }
+
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        debug!("opendir {}", ino);
+        self.apply_commands();
+
+        let mut entries = vec![
+            (OsString::from("."), ino, FileType::Directory),
+            (OsString::from(".."), self.parent(ino), FileType::Directory),
+        ];
+        // Enough to fill the first reply, without fetching the rest of a
+        // large section.
+        entries.extend(self.listing(ino, self.options.page_size as usize));
+        if let Some(errno) = self.failure(ino) {
+            return reply.error(errno);
         }
+        let partial = match self.partial.contains_key(&ino) {
+            true => self.listed.get(&ino).cloned(),
+            false => None
+        };
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.open_dirs.insert(fh, OpenDir { entries: entries, partial: partial });
+        reply.opened(fh, 0);
     }
 
-    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+    fn readdir(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
         debug!("readdir {} {}", ino, offset);
+        self.apply_commands();
+        Stats::incr(&self.control.stats.readdirs, 1);
 
-        if !self.entries.contains_key(&ino) {
-            let mut en = HashMap::new();
-
-            let mut containers = vec![];
-
-            if ino == INO_ROOT {
-                let mut start = 0;
-                if let Ok((first, size)) = self.api.all(self.section, self.kind, start, PAGE_SIZE) {
-                    containers.push(first);
-                    start += PAGE_SIZE;
-                    while start < size {
-                        if let Ok((container, _)) = self.api.all(self.section, self.kind, start, PAGE_SIZE) {
-                            containers.push(container);
-                        }
-                        start += PAGE_SIZE;
-                    }
-                }
-            } else {
-                let mut start = 0;
-                if let Ok((first, size)) = self.api.metadata_children(ino - INO_ROOT, start, PAGE_SIZE) {
-                    containers.push(first);
-                    start += PAGE_SIZE;
-                    while start < size {
-                        if let Ok((container, _)) = self.api.metadata_children(ino - INO_ROOT, start, PAGE_SIZE) {
-                            containers.push(container);
-                        }
-                        start += PAGE_SIZE;
-                    }
-                }
+        let mut dir = match self.open_dirs.remove(&fh) {
+            Some(dir) => dir,
+            None => return reply.error(EBADF)
+        };
+        let wanted = offset as usize + self.options.page_size as usize;
+        if let Some(listed) = dir.partial.filter(|_| dir.entries.len() < wanted) {
+            // More of a section's pages, as long as it's the listing the
+            // rest came from.
+            if self.listed.get(&ino) == Some(&listed) {
+                let known = dir.entries.len() - 2;
+                dir.entries.extend(self.listing_from(ino, known, wanted));
+            }
+            if self.listed.get(&ino) != Some(&listed) || !self.partial.contains_key(&ino) {
+                dir.partial = None;
+            }
+        }
+
+        // Each entry's offset is its position in the listing plus one, so
+        // the kernel resumes after the last entry that fit.
+        for (i, (name, child, kind)) in dir.entries.iter().enumerate().skip(offset as usize) {
+            if reply.add(*child, (i + 1) as i64, *kind, name) {
+                // The buffer is full.
+                break
             }
+        }
+        self.open_dirs.insert(fh, dir);
 
-            for container in containers.iter() {
-                for item in container.items.iter() {
-                    let attr = to_attr(&item);
+        reply.ok();
+    }
 
-                    match item {
-                        api::Item::Directory { rating_key, title, .. } => {
-                            en.insert(OsString::from(escape_name(title)), Entry {rating_key: *rating_key, kind: FileType::RegularFile, attr: attr});
-                        },
-                        api::Item::Track { rating_key, media, .. } => {
-                            let path = &media.part.file;
-                            let filename: String = path.split("/").last().unwrap().into();
-                            en.insert(OsString::from(filename), Entry {rating_key: *rating_key, kind: FileType::RegularFile, attr: attr});
-                        },
-                        _ => ()
-                    }
-                }
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        self.open_dirs.remove(&fh);
+        reply.ok();
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!("getxattr {} {:?}", ino, name);
+        self.apply_commands();
+
+        match self.xattr(ino, name) {
+            Ok(Some(value)) => reply_xattr(reply, &value, size),
+            Ok(None) => reply.error(ENODATA),
+            Err(e) => {
+                warn!("getxattr of {} failed: {}", ino, e);
+                reply.error(errno(&e))
             }
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr {}", ino);
+        self.apply_commands();
 
-            self.entries.insert(ino, en);
+        match self.xattr_names(ino) {
+            Ok(names) => reply_xattr(reply, &names, size),
+            Err(e) => {
+                warn!("listxattr of {} failed: {}", ino, e);
+                reply.error(errno(&e))
+            }
         }
+    }
 
-        let entries = self.entries.get(&ino).unwrap();
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        debug!("statfs");
+        self.apply_commands();
 
-        for (i, (name, entry)) in entries.iter().enumerate().skip(offset as usize) {
-            reply.add(INO_ROOT + entry.rating_key, (i + 1) as i64, entry.kind, name);
+        // A read-only mount: everything is used, nothing is free.
+        let usage = self.usage();
+        let blocks = (usage.bytes + STATFS_BLOCK_SIZE - 1) / STATFS_BLOCK_SIZE;
+        reply.statfs(blocks, 0, 0, usage.files, 0, STATFS_BLOCK_SIZE as u32, NAME_MAX, STATFS_BLOCK_SIZE as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::AtomicUsize;
+    use api::{FirstCharacter, Genre, MediaContainer, MediaKind, Part, Playlist, StreamBody, SubtitleResult};
+    use reqwest::blocking::Response;
+
+    /// A server with one file, counting the reads of it.
+    struct Files {
+        data: Vec<u8>,
+        reads: AtomicUsize,
+    }
+
+    impl Files {
+        fn new(len: u64) -> Self {
+            Files { data: (0..len).map(|i| (i % 251) as u8).collect(), reads: AtomicUsize::new(0) }
         }
 
-        reply.ok();
+        fn part(&self) -> Part {
+            Part { key: "/library/parts/1/file.flac".to_string(), size: self.data.len() as u64, ..Part::default() }
+        }
+
+        fn reads(&self) -> usize {
+            self.reads.load(Ordering::SeqCst)
+        }
+    }
+
+    impl MediaBackend for Files {
+        fn base_url(&self) -> &str { "http://localhost:32400" }
+        fn max_connections(&self) -> usize { 1 }
+        fn sections(&self) -> Result<Vec<api::Section>> { unimplemented!() }
+        fn section_size(&self, _: u64) -> Result<u64> { unimplemented!() }
+        fn all(&self, _: u64, _: MediaKind, _: u64, _: u64) -> Result<(MediaContainer, u64)> { unimplemented!() }
+        fn all_of_type(&self, _: u64, _: u8, _: u64, _: u64) -> Result<(MediaContainer, u64)> { unimplemented!() }
+        fn metadata(&self, _: u64) -> Result<MediaContainer> { unimplemented!() }
+        fn metadata_json(&self, _: u64) -> Result<String> { unimplemented!() }
+        fn metadata_children(&self, _: u64, _: u64, _: u64) -> Result<(MediaContainer, u64)> { unimplemented!() }
+        fn metadata_leaves(&self, _: u64, _: u64, _: u64) -> Result<(MediaContainer, u64)> { unimplemented!() }
+        fn folder(&self, _: u64, _: Option<u64>, _: u64, _: u64) -> Result<(MediaContainer, u64)> { unimplemented!() }
+        fn collections(&self, _: u64, _: u64, _: u64) -> Result<(MediaContainer, u64)> { unimplemented!() }
+        fn collection_children(&self, _: u64, _: u64, _: u64) -> Result<(MediaContainer, u64)> { unimplemented!() }
+        fn genres(&self, _: u64) -> Result<Vec<Genre>> { unimplemented!() }
+        fn genre_items(&self, _: u64, _: MediaKind, _: &str, _: u64, _: u64) -> Result<(MediaContainer, u64)> { unimplemented!() }
+        fn first_characters(&self, _: u64, _: MediaKind) -> Result<Vec<FirstCharacter>> { unimplemented!() }
+        fn first_character_items(&self, _: u64, _: MediaKind, _: &str, _: u64, _: u64) -> Result<(MediaContainer, u64)> { unimplemented!() }
+        fn playlists(&self) -> Result<Vec<Playlist>> { unimplemented!() }
+        fn playlist_items(&self, _: u64, _: u64, _: u64) -> Result<(MediaContainer, u64)> { unimplemented!() }
+        fn recently_added(&self, _: MediaKind) -> Result<MediaContainer> { unimplemented!() }
+        fn on_deck(&self, _: u64) -> Result<MediaContainer> { unimplemented!() }
+        fn search_subtitles(&self, _: u64, _: &str) -> Result<Vec<SubtitleResult>> { unimplemented!() }
+        fn download_subtitle(&self, _: u64, _: &SubtitleResult) -> Result<()> { unimplemented!() }
+        fn sessions_json(&self) -> Result<String> { unimplemented!() }
+        fn notifications(&self) -> Result<Response> { unimplemented!() }
+        fn resource(&self, _: &str) -> Result<Vec<u8>> { unimplemented!() }
+        fn artwork(&self, _: &str, _: Option<(u32, u32)>) -> Result<Vec<u8>> { unimplemented!() }
+        fn stream(&self, _: &Part, _: u64) -> Result<Option<StreamBody>> { unimplemented!() }
+        fn fetch_body(&self, _: &deferred::Request) -> Result<deferred::Body> { unimplemented!() }
+
+        fn file(&self, _: &Part, offset: i64, size: u32) -> Result<Vec<u8>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let start = cmp::min(offset as usize, self.data.len());
+            let end = cmp::min(start + size as usize, self.data.len());
+            Ok(self.data[start..end].to_vec())
+        }
+    }
+
+    /// A chunk cache in an empty directory, removed when the test is done
+    /// with it.
+    struct TempCache {
+        dir: PathBuf,
+        cache: ChunkCache,
+    }
+
+    impl TempCache {
+        fn new(name: &str, max_size: u64) -> Self {
+            let dir = env::temp_dir().join(format!("plexfs-fs-{}-{}", process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            let cache = ChunkCache::open(&dir, max_size).unwrap();
+            TempCache { dir: dir, cache: cache }
+        }
+    }
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    const CHUNK: u64 = cache::CHUNK_SIZE;
+
+    #[test]
+    fn reads_across_chunks() {
+        let files = Files::new(2 * CHUNK + CHUNK / 2);
+        let temp = TempCache::new("across", cache::DEFAULT_CACHE_SIZE);
+        let part = files.part();
+
+        let body = fetch_cached(&files, &temp.cache, &part, CHUNK - 10, 20, None).unwrap();
+        assert_eq!(&body[..], &files.data[CHUNK as usize - 10..CHUNK as usize + 10]);
+        assert_eq!(files.reads(), 2);
+
+        // Both chunks are kept, so reading them again goes nowhere.
+        let body = fetch_cached(&files, &temp.cache, &part, 5, CHUNK as u32, None).unwrap();
+        assert_eq!(&body[..], &files.data[5..CHUNK as usize + 5]);
+        assert_eq!(files.reads(), 2);
+    }
+
+    #[test]
+    fn keeps_the_short_last_chunk() {
+        let files = Files::new(2 * CHUNK + 100);
+        let temp = TempCache::new("last", cache::DEFAULT_CACHE_SIZE);
+        let part = files.part();
+        let key = cache_key(&files, &part);
+
+        // Reading past the end stops at it.
+        let body = fetch_cached(&files, &temp.cache, &part, 2 * CHUNK + 50, 4096, None).unwrap();
+        assert_eq!(&body[..], &files.data[2 * CHUNK as usize + 50..]);
+        assert!(temp.cache.contains(&key, 2));
+
+        let body = fetch_cached(&files, &temp.cache, &part, 2 * CHUNK, 4096, None).unwrap();
+        assert_eq!(body.len(), 100);
+        assert_eq!(files.reads(), 1);
+    }
+
+    #[test]
+    fn short_chunk_of_unknown_size_isnt_kept() {
+        let files = Files::new(100);
+        let temp = TempCache::new("unknown", cache::DEFAULT_CACHE_SIZE);
+        let part = Part { size: 0, ..files.part() };
+        let key = cache_key(&files, &part);
+
+        let body = fetch_cached(&files, &temp.cache, &part, 0, 4096, None).unwrap();
+        assert_eq!(body, files.data);
+        // It may have been cut short rather than be the end of the file.
+        assert!(!temp.cache.contains(&key, 0));
+    }
+
+    #[test]
+    fn refetches_truncated_chunks() {
+        let files = Files::new(CHUNK + 100);
+        let temp = TempCache::new("truncated", cache::DEFAULT_CACHE_SIZE);
+        let part = files.part();
+        let key = cache_key(&files, &part);
+        temp.cache.put(&key, 0, &files.data[..1000]);
+
+        let body = fetch_cached(&files, &temp.cache, &part, 0, 2000, None).unwrap();
+        assert_eq!(&body[..], &files.data[..2000]);
+        assert_eq!(files.reads(), 1);
+        assert_eq!(temp.cache.get(&key, 0, part.size).map(|chunk| chunk.len() as u64), Some(CHUNK));
+    }
+
+    #[test]
+    fn evicts_under_cache_size() {
+        let files = Files::new(3 * CHUNK);
+        let temp = TempCache::new("evict", 2 * CHUNK);
+        let part = files.part();
+        let key = cache_key(&files, &part);
+
+        fetch_cached(&files, &temp.cache, &part, 0, 1, None).unwrap();
+        fetch_cached(&files, &temp.cache, &part, CHUNK, 1, None).unwrap();
+        // Chunk 0 is used again, so chunk 1 is the one to go.
+        fetch_cached(&files, &temp.cache, &part, 0, 1, None).unwrap();
+        fetch_cached(&files, &temp.cache, &part, 2 * CHUNK, 1, None).unwrap();
+        assert!(temp.cache.contains(&key, 0));
+        assert!(!temp.cache.contains(&key, 1));
+        assert!(temp.cache.contains(&key, 2));
+        assert_eq!(files.reads(), 3);
     }
 }
@@ -0,0 +1,906 @@
+//! A backend for Jellyfin, and Emby which it was forked from, describing
+//! their items in Plex's model.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Result};
+use reqwest::StatusCode;
+use reqwest::blocking::Response;
+use reqwest::header::{ACCEPT, RANGE};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use time::PrimitiveDateTime;
+
+use super::api::{self, ApiOptions, FirstCharacter, Genre, Item, Media, MediaContainer, MediaKind, Part, Playlist, Section, StreamBody, SubtitleResult, Tag};
+use super::backend::MediaBackend;
+use super::deferred;
+use super::scheduler::Scheduler;
+
+const TOKEN_HEADER: &str = "X-Emby-Token";
+
+/// Fields listed items should have beyond the defaults.
+const FIELDS: &str = "Path,MediaSources,DateCreated,Overview,ProviderIds,ChildCount,RecursiveItemCount,PremiereDate,Genres";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct User {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ItemsResult {
+    #[serde(default)]
+    items: Vec<JellyfinItem>,
+    #[serde(default)]
+    total_record_count: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct JellyfinItem {
+    id: String,
+    name: String,
+    /// "MusicArtist", "MusicAlbum", "Audio", "Movie", "Series", "Season",
+    /// "Episode", "Photo", "Folder" and so on.
+    #[serde(rename = "Type")]
+    kind: String,
+    /// What a library holds: "music", "movies", "tvshows", "photos"...
+    collection_type: Option<String>,
+    overview: Option<String>,
+    index_number: Option<u64>,
+    parent_index_number: Option<u64>,
+    production_year: Option<u64>,
+    premiere_date: Option<String>,
+    date_created: Option<String>,
+    album: Option<String>,
+    album_id: Option<String>,
+    album_artist: Option<String>,
+    album_artists: Vec<ItemRef>,
+    /// Who performs a track.
+    artists: Vec<String>,
+    series_name: Option<String>,
+    series_id: Option<String>,
+    child_count: Option<u64>,
+    recursive_item_count: Option<u64>,
+    provider_ids: HashMap<String, String>,
+    /// Out of 10.
+    community_rating: Option<f64>,
+    genres: Vec<String>,
+    /// Tags of the item's images, by type: "Primary", "Backdrop"...
+    image_tags: HashMap<String, String>,
+    media_sources: Vec<MediaSource>,
+    user_data: Option<UserData>,
+}
+
+/// Another item an item refers to, such as one of an album's artists.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct ItemRef {
+    id: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct MediaSource {
+    id: String,
+    path: Option<String>,
+    /// Can be several names, as in "mov,mp4,m4a".
+    container: Option<String>,
+    size: Option<u64>,
+    /// In bits per second.
+    bitrate: Option<u64>,
+    /// In 100 ns ticks.
+    run_time_ticks: Option<u64>,
+    media_streams: Vec<MediaStream>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct MediaStream {
+    /// "Video", "Audio" or "Subtitle".
+    #[serde(rename = "Type")]
+    kind: String,
+    codec: Option<String>,
+    height: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct UserData {
+    last_played_date: Option<String>,
+    /// The user's rating, out of 10.
+    rating: Option<f64>,
+}
+
+/// Rating keys are below this, leaving the inodes above them to plexfs.
+const KEY_SPACE: u64 = 1 << 48;
+
+/// Rating keys for Jellyfin's IDs, which are GUIDs, recorded as they're
+/// first seen. Kept with each ID is its item type, which decides how its
+/// children are listed.
+#[derive(Debug, Default)]
+struct Ids {
+    keys: HashMap<String, u64>,
+    ids: HashMap<u64, (String, String)>,
+}
+
+impl Ids {
+    /// An ID's key is a hash of it, so it's the same in every mount, whatever
+    /// order things are listed in. One that clashes with another ID's takes
+    /// the next free key.
+    fn key(&mut self, id: &str, kind: &str) -> u64 {
+        if let Some(key) = self.keys.get(id) {
+            let entry = self.ids.get_mut(key).unwrap();
+            if entry.1.is_empty() {
+                entry.1 = kind.to_string();
+            }
+            return *key;
+        }
+        let mut key = fnv1a(id.as_bytes()) % KEY_SPACE + 1;
+        while self.ids.contains_key(&key) {
+            key = key % KEY_SPACE + 1;
+        }
+        self.keys.insert(id.to_string(), key);
+        self.ids.insert(key, (id.to_string(), kind.to_string()));
+        key
+    }
+}
+
+/// The 64-bit FNV-1a hash of `bytes`, which unlike `std`'s hasher is fixed
+/// from one build to the next.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+pub struct JellyfinAPI {
+    base_url: String,
+    token: String,
+    /// Listings are per user, for what they can see and have played.
+    user_id: String,
+    client: reqwest::blocking::Client,
+    options: ApiOptions,
+    scheduler: Arc<Scheduler>,
+    ids: Mutex<Ids>,
+    /// Query arguments for `options.sort`, empty if not given.
+    sort_args: String,
+}
+
+/// The Jellyfin item types for a Plex metadata type.
+fn item_types(type_id: u8) -> Option<&'static str> {
+    match type_id {
+        1 => Some("Movie"),
+        2 => Some("Series"),
+        3 => Some("Season"),
+        4 => Some("Episode"),
+        8 => Some("MusicArtist"),
+        9 => Some("MusicAlbum"),
+        10 => Some("Audio"),
+        13 => Some("Photo"),
+        _ => None
+    }
+}
+
+/// Jellyfin's `SortBy` and `SortOrder` for a Plex sort order such as
+/// `addedAt:desc,titleSort`, as query arguments.
+fn sort_args(sort: &str) -> Result<String> {
+    let mut fields = vec![];
+    let mut orders = vec![];
+    for part in sort.split(',') {
+        let mut halves = part.splitn(2, ':');
+        let field = match halves.next().unwrap_or("") {
+            "addedAt" => "DateCreated",
+            "title" | "titleSort" => "SortName",
+            "year" => "ProductionYear",
+            "originallyAvailableAt" => "PremiereDate",
+            "lastViewedAt" => "DatePlayed",
+            "viewCount" => "PlayCount",
+            "rating" => "CommunityRating",
+            "duration" => "Runtime",
+            "random" => "Random",
+            other => return Err(anyhow!("can't sort by \"{}\" with Jellyfin", other))
+        };
+        fields.push(field);
+        orders.push(match halves.next() {
+            Some("desc") => "Descending",
+            _ => "Ascending"
+        });
+    }
+    Ok(format!("&SortBy={}&SortOrder={}", fields.join(","), orders.join(",")))
+}
+
+/// Seconds since the epoch of a date like `2021-03-04T12:34:56.1234567Z`.
+fn timestamp(date: &str) -> Option<u64> {
+    let date = PrimitiveDateTime::parse(date.get(..19)?, "%Y-%m-%dT%H:%M:%S").ok()?;
+    Some(cmp::max(date.assume_utc().timestamp(), 0) as u64)
+}
+
+fn media(source: &MediaSource) -> Media {
+    let stream = |kind: &str| source.media_streams.iter().find(|s| s.kind == kind);
+    // The file's own extension says more than a list of container names.
+    let container = source.path.as_ref()
+        .and_then(|path| Path::new(path).extension())
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .or_else(|| source.container.as_ref().and_then(|c| c.split(',').next()).map(String::from));
+    let height = stream("Video").and_then(|s| s.height);
+    Media {
+        container: container.clone(),
+        video_resolution: height.map(|h| h.to_string()),
+        height: height,
+        bitrate: source.bitrate.map(|b| b / 1000),
+        audio_codec: stream("Audio").and_then(|s| s.codec.clone()),
+        duration: source.run_time_ticks.unwrap_or(0) / 10_000,
+        part: Part {
+            key: format!("/Items/{}/Download", source.id),
+            file: source.path.clone().unwrap_or_default(),
+            size: source.size.unwrap_or(0),
+            container: container,
+            streams: vec![],
+        }
+    }
+}
+
+impl JellyfinAPI {
+    /// Connects as the user `token` belongs to.
+    pub fn new(base_url: String, token: String, options: ApiOptions) -> Result<Self> {
+        if !options.filters.is_empty() {
+            return Err(anyhow!("--filter isn't supported with Jellyfin"));
+        }
+        let sort_args = match &options.sort {
+            Some(sort) => sort_args(sort)?,
+            None => String::new()
+        };
+        let client = api::client_builder(&options)?
+            .pool_max_idle_per_host(options.max_connections)
+            .timeout(options.read_timeout)
+            .build()?;
+        let mut jellyfin = JellyfinAPI {
+            base_url: base_url,
+            token: token,
+            user_id: String::new(),
+            client: client,
+            scheduler: Arc::new(Scheduler::new(options.max_connections)),
+            options: options,
+            ids: Mutex::new(Ids::default()),
+            sort_args: sort_args,
+        };
+        let user: User = jellyfin.get("/Users/Me", "")?;
+        jellyfin.user_id = user.id;
+        Ok(jellyfin)
+    }
+
+    fn get_text(&self, url: &str, args: &str) -> Result<String> {
+        let request = deferred::Request {
+            url: url.to_string(),
+            args: args.to_string(),
+            start: 0,
+            size: 0,
+        };
+        match deferred::answer(request) {
+            Some(body) => Ok(String::from_utf8(body?.0)?),
+            None => self.fetch_text(url, args)
+        }
+    }
+
+    fn fetch_text(&self, url: &str, args: &str) -> Result<String> {
+        api::retrying(&self.options, url, || {
+            let _permit = self.scheduler.acquire();
+            let full_url = format!("{}{}?{}", self.base_url, url, args.trim_start_matches('&'));
+            debug!("GET {}", full_url);
+            let resp = self.client.get(&full_url)
+                .header(TOKEN_HEADER, &self.token[..])
+                .header(ACCEPT, "application/json")
+                .send()?
+                .error_for_status()?;
+            Ok(resp.text()?)
+        })
+    }
+
+    fn get<T>(&self, url: &str, args: &str) -> Result<T>
+        where T: DeserializeOwned
+    {
+        let text = self.get_text(url, args)?;
+        Ok(serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
+    }
+
+    /// A page of the user's items matching `args`, and the total.
+    fn items(&self, url: &str, args: &str, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let args = format!("{}&StartIndex={}&Limit={}&Fields={}", args, start, size, FIELDS);
+        let result: ItemsResult = self.get(url, &args)?;
+        Ok((self.container(&result.items), result.total_record_count))
+    }
+
+    fn user_items(&self, args: &str, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        self.items(&format!("/Users/{}/Items", self.user_id), args, start, size)
+    }
+
+    /// The IDs of what's in the collection `id` and those nested in it.
+    fn collection_items(&self, id: &str, seen: &mut HashSet<String>) -> Result<Vec<String>> {
+        if !seen.insert(id.to_string()) {
+            return Ok(vec![]);
+        }
+        let children: ItemsResult = self.get(&format!("/Users/{}/Items", self.user_id), &format!("&ParentId={}", id))?;
+        let mut ids = vec![];
+        for child in children.items {
+            match &child.kind[..] {
+                "BoxSet" => ids.extend(self.collection_items(&child.id, seen)?),
+                _ => ids.push(child.id)
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Whether any of `ids` is in the library `library`.
+    fn any_in_library(&self, library: &str, ids: &[String]) -> Result<bool> {
+        for chunk in ids.chunks(100) {
+            let args = format!("&ParentId={}&Recursive=true&Ids={}&Limit=0", library, chunk.join(","));
+            let result: ItemsResult = self.get(&format!("/Users/{}/Items", self.user_id), &args)?;
+            if result.total_record_count > 0 {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn key(&self, id: &str, kind: &str) -> u64 {
+        self.ids.lock().unwrap().key(id, kind)
+    }
+
+    /// The Jellyfin ID and item type of a rating key.
+    fn id(&self, rating_key: u64) -> Result<(String, String)> {
+        self.ids.lock().unwrap().ids.get(&rating_key).cloned()
+            .ok_or_else(|| anyhow!("no item {}", rating_key))
+    }
+
+    fn container(&self, items: &[JellyfinItem]) -> MediaContainer {
+        MediaContainer { items: items.iter().map(|item| self.item(item)).collect() }
+    }
+
+    fn item(&self, item: &JellyfinItem) -> Item {
+        let rating_key = self.key(&item.id, &item.kind);
+        // Provider IDs are shared by copies of the same thing, as Plex's
+        // GUIDs are.
+        let guid = match item.provider_ids.iter().min() {
+            Some((provider, id)) => format!("{}://{}", provider.to_lowercase(), id),
+            None => format!("jellyfin://{}", item.id)
+        };
+        let title = item.name.clone();
+        let summary = item.overview.clone().unwrap_or_default();
+        let added_at = item.date_created.as_ref().and_then(|d| timestamp(d)).unwrap_or(0);
+        let last_viewed_at = item.user_data.as_ref()
+            .and_then(|d| d.last_played_date.as_ref())
+            .and_then(|d| timestamp(d))
+            .unwrap_or(0);
+        let date = item.premiere_date.as_ref().and_then(|d| d.get(..10)).map(String::from);
+        let media: Vec<Media> = item.media_sources.iter().map(media).collect();
+        let user_rating = item.user_data.as_ref().and_then(|d| d.rating);
+        let genres: Vec<Tag> = item.genres.iter().map(|genre| Tag { tag: genre.clone() }).collect();
+
+        match &item.kind[..] {
+            "Audio" => Item::Track {
+                rating_key: rating_key,
+                guid: guid,
+                title: title,
+                index: item.index_number,
+                parent_index: item.parent_index_number,
+                parent_rating_key: item.album_id.as_ref().map_or(0, |id| self.key(id, "MusicAlbum")),
+                parent_title: item.album.clone().unwrap_or_default(),
+                parent_year: item.production_year,
+                grandparent_title: item.album_artist.clone().unwrap_or_default(),
+                grandparent_rating_key: item.album_artists.first().map_or(0, |artist| self.key(&artist.id, "MusicArtist")),
+                original_title: match (item.artists.first(), &item.album_artist) {
+                    (Some(artist), Some(album_artist)) if artist == album_artist => None,
+                    (artist, _) => artist.cloned()
+                },
+                summary: summary,
+                rating: item.community_rating,
+                user_rating: user_rating,
+                genres: genres,
+                last_viewed_at: last_viewed_at,
+                added_at: added_at,
+                updated_at: added_at,
+                media: media
+            },
+            "Photo" => Item::Photo {
+                rating_key: rating_key,
+                guid: guid,
+                title: title,
+                originally_available_at: date,
+                summary: summary,
+                added_at: added_at,
+                updated_at: added_at,
+                media: media
+            },
+            kind if !media.is_empty() => Item::Video {
+                rating_key: rating_key,
+                guid: guid,
+                kind: match kind {
+                    "Movie" => "movie",
+                    "Episode" => "episode",
+                    _ => "clip"
+                }.to_string(),
+                title: title,
+                grandparent_title: item.series_name.clone().unwrap_or_default(),
+                grandparent_rating_key: item.series_id.as_ref().map_or(0, |id| self.key(id, "Series")),
+                year: item.production_year,
+                index: item.index_number,
+                parent_index: item.parent_index_number,
+                absolute_index: None,
+                summary: summary,
+                rating: item.community_rating,
+                user_rating: user_rating,
+                genres: genres,
+                last_viewed_at: last_viewed_at,
+                added_at: added_at,
+                updated_at: added_at,
+                media: media
+            },
+            kind => Item::Directory {
+                rating_key: rating_key,
+                key: format!("/Items/{}", item.id),
+                guid: guid,
+                title: title,
+                parent_title: item.album_artist.clone().unwrap_or_default(),
+                parent_rating_key: match &item.kind[..] {
+                    "MusicAlbum" => item.album_artists.first().map_or(0, |artist| self.key(&artist.id, "MusicArtist")),
+                    "Season" => item.series_id.as_ref().map_or(0, |id| self.key(id, "Series")),
+                    _ => 0
+                },
+                summary: summary,
+                kind: match kind {
+                    "MusicArtist" => "artist",
+                    "MusicAlbum" => "album",
+                    "Series" => "show",
+                    "Season" => "season",
+                    "BoxSet" => "collection",
+                    _ => ""
+                }.to_string(),
+                index: item.index_number,
+                year: item.production_year,
+                originally_available_at: date,
+                thumb: match item.image_tags.contains_key("Primary") {
+                    true => Some(format!("/Items/{}/Images/Primary", item.id)),
+                    false => None
+                },
+                art: None,
+                child_count: item.child_count.unwrap_or(0),
+                leaf_count: item.recursive_item_count.unwrap_or(0),
+                rating: item.community_rating,
+                user_rating: user_rating,
+                genres: genres,
+                subformats: vec![],
+                last_viewed_at: last_viewed_at,
+                added_at: added_at,
+                updated_at: added_at,
+            }
+        }
+    }
+
+    /// Appends up to `size` bytes at `offset` to `buf`. Bytes received
+    /// before an error are kept.
+    fn file_range(&self, part: &Part, offset: i64, size: u32, buf: &mut Vec<u8>) -> Result<()> {
+        let _permit = self.scheduler.acquire();
+        let full_url = format!("{}{}", self.base_url, part.key);
+        debug!("GET {}", full_url);
+        let resp = self.client.get(&full_url)
+            .header(TOKEN_HEADER, &self.token[..])
+            .header(RANGE, format!("bytes={}-{}", offset, offset + size as i64 - 1))
+            .send()?;
+        if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(());
+        }
+        let resp = resp.error_for_status()?;
+        resp.take(size as u64).read_to_end(buf)?;
+        Ok(())
+    }
+}
+
+impl MediaBackend for JellyfinAPI {
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn max_connections(&self) -> usize {
+        self.options.max_connections
+    }
+
+    /// Libraries, with their collection types named as Plex's section
+    /// types. Their folders on disk aren't listed.
+    fn sections(&self) -> Result<Vec<Section>> {
+        let result: ItemsResult = self.get(&format!("/Users/{}/Views", self.user_id), "")?;
+        Ok(result.items.iter().map(|view| {
+            let kind = match view.collection_type.as_ref().map(|t| &t[..]) {
+                Some("music") => "artist",
+                Some("movies") => "movie",
+                Some("tvshows") => "show",
+                Some("photos") | Some("homevideos") => "photo",
+                Some(other) => other,
+                None => ""
+            };
+            Section {
+                key: self.key(&view.id, &view.kind),
+                kind: kind.to_string(),
+                title: view.name.clone(),
+                locations: vec![],
+            }
+        }).collect())
+    }
+
+    fn section_size(&self, section: u64) -> Result<u64> {
+        let (id, _) = self.id(section)?;
+        self.user_items(&format!("&ParentId={}", id), 0, 0).map(|(_, total)| total)
+    }
+
+    fn all(&self, section: u64, kind: MediaKind, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let (id, _) = self.id(section)?;
+        let sort = &self.sort_args;
+        match kind {
+            // Artists aren't below the library's folders.
+            MediaKind::Music => {
+                let args = format!("&ParentId={}&UserId={}{}", id, self.user_id, sort);
+                self.items("/Artists/AlbumArtists", &args, start, size)
+            }
+            MediaKind::TV => self.user_items(&format!("&ParentId={}&Recursive=true&IncludeItemTypes=Series{}", id, sort), start, size),
+            MediaKind::Video => self.user_items(&format!("&ParentId={}&Recursive=true&IncludeItemTypes=Movie{}", id, sort), start, size),
+            MediaKind::Photo => self.user_items(&format!("&ParentId={}{}", id, sort), start, size)
+        }
+    }
+
+    fn all_of_type(&self, section: u64, type_id: u8, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let (id, _) = self.id(section)?;
+        let types = item_types(type_id).ok_or_else(|| anyhow!("no Jellyfin item type for type {}", type_id))?;
+        self.user_items(&format!("&ParentId={}&Recursive=true&IncludeItemTypes={}{}", id, types, self.sort_args), start, size)
+    }
+
+    fn metadata(&self, rating_key: u64) -> Result<MediaContainer> {
+        let (id, _) = self.id(rating_key)?;
+        let item: JellyfinItem = self.get(&format!("/Users/{}/Items/{}", self.user_id, id), "")?;
+        Ok(self.container(&[item]))
+    }
+
+    fn metadata_json(&self, rating_key: u64) -> Result<String> {
+        let (id, _) = self.id(rating_key)?;
+        self.get_text(&format!("/Users/{}/Items/{}", self.user_id, id), "")
+    }
+
+    /// An artist's children are their albums; anything else's are the items
+    /// directly below it.
+    fn metadata_children(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let args = match self.id(rating_key)? {
+            (id, ref kind) if kind == "MusicArtist" => format!("&AlbumArtistIds={}&IncludeItemTypes=MusicAlbum&Recursive=true", id),
+            (id, _) => format!("&ParentId={}", id)
+        };
+        self.user_items(&args, start, size)
+    }
+
+    fn metadata_leaves(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let args = match self.id(rating_key)? {
+            (id, ref kind) if kind == "MusicArtist" => format!("&ArtistIds={}&IncludeItemTypes=Audio&Recursive=true", id),
+            (id, _) => format!("&ParentId={}&Recursive=true&IsFolder=false", id)
+        };
+        self.user_items(&args, start, size)
+    }
+
+    fn folder(&self, _section: u64, _parent: Option<u64>, _start: u64, _size: u64) -> Result<(MediaContainer, u64)> {
+        Err(anyhow!("the folders layout isn't supported with Jellyfin"))
+    }
+
+    /// Jellyfin keeps collections in a library of their own, so a section's
+    /// are those holding something from it, directly or through a
+    /// collection nested in them. Jellyfin has no smart collections.
+    fn collections(&self, section: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let (library, _) = self.id(section)?;
+        let url = format!("/Users/{}/Items", self.user_id);
+        let sets: ItemsResult = self.get(&url, &format!("&IncludeItemTypes=BoxSet&Recursive=true&Fields={}{}", FIELDS, self.sort_args))?;
+        let mut found = vec![];
+        for set in sets.items {
+            let ids = self.collection_items(&set.id, &mut HashSet::new())?;
+            if self.any_in_library(&library, &ids)? {
+                found.push(set);
+            }
+        }
+        let total = found.len() as u64;
+        let page: Vec<_> = found.into_iter().skip(start as usize).take(size as usize).collect();
+        Ok((self.container(&page), total))
+    }
+
+    /// A collection's members are its children, nested collections among
+    /// them.
+    fn collection_children(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        self.metadata_children(rating_key, start, size)
+    }
+
+    fn genres(&self, section: u64) -> Result<Vec<Genre>> {
+        let (id, _) = self.id(section)?;
+        let result: ItemsResult = self.get("/Genres", &format!("&ParentId={}&UserId={}", id, self.user_id))?;
+        Ok(result.items.into_iter().map(|genre| Genre { key: genre.id, title: genre.name }).collect())
+    }
+
+    fn genre_items(&self, section: u64, kind: MediaKind, genre: &str, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let (id, _) = self.id(section)?;
+        let types = item_types(kind as u8).ok_or_else(|| anyhow!("no genres in {:?} sections", kind))?;
+        let args = format!("&ParentId={}&Recursive=true&IncludeItemTypes={}&GenreIds={}{}", id, types, genre, self.sort_args);
+        self.user_items(&args, start, size)
+    }
+
+    /// Jellyfin has no such listing, so it's every letter whether or not
+    /// anything starts with it.
+    fn first_characters(&self, _section: u64, _kind: MediaKind) -> Result<Vec<FirstCharacter>> {
+        Ok(std::iter::once('#').chain('A'..='Z')
+           .map(|c| FirstCharacter { key: c.to_string(), title: c.to_string() })
+           .collect())
+    }
+
+    fn first_character_items(&self, section: u64, kind: MediaKind, character: &str, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        // Sort names before "A" are those starting with digits and symbols.
+        let filter = match character {
+            "#" => "&NameLessThan=A".to_string(),
+            letter => format!("&NameStartsWith={}", letter)
+        };
+        let (id, _) = self.id(section)?;
+        let types = item_types(kind as u8).ok_or_else(|| anyhow!("can't list {:?} sections by letter", kind))?;
+        let args = format!("&ParentId={}&Recursive=true&IncludeItemTypes={}{}{}", id, types, filter, self.sort_args);
+        self.user_items(&args, start, size)
+    }
+
+    fn playlists(&self) -> Result<Vec<Playlist>> {
+        let url = format!("/Users/{}/Items", self.user_id);
+        let result: ItemsResult = self.get(&url, "&IncludeItemTypes=Playlist&Recursive=true")?;
+        Ok(result.items.iter().map(|item| Playlist {
+            rating_key: self.key(&item.id, &item.kind),
+            title: item.name.clone(),
+        }).collect())
+    }
+
+    fn playlist_items(&self, rating_key: u64, start: u64, size: u64) -> Result<(MediaContainer, u64)> {
+        let (id, _) = self.id(rating_key)?;
+        let args = format!("&UserId={}", self.user_id);
+        self.items(&format!("/Playlists/{}/Items", id), &args, start, size)
+    }
+
+    fn recently_added(&self, kind: MediaKind) -> Result<MediaContainer> {
+        // Jellyfin groups episodes and tracks into their seasons and albums,
+        // as Plex does.
+        let types = item_types(kind.leaf_type()).unwrap_or("");
+        let args = format!("&IncludeItemTypes={}&Limit=100&Fields={}", types, FIELDS);
+        let items: Vec<JellyfinItem> = self.get(&format!("/Users/{}/Items/Latest", self.user_id), &args)?;
+        Ok(self.container(&items))
+    }
+
+    fn on_deck(&self, section: u64) -> Result<MediaContainer> {
+        let (id, _) = self.id(section)?;
+        let (resume, _) = self.user_items(&format!("&ParentId={}&Filters=IsResumable&Recursive=true&SortBy=DatePlayed&SortOrder=Descending", id), 0, 100)?;
+        let args = format!("&UserId={}&ParentId={}", self.user_id, id);
+        let (next_up, _) = self.items("/Shows/NextUp", &args, 0, 100)?;
+        let mut items = resume.items;
+        items.extend(next_up.items);
+        // Most recently played first, as Plex lists it. An episode part
+        // watched can also be the next one up.
+        items.sort_by_key(|item| cmp::Reverse(item.timestamps().0));
+        let mut seen = HashSet::new();
+        items.retain(|item| seen.insert(item.rating_key()));
+        Ok(MediaContainer { items: items })
+    }
+
+    fn search_subtitles(&self, _rating_key: u64, _language: &str) -> Result<Vec<SubtitleResult>> {
+        Err(anyhow!("searching for subtitles isn't supported with Jellyfin"))
+    }
+
+    fn download_subtitle(&self, _rating_key: u64, _subtitle: &SubtitleResult) -> Result<()> {
+        Err(anyhow!("searching for subtitles isn't supported with Jellyfin"))
+    }
+
+    fn sessions_json(&self) -> Result<String> {
+        self.get_text("/Sessions", "")
+    }
+
+    fn notifications(&self) -> Result<Response> {
+        Err(anyhow!("following library changes isn't supported with Jellyfin"))
+    }
+
+    fn resource(&self, path: &str) -> Result<Vec<u8>> {
+        api::retrying(&self.options, path, || {
+            let _permit = self.scheduler.acquire();
+            let full_url = format!("{}{}", self.base_url, path);
+            debug!("GET {}", full_url);
+            let mut resp = self.client.get(&full_url)
+                .header(TOKEN_HEADER, &self.token[..])
+                .send()?
+                .error_for_status()?;
+            let mut body = vec![];
+            resp.copy_to(&mut body)?;
+            Ok(body)
+        })
+    }
+
+    fn artwork(&self, thumb: &str, size: Option<(u32, u32)>) -> Result<Vec<u8>> {
+        match size {
+            Some((width, height)) => self.resource(&format!("{}?maxWidth={}&maxHeight={}", thumb, width, height)),
+            None => self.resource(thumb)
+        }
+    }
+
+    fn file(&self, part: &Part, offset: i64, size: u32) -> Result<Vec<u8>> {
+        api::read_resuming(&self.options, part, offset, size, |offset, size, buf| self.file_range(part, offset, size, buf))
+    }
+
+    fn stream(&self, part: &Part, offset: u64) -> Result<Option<StreamBody>> {
+        api::retrying(&self.options, &part.key, || {
+            let permit = match self.scheduler.clone().try_acquire_stream() {
+                Some(permit) => permit,
+                None => return Ok(None)
+            };
+            let full_url = format!("{}{}", self.base_url, part.key);
+            debug!("GET {} (streaming from {})", full_url, offset);
+            let resp = self.client.get(&full_url)
+                .header(TOKEN_HEADER, &self.token[..])
+                .header(RANGE, format!("bytes={}-", offset))
+                .send()?;
+            if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+                return Ok(None);
+            }
+            Ok(Some(StreamBody::new(resp.error_for_status()?, permit)))
+        })
+    }
+
+    fn fetch_body(&self, request: &deferred::Request) -> Result<deferred::Body> {
+        Ok((self.fetch_text(&request.url, &request.args)?.into_bytes(), 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jellyfin() -> JellyfinAPI {
+        let options = ApiOptions::default();
+        JellyfinAPI {
+            base_url: "http://localhost:8096".to_string(),
+            token: String::new(),
+            user_id: "user".to_string(),
+            client: reqwest::blocking::Client::new(),
+            scheduler: Arc::new(Scheduler::new(options.max_connections)),
+            options: options,
+            ids: Mutex::new(Ids::default()),
+            sort_args: String::new(),
+        }
+    }
+
+    fn parse(json: &str) -> JellyfinItem {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn keys_are_stable() {
+        let (mut first, mut second) = (Ids::default(), Ids::default());
+        let a = first.key("f27caa37e5142225cceded48f6553502", "MusicAlbum");
+        first.key("0b8c1f7a2d3e4f5a6b7c8d9e0f1a2b3c", "Audio");
+        second.key("0b8c1f7a2d3e4f5a6b7c8d9e0f1a2b3c", "Audio");
+        assert_eq!(second.key("f27caa37e5142225cceded48f6553502", "MusicAlbum"), a);
+        assert!(a > 0 && a <= KEY_SPACE);
+    }
+
+    #[test]
+    fn clashing_keys_take_the_next() {
+        let mut ids = Ids::default();
+        let key = fnv1a(b"a") % KEY_SPACE + 1;
+        ids.ids.insert(key, ("other".to_string(), String::new()));
+        assert_eq!(ids.key("a", ""), key % KEY_SPACE + 1);
+        assert_eq!(ids.ids[&(key % KEY_SPACE + 1)].0, "a");
+    }
+
+    #[test]
+    fn track() {
+        let jellyfin = jellyfin();
+        let item = jellyfin.item(&parse(r#"{
+            "Id": "t1", "Name": "Song", "Type": "Audio",
+            "IndexNumber": 3, "ParentIndexNumber": 1, "ProductionYear": 1999,
+            "Album": "Album", "AlbumId": "a1", "AlbumArtist": "Band",
+            "AlbumArtists": [{"Id": "ar1", "Name": "Band"}], "Artists": ["Guest"],
+            "DateCreated": "2021-03-04T12:34:56.1234567Z",
+            "UserData": {"LastPlayedDate": "2021-03-05T00:00:00Z", "Rating": 8.0},
+            "MediaSources": [{"Id": "m1", "Path": "/music/Band/Album/03.FLAC", "Container": "flac",
+                              "Size": 1234, "Bitrate": 900000, "RunTimeTicks": 1800000000,
+                              "MediaStreams": [{"Type": "Audio", "Codec": "flac"}]}]
+        }"#));
+        match &item {
+            Item::Track { index, parent_index, parent_rating_key, parent_title, grandparent_title,
+                          grandparent_rating_key, original_title, user_rating, added_at, last_viewed_at, .. } => {
+                assert_eq!((*index, *parent_index), (Some(3), Some(1)));
+                assert_eq!(*parent_rating_key, jellyfin.key("a1", ""));
+                assert_eq!(*grandparent_rating_key, jellyfin.key("ar1", ""));
+                assert_eq!((&parent_title[..], &grandparent_title[..]), ("Album", "Band"));
+                assert_eq!(original_title.as_ref().map(|t| &t[..]), Some("Guest"));
+                assert_eq!(*user_rating, Some(8.0));
+                assert_eq!((*added_at, *last_viewed_at), (1614861296, 1614902400));
+            }
+            other => panic!("not a track: {:?}", other)
+        }
+        assert_eq!(jellyfin.id(item.rating_key()).unwrap(), ("t1".to_string(), "Audio".to_string()));
+
+        let media = &item.media()[0];
+        assert_eq!(media.container.as_ref().map(|c| &c[..]), Some("flac"));
+        assert_eq!((media.bitrate, media.duration), (Some(900), 180_000));
+        assert_eq!((media.part.size, &media.part.key[..]), (1234, "/Items/m1/Download"));
+    }
+
+    #[test]
+    fn album_artist_isnt_an_original_title() {
+        let item = jellyfin().item(&parse(r#"{"Id": "t1", "Type": "Audio", "AlbumArtist": "Band", "Artists": ["Band"]}"#));
+        match item {
+            Item::Track { original_title, .. } => assert_eq!(original_title, None),
+            other => panic!("not a track: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn album() {
+        let jellyfin = jellyfin();
+        let item = jellyfin.item(&parse(r#"{
+            "Id": "a1", "Name": "Album", "Type": "MusicAlbum", "AlbumArtist": "Band",
+            "AlbumArtists": [{"Id": "ar1"}], "ProductionYear": 1999,
+            "PremiereDate": "1999-06-01T00:00:00.0000000Z", "ChildCount": 12,
+            "ImageTags": {"Primary": "abc"}, "Genres": ["Rock"], "SomethingNew": true
+        }"#));
+        match item {
+            Item::Directory { kind, parent_title, parent_rating_key, year, originally_available_at, thumb, child_count, genres, .. } => {
+                assert_eq!(kind, "album");
+                assert_eq!(parent_title, "Band");
+                assert_eq!(parent_rating_key, jellyfin.key("ar1", ""));
+                assert_eq!(year, Some(1999));
+                assert_eq!(originally_available_at.as_ref().map(|d| &d[..]), Some("1999-06-01"));
+                assert_eq!(thumb.as_ref().map(|t| &t[..]), Some("/Items/a1/Images/Primary"));
+                assert_eq!(child_count, 12);
+                assert_eq!(genres, vec![Tag { tag: "Rock".to_string() }]);
+            }
+            other => panic!("not a directory: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn episode() {
+        let jellyfin = jellyfin();
+        let item = jellyfin.item(&parse(r#"{
+            "Id": "e1", "Name": "Pilot", "Type": "Episode", "SeriesName": "Show", "SeriesId": "s1",
+            "IndexNumber": 1, "ParentIndexNumber": 1, "ProviderIds": {"Tvdb": "42", "Imdb": "tt1"},
+            "MediaSources": [{"Id": "m1", "Container": "mkv,webm",
+                              "MediaStreams": [{"Type": "Video", "Height": 1080}]}]
+        }"#));
+        assert_eq!(item.guid(), "imdb://tt1");
+        match &item {
+            Item::Video { kind, grandparent_title, grandparent_rating_key, .. } => {
+                assert_eq!(kind, "episode");
+                assert_eq!(grandparent_title, "Show");
+                assert_eq!(*grandparent_rating_key, jellyfin.key("s1", ""));
+            }
+            other => panic!("not a video: {:?}", other)
+        }
+        let media = &item.media()[0];
+        assert_eq!(media.container.as_ref().map(|c| &c[..]), Some("mkv"));
+        assert_eq!(media.video_resolution.as_ref().map(|r| &r[..]), Some("1080"));
+    }
+
+    #[test]
+    fn items_result() {
+        let result: ItemsResult = serde_json::from_str(r#"{"Items": [{"Id": "x", "Type": "Folder"}], "TotalRecordCount": 7}"#).unwrap();
+        assert_eq!((result.items.len(), result.total_record_count), (1, 7));
+        let empty: ItemsResult = serde_json::from_str("{}").unwrap();
+        assert!(empty.items.is_empty());
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp("1970-01-01T00:01:00Z"), Some(60));
+        assert_eq!(timestamp("2021-03-04T12:34:56.1234567Z"), Some(1614861296));
+        assert_eq!(timestamp("2021-03-04"), None);
+    }
+
+    #[test]
+    fn sorts() {
+        assert_eq!(sort_args("addedAt:desc,titleSort").unwrap(), "&SortBy=DateCreated,SortName&SortOrder=Descending,Ascending");
+        assert!(sort_args("bogus").is_err());
+    }
+}
@@ -6,59 +6,1011 @@ extern crate libc;
 extern crate quick_xml;
 extern crate reqwest;
 extern crate serde;
+#[macro_use] extern crate serde_json;
+extern crate tar;
 extern crate time;
+extern crate toml;
 #[macro_use] extern crate log;
 
+#[cfg(feature = "dbus")] extern crate dbus;
+
 mod api;
+mod backend;
+mod cache;
+mod cmd;
+mod config;
+mod control;
+mod deferred;
+#[cfg(feature = "dbus")] mod dbus_service;
+mod discovery;
 mod fs;
+mod jellyfin;
+mod nfo;
+mod notifications;
+mod pool;
+mod sanitize;
+mod scheduler;
+mod servers;
+mod stream;
+mod template;
+mod times;
 
 use std::env;
 use std::ffi::OsStr;
-use clap::{App, Arg, crate_version};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand, crate_version};
+
+use backend::{BackendKind, MediaBackend};
+use config::{Config, Profile};
+use template::Template;
+
+/// Selects the server and library to talk to.
+fn connection_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("profile").short("p").long("profile").help(
+            "Named profile from the config file.",
+        ).takes_value(true),
+        Arg::with_name("token").short("t").long("token").help(
+            "Plex API token. Visible to other users in ps; prefer --token-file or PLEX_TOKEN.",
+        ).takes_value(true),
+        Arg::with_name("token-file").long("token-file").help(
+            "File to read the Plex API token from.",
+        ).takes_value(true).conflicts_with("token"),
+        Arg::with_name("host").short("h").long("host").help(
+            "Plex server endpoint, as host:port or a URL like https://plex.example.com:32400. (default: found on the local network or through plex.tv)",
+        ).takes_value(true),
+        Arg::with_name("backend").long("backend").help(
+            "Kind of media server. Jellyfin and Emby servers need --host. (default: plex)",
+        ).takes_value(true).possible_values(backend::BackendKind::NAMES),
+        Arg::with_name("insecure").long("insecure").help(
+            "Accepts TLS certificates that don't verify, such as self-signed ones.",
+        ),
+        proxy_arg(),
+        Arg::with_name("section").short("s").long("section").help(
+            "Plex library section, by ID or title. May be given more than once to mount each as a directory.",
+        ).takes_value(true).multiple(true).number_of_values(1),
+        Arg::with_name("all-sections").long("all-sections").help(
+            "Mounts every section plexfs supports, each as a directory.",
+        ).conflicts_with("section"),
+        Arg::with_name("read-retries").long("read-retries").help(
+            "Times a failed or interrupted file read is resumed before giving up. (default: 3)",
+        ).takes_value(true),
+        Arg::with_name("max-connections").long("max-connections").help(
+            "Requests to the server allowed in flight at once. (default: 8)",
+        ).takes_value(true),
+        Arg::with_name("connect-timeout").long("connect-timeout").help(
+            "Seconds allowed to connect to the server. (default: 10)",
+        ).takes_value(true),
+        Arg::with_name("read-timeout").long("read-timeout").help(
+            "Seconds allowed for the server to start answering, and between reads of an answer; 0 waits indefinitely. (default: 30)",
+        ).takes_value(true),
+        Arg::with_name("retries").long("retries").help(
+            "Times a request is repeated after a server error or a failed connection. (default: 3)",
+        ).takes_value(true),
+        Arg::with_name("retry-backoff").long("retry-backoff").help(
+            "Milliseconds to wait before the first retry, doubled for each after it. (default: 500)",
+        ).takes_value(true),
+        Arg::with_name("retry-jitter").long("retry-jitter").help(
+            "Most milliseconds added to each retry's wait at random. (default: 250)",
+        ).takes_value(true),
+    ]
+}
+
+fn proxy_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("proxy").long("proxy").help(
+        "Proxy to reach the server and plex.tv through, e.g. socks5h://jumphost:1080 or http://proxy:3128. (default: from HTTP_PROXY, HTTPS_PROXY or ALL_PROXY)",
+    ).takes_value(true)
+}
+
+fn mount_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("min-resolution").long("min-resolution").help(
+            "Only expose media versions at least this tall, in pixels. (integer)",
+        ).takes_value(true),
+        Arg::with_name("max-bitrate").long("max-bitrate").help(
+            "Only expose media versions at most this bitrate, in kbps. (integer)",
+        ).takes_value(true),
+        Arg::with_name("layout").long("layout").help(
+            "Directory structure: Plex's metadata hierarchy, the folders the files are in on the server, Plex's folder view, or a path template such as \"{artist}/{album} ({year})/{track:02} - {title}.{ext}\". (default: metadata)",
+        ).takes_value(true),
+        Arg::with_name("root").long("root").help(
+            "Item to show at the root instead of the whole section, by path, or by rating key if nothing is at that path or it starts with key:. (e.g. \"Artist/Album\")",
+        ).takes_value(true),
+        Arg::with_name("playlist").long("playlist").help(
+            "Playlist to show at the root in playlist order, by rating key or title.",
+        ).takes_value(true).conflicts_with("root"),
+        Arg::with_name("view").long("view").help(
+            "Adds a virtual top-level directory. May be given more than once.",
+        ).takes_value(true).multiple(true).number_of_values(1).possible_values(fs::View::NAMES),
+        Arg::with_name("filter").long("filter").help(
+            "Only shows the items of a section matching a Plex filter, e.g. artist.title=Beatles, unwatched=1 or year>>=2000. May be given more than once.",
+        ).takes_value(true).multiple(true).number_of_values(1),
+        Arg::with_name("sort").long("sort").help(
+            "Orders section listings and directories with a Plex sort, e.g. addedAt:desc or year,titleSort. (default: by name)",
+        ).takes_value(true),
+        Arg::with_name("time-fallback").long("time-fallback").help(
+            "Timestamps to use, in order, when Plex leaves one unset. (default: updated,added,mount)",
+        ).takes_value(true).use_delimiter(true).possible_values(times::TimeSource::NAMES),
+        Arg::with_name("atime").long("atime").help(
+            "How atime is reported: Plex's last viewed time, mtime, or also bumped by reads through the mount. (default: plex)",
+        ).takes_value(true).possible_values(times::AtimeMode::NAMES),
+        Arg::with_name("sanitize").long("sanitize").help(
+            "File name rules: posix, or windows to also avoid names invalid over SMB. (default: posix)",
+        ).takes_value(true).possible_values(sanitize::Charset::NAMES),
+        Arg::with_name("replacement").long("replacement").help(
+            "Character to replace those invalid in file names with. (default: _)",
+        ).takes_value(true),
+        Arg::with_name("nfc").long("nfc").help(
+            "Normalizes names to Unicode NFC, as most Linux and Windows software expects.",
+        ),
+        Arg::with_name("strip-control").long("strip-control").help(
+            "Removes control characters from names.",
+        ),
+        Arg::with_name("naming").long("naming").help(
+            "Track file names: the file's name on the server, or \"NN - Title\" from Plex's metadata. (default: file)",
+        ).takes_value(true).possible_values(fs::Naming::NAMES),
+        Arg::with_name("track-numbers").long("track-numbers").help(
+            "Names track files \"NN - Title\" from Plex's metadata, so albums list in order. Same as --naming title.",
+        ).conflicts_with("naming"),
+        Arg::with_name("absolute-episodes").long("absolute-episodes").help(
+            "Names episodes \"Show - 0134 - Title\" from their absolute number, where Plex has one, instead of SxxEyy.",
+        ),
+        Arg::with_name("absolute-show").long("absolute-show").help(
+            "Names the episodes of this show, by title or rating key, as --absolute-episodes does. May be given more than once.",
+        ).takes_value(true).multiple(true).number_of_values(1),
+        Arg::with_name("disc-dirs").long("disc-dirs").help(
+            "Lists the tracks of albums with several discs under Disc 1/, Disc 2/ and so on.",
+        ),
+        Arg::with_name("bucket-alpha").long("bucket-alpha").help(
+            "Lists each section's items under a directory for their first letter, as A/, B/ and #/.",
+        ),
+        Arg::with_name("album-order").long("album-order").help(
+            "Order of albums under an artist: by title, or by release date. (default: title)",
+        ).takes_value(true).possible_values(fs::AlbumOrder::NAMES),
+        Arg::with_name("year-prefix").long("year-prefix").help(
+            "Starts each album's name with its release year, as in \"1973 - The Dark Side of the Moon\".",
+        ),
+        Arg::with_name("max-name-length").long("max-name-length").help(
+            "Longer names are shortened, with a hash to keep them unique. (default: 255)",
+        ).takes_value(true),
+        Arg::with_name("metadata-dir").long("metadata-dir").help(
+            "Exposes each item's full metadata as .metadata/<rating key>.json.",
+        ),
+        Arg::with_name("no-covers").long("no-covers").help(
+            "Leaves out the cover.jpg and folder.jpg files showing the artwork of albums, shows and seasons.",
+        ),
+        Arg::with_name("artwork-size").long("artwork-size").help(
+            "Scales cover.jpg and folder.jpg down to fit WIDTHxHEIGHT, or a square of the size given, on the server. (default: full size)",
+        ).takes_value(true),
+        Arg::with_name("lyrics").long("lyrics").help(
+            "Exposes tracks' lyrics as .lrc files beside them. Costs a metadata request per track listed.",
+        ),
+        Arg::with_name("subtitles").long("subtitles").help(
+            "Exposes videos' subtitle files as <name>.<language>.srt and the like beside them. Costs a metadata request per video listed.",
+        ),
+        Arg::with_name("subtitle-language").long("subtitle-language").help(
+            "Language .plexfs/subtitles/ searches for subtitles in. (default: en)",
+        ).takes_value(true),
+        Arg::with_name("nfo").long("nfo").help(
+            "Exposes Kodi NFO files describing movies, shows and episodes.",
+        ),
+        Arg::with_name("cache-dir").long("cache-dir").help(
+            "Keeps file data read through the mount in this directory, for later reads and mounts.",
+        ).takes_value(true),
+        Arg::with_name("cache-size").long("cache-size").help(
+            "MiB the cache directory may use before the least recently used data is removed. (default: 1024)",
+        ).takes_value(true),
+        Arg::with_name("readahead").long("readahead").help(
+            "KiB of an open file fetched ahead of sequential reads. (default: 2048)",
+        ).takes_value(true),
+        Arg::with_name("dir-ttl").long("dir-ttl").help(
+            "Seconds a directory listing is used before it's fetched again in the background when next used. 0 keeps listings until flushed. (default: until flushed)",
+        ).takes_value(true),
+        Arg::with_name("page-size").long("page-size").help(
+            "Items requested per page when listing a directory. (default: 500)",
+        ).takes_value(true),
+        Arg::with_name("metadata-concurrency").long("metadata-concurrency").help(
+            "Parallel metadata requests when a listing lacks file details. (default: 8)",
+        ).takes_value(true),
+    ]
+}
 
 fn app<'a, 'b>() -> App<'a, 'b> {
     App::new(format!("plexfs {}", crate_version!()))
         .about("Mount a Plex server as a local filesystem.")
+        .setting(AppSettings::ArgRequiredElseHelp)
         .arg(Arg::with_name("version").short("v").long("version").help(
             "Prints version info.",
         ))
-        .arg(Arg::with_name("token").short("t").long("token").help(
-            "Plex API token.",
-        ).required(true).takes_value(true))
-        .arg(Arg::with_name("host").short("h").long("host").help(
-            "Plex server endpoint.",
-        ).takes_value(true))
-        .arg(Arg::with_name("section").short("s").long("section").help(
-            "Plex library section. (integer)",
-        ).required(true).takes_value(true))
-        .arg(Arg::with_name("mountpoint").index(1).required(true))
+        .arg(Arg::with_name("config").short("c").long("config").help(
+            "Config file. (default: ~/.config/plexfs/config.toml)",
+        ).takes_value(true).global(true))
+        .subcommand(SubCommand::with_name("mount")
+            .about("Mounts a library section.")
+            .args(&connection_args())
+            .args(&mount_args())
+            .arg(Arg::with_name("dbus").long("dbus").help(
+                "Serves the org.plexfs control interface on the session bus.",
+            ))
+            .arg(Arg::with_name("live").long("live").help(
+                "Follows the server's notifications, refreshing listings as items are added, changed or deleted.",
+            ))
+            .arg(Arg::with_name("prefetch").long("prefetch").help(
+                "Downloads the mounted sections into the cache directory in the background, while nothing else is being read.",
+            ))
+            .arg(Arg::with_name("prefetch-rate").long("prefetch-rate").help(
+                "KiB per second --prefetch downloads at most. (default: no limit)",
+            ).takes_value(true))
+            .arg(Arg::with_name("all-servers").long("all-servers").help(
+                "Mounts every server on the plex.tv account, including shared ones, each as a directory.",
+            ).conflicts_with_all(&["host", "section", "all-sections"]))
+            .arg(Arg::with_name("mountpoint").index(1).required(true).multiple(true).max_values(2)
+                 .value_name("[PROFILE] MOUNTPOINT").help(
+                "Where to mount, optionally preceded by the profile to use.",
+            )))
+        .subcommand(SubCommand::with_name("login")
+            .about("Signs in through plex.tv/link and saves the token to the config file.")
+            .arg(Arg::with_name("profile").short("p").long("profile").help(
+                "Profile to save the token in. (default: the default profile, or \"default\")",
+            ).takes_value(true))
+            .arg(Arg::with_name("host").short("h").long("host").help(
+                "Plex server endpoint to save alongside the token.",
+            ).takes_value(true))
+            .arg(proxy_arg()))
+        .subcommand(SubCommand::with_name("sections")
+            .about("Lists the library sections on the server, to find what to mount.")
+            .args(&connection_args()))
+        .subcommand(SubCommand::with_name("tree")
+            .about("Prints the directory tree a mount would show, without mounting.")
+            .args(&connection_args())
+            .args(&mount_args())
+            .arg(Arg::with_name("depth").short("L").long("depth").help(
+                "Descends at most this many levels. (integer)",
+            ).takes_value(true))
+            .arg(Arg::with_name("all").short("a").long("all").help(
+                "Includes hidden entries like .plexfs.",
+            ))
+            .arg(Arg::with_name("path").index(1).help(
+                "Directory inside the mount to start from.",
+            )))
+        .subcommand(SubCommand::with_name("ls")
+            .about("Lists a directory as a mount would show it, without mounting.")
+            .args(&connection_args())
+            .args(&mount_args())
+            .arg(Arg::with_name("long").short("l").long("long").help(
+                "Shows type, size and modification time.",
+            ))
+            .arg(Arg::with_name("all").short("a").long("all").help(
+                "Includes hidden entries like .plexfs.",
+            ))
+            .arg(Arg::with_name("path").index(1).help(
+                "Path inside the mount.",
+            )))
+        .subcommand(SubCommand::with_name("cat")
+            .about("Writes a file from the mount to stdout, without mounting.")
+            .args(&connection_args())
+            .args(&mount_args())
+            .arg(Arg::with_name("range").short("r").long("range").help(
+                "Inclusive byte range to write, START-END or START-.",
+            ).takes_value(true))
+            .arg(Arg::with_name("path").index(1).required(true).help(
+                "Path inside the mount.",
+            )))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Compares a local copy against the server, printing what differs.")
+            .args(&connection_args())
+            .args(&mount_args())
+            .arg(Arg::with_name("spot-check").long("spot-check").help(
+                "Also compares the start, middle and end of each file's content.",
+            ))
+            .arg(Arg::with_name("local").index(1).required(true).help(
+                "Local copy of the path.",
+            ))
+            .arg(Arg::with_name("path").index(2).help(
+                "Path inside the mount the copy was made from. (default: /)",
+            )))
+        .subcommand(SubCommand::with_name("watch")
+            .about("Moves files dropped into a folder into the library and has the server scan them.")
+            .args(&connection_args())
+            .arg(Arg::with_name("library-path").long("library-path").help(
+                "Library folder to move files into, as seen from this machine.",
+            ).takes_value(true).required(true))
+            .arg(Arg::with_name("server-path").long("server-path").help(
+                "The library folder as the server sees it, to scan only what was added.",
+            ).takes_value(true))
+            .arg(Arg::with_name("interval").long("interval").help(
+                "Seconds between checks of the folder. (default: 10)",
+            ).takes_value(true))
+            .arg(Arg::with_name("incoming").index(1).required(true).help(
+                "Folder to watch.",
+            )))
+        .subcommand(SubCommand::with_name("serve-http")
+            .about("Serves the tree a mount would show over HTTP, with directory listings and range requests.")
+            .args(&connection_args())
+            .args(&mount_args())
+            .arg(Arg::with_name("listen").short("l").long("listen").help(
+                "Address to listen on. (default: 127.0.0.1:8080)",
+            ).takes_value(true)))
+        .subcommand(SubCommand::with_name("browse")
+            .about("Browses the tree a mount would show interactively.")
+            .args(&connection_args())
+            .args(&mount_args())
+            .arg(Arg::with_name("mountpoint").long("mountpoint").help(
+                "Where the same library is mounted, for opening files.",
+            ).takes_value(true)))
+        .subcommand(SubCommand::with_name("export")
+            .about("Writes a directory or file from the mount to a tar or zip archive, without mounting.")
+            .args(&connection_args())
+            .args(&mount_args())
+            .arg(Arg::with_name("output").short("o").long("output").help(
+                "Archive to write, or - for stdout. (default: -)",
+            ).takes_value(true))
+            .arg(Arg::with_name("format").long("format").help(
+                "Archive format. Zip needs a file to write to. (default: zip if the output ends in .zip, otherwise tar)",
+            ).takes_value(true).possible_values(cmd::export::Format::NAMES))
+            .arg(Arg::with_name("path").index(1).required(true).help(
+                "Path inside the mount.",
+            )))
+        .subcommand(SubCommand::with_name("cache")
+            .about("Manages the chunk cache given with --cache-dir.")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("seed")
+                .about("Fills the cache from a local copy of the library, so those files are never downloaded.")
+                .args(&connection_args())
+                .args(&mount_args())
+                .arg(Arg::with_name("local").index(1).required(true).help(
+                    "Local copy of the path.",
+                ))
+                .arg(Arg::with_name("path").index(2).help(
+                    "Path inside the mount the copy is of. (default: /)",
+                ))))
 }
 
-fn main() {
-    env_logger::init();
+fn value_opt<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    if matches.is_present(name) {
+        Some(value_t_or_exit!(matches, name, T))
+    } else {
+        None
+    }
+}
 
-    let matches = app().get_matches();
-    if matches.is_present("version") {
-        println!("plexfs {}", crate_version!());
-        return;
+/// A width and height, `300x200`, or one number for both.
+fn parse_size(size: &str) -> Result<(u32, u32)> {
+    let parsed = match size.find('x') {
+        Some(i) => (size[..i].parse(), size[i + 1..].parse()),
+        None => (size.parse(), size.parse())
+    };
+    match parsed {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(anyhow!("invalid size \"{}\"; expected WIDTHxHEIGHT, e.g. 300x300", size))
+    }
+}
+
+/// Values of a multi-valued flag, or `default` if it wasn't given.
+fn values_or(matches: &ArgMatches, name: &str, default: Option<Vec<String>>) -> Option<Vec<String>> {
+    matches.values_of(name)
+        .map(|vs| vs.map(String::from).collect())
+        .or(default)
+}
+
+fn parse_names<T, F>(names: &[String], what: &str, from_name: F) -> Result<Vec<T>>
+    where F: Fn(&str) -> Option<T>
+{
+    names.iter()
+        .map(|name| from_name(name).ok_or_else(|| anyhow!("unknown {} \"{}\"", what, name)))
+        .collect()
+}
+
+/// Server endpoint, token and sections, from flags or the selected profile.
+struct Connection {
+    base_url: String,
+    token: String,
+    /// Keys or titles.
+    sections: Vec<String>,
+    all_sections: bool,
+    api: api::ApiOptions,
+    backend: BackendKind,
+}
+
+impl Connection {
+    fn api(&self) -> Result<api::PlexAPI> {
+        if self.backend != BackendKind::Plex {
+            return Err(anyhow!("this command only works with Plex servers"));
+        }
+        api::PlexAPI::new(self.base_url.clone(), self.token.clone(), self.api.clone())
+    }
+
+    /// Connects to the server with whichever backend it was given as.
+    fn backend(&self) -> Result<Arc<dyn MediaBackend>> {
+        match self.backend {
+            BackendKind::Plex => {
+                let mut api = self.api()?;
+                match api.identify() {
+                    Ok(info) => info!("connected to Plex Media Server {}", info.version),
+                    Err(e) => warn!("couldn't identify the server, assuming it supports everything: {}", e)
+                }
+                Ok(Arc::new(api))
+            }
+            BackendKind::Jellyfin => {
+                let api = jellyfin::JellyfinAPI::new(self.base_url.clone(), self.token.clone(), self.api.clone())?;
+                Ok(Arc::new(api))
+            }
+        }
     }
+}
 
-    let host = matches.value_of("host")
-        .unwrap_or("192.168.1.100:32400")
-        .parse()
-        .unwrap();
-    let token = matches.value_of("token")
-        .unwrap()
-        .into();
-    let section = value_t_or_exit!(matches, "section", u64);
-    let media_kind = api::MediaKind::Music;
-    let mountpoint = matches.value_of("mountpoint").unwrap();
+fn read_token_file(path: &str) -> Result<String> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading token file {}", path))?;
+    Ok(text.trim().to_string())
+}
 
-    let fs = fs::PlexFS::new(host, token, section, media_kind);
+/// The token from, in order: `--token`, `--token-file`, `PLEX_TOKEN`, the
+/// profile's `token-file`, the profile's `token`.
+fn token(matches: &ArgMatches, profile: &Profile) -> Result<String> {
+    if let Some(token) = matches.value_of("token") {
+        return Ok(token.to_string());
+    }
+    if let Some(path) = matches.value_of("token-file") {
+        return read_token_file(path);
+    }
+    if let Some(token) = env::var("PLEX_TOKEN").ok().filter(|t| !t.is_empty()) {
+        return Ok(token);
+    }
+    if let Some(path) = &profile.token_file {
+        return read_token_file(path);
+    }
+    profile.token.clone()
+        .ok_or_else(|| anyhow!("no token given; pass --token-file, set PLEX_TOKEN or set it in a profile"))
+}
 
+fn api_options(matches: &ArgMatches, profile: &Profile) -> Result<api::ApiOptions> {
+    let filters = values_or(matches, "filter", profile.filters.clone()).unwrap_or_default();
+    let filters = filters.iter().map(|filter| api::Filter::parse(filter)).collect::<Result<_>>()?;
+    let sort = matches.value_of("sort").map(String::from).or(profile.sort.clone());
+    if let Some(sort) = &sort {
+        api::check_sort(sort)?;
+    }
+    Ok(api::ApiOptions {
+        read_retries: value_opt(matches, "read-retries")
+            .or(profile.read_retries)
+            .unwrap_or(api::DEFAULT_READ_RETRIES),
+        max_connections: value_opt(matches, "max-connections")
+            .or(profile.max_connections)
+            .unwrap_or(api::DEFAULT_MAX_CONNECTIONS),
+        insecure: matches.is_present("insecure") || profile.insecure.unwrap_or(false),
+        connect_timeout: value_opt(matches, "connect-timeout")
+            .or(profile.connect_timeout)
+            .map(Duration::from_secs)
+            .unwrap_or(api::DEFAULT_CONNECT_TIMEOUT),
+        read_timeout: match value_opt(matches, "read-timeout").or(profile.read_timeout) {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => Some(api::DEFAULT_READ_TIMEOUT)
+        },
+        proxy: matches.value_of("proxy").map(String::from).or(profile.proxy.clone()),
+        retries: value_opt(matches, "retries")
+            .or(profile.retries)
+            .unwrap_or(api::DEFAULT_RETRIES),
+        retry_backoff: value_opt(matches, "retry-backoff")
+            .or(profile.retry_backoff)
+            .map(Duration::from_millis)
+            .unwrap_or(api::DEFAULT_RETRY_BACKOFF),
+        retry_jitter: value_opt(matches, "retry-jitter")
+            .or(profile.retry_jitter)
+            .map(Duration::from_millis)
+            .unwrap_or(api::DEFAULT_RETRY_JITTER),
+        filters: filters,
+        sort: sort,
+    })
+}
+
+fn connection(matches: &ArgMatches, profile: &Profile) -> Result<Connection> {
+    let token = token(matches, profile)?;
+    let api = api_options(matches, profile)?;
+    let backend = match matches.value_of("backend").map(String::from).or(profile.backend.clone()) {
+        Some(name) => BackendKind::from_name(&name)
+            .ok_or_else(|| anyhow!("unknown backend \"{}\"", name))?,
+        None => Default::default()
+    };
+    let base_url = match matches.value_of("host").map(String::from).or(profile.host.clone()) {
+        Some(host) => api::base_url(&host)?,
+        // Discovery only finds Plex servers.
+        None if backend != BackendKind::Plex => {
+            return Err(anyhow!("no host given; pass --host or set it in a profile"));
+        }
+        None => {
+            let url = discovery::discover(&token, &api)?;
+            info!("using server {}", url);
+            url
+        }
+    };
+    let sections = match matches.values_of("section") {
+        Some(values) => values.map(String::from).collect(),
+        None => profile.sections.clone()
+            .or(profile.section.clone().map(|section| vec![section]))
+            .unwrap_or_default()
+            .iter()
+            .map(|section| section.to_spec())
+            .collect()
+    };
+    // Sections given on the command line win over a profile's all-sections.
+    let all_sections = match (matches.is_present("all-sections"), matches.is_present("section")) {
+        (true, _) => true,
+        (false, true) => false,
+        (false, false) => profile.all_sections.unwrap_or(false)
+    };
+
+    Ok(Connection {
+        base_url: base_url,
+        token: token,
+        sections: sections,
+        all_sections: all_sections,
+        api: api,
+        backend: backend,
+    })
+}
+
+fn mount_options(matches: &ArgMatches, profile: &Profile) -> Result<fs::Options> {
+    let views = values_or(matches, "view", profile.views.clone()).unwrap_or_default();
+    let times = match values_or(matches, "time-fallback", profile.time_fallback.clone()) {
+        Some(names) => times::TimePolicy {
+            fallback: parse_names(&names, "time source", times::TimeSource::from_name)?,
+            ..Default::default()
+        },
+        None => Default::default()
+    };
+    let atime = match matches.value_of("atime").map(String::from).or(profile.atime.clone()) {
+        Some(name) => times::AtimeMode::from_name(&name)
+            .ok_or_else(|| anyhow!("unknown atime mode \"{}\"", name))?,
+        None => Default::default()
+    };
+    let charset = match matches.value_of("sanitize").map(String::from).or(profile.sanitize.clone()) {
+        Some(name) => sanitize::Charset::from_name(&name)
+            .ok_or_else(|| anyhow!("unknown sanitize mode \"{}\"", name))?,
+        None => Default::default()
+    };
+    let replacement = match matches.value_of("replacement").map(String::from).or(profile.replacement.clone()) {
+        Some(replacement) => {
+            let mut chars = replacement.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(anyhow!("replacement \"{}\" isn't a single character", replacement))
+            }
+        }
+        None => sanitize::DEFAULT_REPLACEMENT
+    };
+    let names = sanitize::NamePolicy {
+        charset: charset,
+        max_len: value_opt(matches, "max-name-length")
+            .or(profile.max_name_length)
+            .unwrap_or(sanitize::DEFAULT_MAX_LEN),
+        replacement: replacement,
+        normalize: matches.is_present("nfc") || profile.nfc.unwrap_or(false),
+        strip_control: matches.is_present("strip-control") || profile.strip_control.unwrap_or(false),
+    };
+    names.check()?;
+    let album_order = match matches.value_of("album-order").map(String::from).or(profile.album_order.clone()) {
+        Some(name) => fs::AlbumOrder::from_name(&name)
+            .ok_or_else(|| anyhow!("unknown album order \"{}\"", name))?,
+        None => Default::default()
+    };
+    let layout = match matches.value_of("layout").map(String::from).or(profile.layout.clone()) {
+        Some(template) if Template::is_template(&template) => fs::Layout::Template(Template::parse(&template)?),
+        Some(name) => fs::Layout::from_name(&name)
+            .ok_or_else(|| anyhow!("unknown layout \"{}\"; expected one of {} or a template", name, fs::Layout::NAMES.join(", ")))?,
+        None => Default::default()
+    };
+    let naming = match matches.value_of("naming").map(String::from).or(profile.naming.clone()) {
+        Some(name) => fs::Naming::from_name(&name)
+            .ok_or_else(|| anyhow!("unknown naming mode \"{}\"", name))?,
+        None if matches.is_present("track-numbers") || profile.track_numbers.unwrap_or(false) => fs::Naming::Title,
+        None => Default::default()
+    };
+
+    let policy = |policy: &config::Policy| -> Result<fs::Policy> {
+        let album_order = match &policy.album_order {
+            Some(name) => Some(fs::AlbumOrder::from_name(name)
+                               .ok_or_else(|| anyhow!("unknown album order \"{}\"", name))?),
+            None => None
+        };
+        Ok(fs::Policy {
+            cache: policy.cache,
+            dir_ttl: policy.dir_ttl.map(|secs| Some(secs).filter(|&secs| secs > 0).map(Duration::from_secs)),
+            album_order: album_order,
+            year_prefix: policy.year_prefix,
+        })
+    };
+    let section_policies = profile.section_policy.iter().flatten()
+        .map(|(section, p)| Ok((section.clone(), policy(p)?)))
+        .collect::<Result<_>>()?;
+    let view_policies = profile.view_policy.iter().flatten()
+        .map(|(name, p)| match fs::View::from_name(name) {
+            Some(view) => Ok((view, policy(p)?)),
+            None => Err(anyhow!("unknown view \"{}\" in view-policy", name))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(fs::Options {
+        filter: fs::MediaFilter {
+            min_resolution: value_opt(matches, "min-resolution").or(profile.min_resolution),
+            max_bitrate: value_opt(matches, "max-bitrate").or(profile.max_bitrate),
+        },
+        layout: layout,
+        views: parse_names(&views, "view", fs::View::from_name)?,
+        times: times,
+        atime: atime,
+        metadata_concurrency: value_opt(matches, "metadata-concurrency")
+            .or(profile.metadata_concurrency)
+            .unwrap_or(fs::DEFAULT_METADATA_CONCURRENCY),
+        page_size: value_opt(matches, "page-size")
+            .or(profile.page_size)
+            .unwrap_or(fs::DEFAULT_PAGE_SIZE)
+            .max(1),
+        dir_ttl: value_opt(matches, "dir-ttl")
+            .or(profile.dir_ttl)
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+        prefetch_rate: value_opt::<u64>(matches, "prefetch-rate")
+            .or(profile.prefetch_rate)
+            .map(|kib| kib * 1024),
+        section_policies: section_policies,
+        view_policies: view_policies,
+        readahead: value_opt::<u64>(matches, "readahead")
+            .or(profile.readahead)
+            .map(|kib| kib * 1024)
+            .unwrap_or(fs::DEFAULT_READAHEAD),
+        metadata_dir: matches.is_present("metadata-dir") || profile.metadata_dir.unwrap_or(false),
+        covers: !matches.is_present("no-covers") && profile.covers.unwrap_or(true),
+        artwork_size: match matches.value_of("artwork-size").map(String::from).or(profile.artwork_size.clone()) {
+            Some(size) => Some(parse_size(&size)?),
+            None => None
+        },
+        lyrics: matches.is_present("lyrics") || profile.lyrics.unwrap_or(false),
+        subtitles: matches.is_present("subtitles") || profile.subtitles.unwrap_or(false),
+        subtitle_language: matches.value_of("subtitle-language").map(String::from)
+            .or(profile.subtitle_language.clone())
+            .unwrap_or_else(|| fs::DEFAULT_SUBTITLE_LANGUAGE.to_string()),
+        nfo: matches.is_present("nfo") || profile.nfo.unwrap_or(false),
+        disc_dirs: matches.is_present("disc-dirs") || profile.disc_dirs.unwrap_or(false),
+        bucket_alpha: matches.is_present("bucket-alpha") || profile.bucket_alpha.unwrap_or(false),
+        server_order: matches.is_present("sort") || profile.sort.is_some(),
+        names: names,
+        naming: naming,
+        absolute_episodes: matches.is_present("absolute-episodes") || profile.absolute_episodes.unwrap_or(false),
+        absolute_shows: values_or(matches, "absolute-show", profile.absolute_shows.clone()).unwrap_or_default(),
+        album_order: album_order,
+        year_prefix: matches.is_present("year-prefix") || profile.year_prefix.unwrap_or(false),
+    })
+}
+
+/// The sections to mount, with their kinds and titles. With
+/// `--all-sections`, kinds plexfs can't mount are left out; a section asked
+/// for by name is mounted as music if its kind is unknown.
+fn mounted_sections(api: &dyn MediaBackend, conn: &Connection) -> Result<Vec<fs::Section>> {
+    if conn.sections.is_empty() && !conn.all_sections {
+        return Ok(vec![]);
+    }
+    let available = match api.sections() {
+        Ok(sections) => sections,
+        Err(e) if !conn.all_sections => {
+            // Sections given by key can still be mounted blind.
+            warn!("couldn't list sections, assuming they're music: {}", e);
+            return conn.sections.iter().map(|spec| {
+                let key = spec.parse()
+                    .map_err(|_| anyhow!("can't look up section \"{}\" by title: {}", spec, e))?;
+                Ok(fs::Section { key: key, kind: api::MediaKind::Music, title: spec.clone() })
+            }).collect();
+        }
+        Err(e) => return Err(e)
+    };
+
+    if conn.all_sections {
+        return Ok(available.into_iter().filter_map(|section| {
+            let kind = api::MediaKind::from_section_type(&section.kind)?;
+            Some(fs::Section { key: section.key, kind: kind, title: section.title })
+        }).collect());
+    }
+
+    conn.sections.iter().map(|spec| {
+        let section = api::find_section(&available, spec)?;
+        let kind = match api::MediaKind::from_section_type(&section.kind) {
+            Some(kind) => kind,
+            None => {
+                warn!("section {} is of unsupported type \"{}\", mounting it as music", section.key, section.kind);
+                api::MediaKind::Music
+            }
+        };
+        Ok(fs::Section { key: section.key, kind: kind, title: section.title.clone() })
+    }).collect()
+}
+
+/// The chunk cache from `--cache-dir`, if given.
+fn chunk_cache(matches: &ArgMatches, profile: &Profile) -> Result<Option<Arc<cache::ChunkCache>>> {
+    let dir = match matches.value_of("cache-dir").map(String::from).or(profile.cache_dir.clone()) {
+        Some(dir) => dir,
+        None => return Ok(None)
+    };
+    let size = value_opt::<u64>(matches, "cache-size")
+        .or(profile.cache_size)
+        .map(|mib| mib * 1024 * 1024)
+        .unwrap_or(cache::DEFAULT_CACHE_SIZE);
+    Ok(Some(Arc::new(cache::ChunkCache::open(Path::new(&dir), size)?)))
+}
+
+/// The profile given with `--profile`, or before the mountpoint as in
+/// `plexfs mount home /mnt/plex`.
+fn profile_name<'a>(matches: &'a ArgMatches) -> Option<&'a str> {
+    let positional = match matches.values_of("mountpoint") {
+        Some(mut values) if values.len() == 2 => values.next(),
+        _ => None
+    };
+    matches.value_of("profile").or(positional)
+}
+
+/// Builds the filesystem described by the connection and mount flags.
+fn filesystem(config: &Config, matches: &ArgMatches) -> Result<fs::PlexFS> {
+    let profile = config.profile(profile_name(matches))?;
+    let conn = connection(matches, &profile)?;
+    // Either on the command line wins over the other in the profile.
+    let (root, playlist) = match (matches.value_of("root"), matches.value_of("playlist")) {
+        (Some(root), _) => (Some(root.to_string()), None),
+        (None, Some(playlist)) => (None, Some(playlist.to_string())),
+        (None, None) => match (&profile.root, &profile.playlist) {
+            (Some(_), Some(_)) => return Err(anyhow!("a profile can't set both root and playlist")),
+            (root, playlist) => (root.clone(), playlist.clone())
+        }
+    };
+    let options = mount_options(matches, &profile)?;
+
+    let api = conn.backend()?;
+    let sections = mounted_sections(&*api, &conn)?;
+    // A playlist can span sections, so doesn't need one.
+    if sections.is_empty() && playlist.is_none() {
+        return Err(anyhow!("no section given; pass --section or set it in a profile"));
+    }
+
+    let mut fs = fs::PlexFS::new(api, sections, options);
+    if let Some(cache) = chunk_cache(matches, &profile)? {
+        fs.set_cache(cache);
+    }
+    if let Some(root) = root {
+        fs.set_root(&root)?;
+    }
+    if let Some(playlist) = playlist {
+        fs.set_playlist(&playlist)?;
+    }
+    Ok(fs)
+}
+
+/// Builds a filesystem with a directory for each server on the account,
+/// each showing every section plexfs supports.
+fn servers_filesystem(config: &Config, matches: &ArgMatches) -> Result<servers::ServersFS> {
+    let profile = config.profile(profile_name(matches))?;
+    if matches.is_present("root") || matches.is_present("playlist") {
+        return Err(anyhow!("--root and --playlist can't be used with --all-servers"));
+    }
+    let account_token = token(matches, &profile)?;
+    let api_options = api_options(matches, &profile)?;
+    let options = mount_options(matches, &profile)?;
+    let cache = chunk_cache(matches, &profile)?;
+    let live = matches.is_present("live") || profile.live.unwrap_or(false);
+    let prefetch = matches.is_present("prefetch") || profile.prefetch.unwrap_or(false);
+
+    let mut names = vec![];
+    let mut filesystems = vec![];
+    for server in discovery::servers(&account_token, &api_options)? {
+        let base_url = match discovery::first_reachable(&server.urls, &api_options) {
+            Some(url) => url,
+            None => {
+                warn!("server \"{}\" isn't reachable, leaving it out", server.name);
+                continue;
+            }
+        };
+        let conn = Connection {
+            base_url: base_url,
+            token: server.token,
+            sections: vec![],
+            all_sections: true,
+            api: api_options.clone(),
+            backend: BackendKind::Plex,
+        };
+        let mut api = conn.api()?;
+        if let Err(e) = api.identify() {
+            warn!("couldn't identify server \"{}\", assuming it supports everything: {}", server.name, e);
+        }
+        let api = Arc::new(api);
+        let sections = match mounted_sections(&*api, &conn) {
+            Ok(sections) if !sections.is_empty() => sections,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("couldn't list the sections of \"{}\", leaving it out: {}", server.name, e);
+                continue;
+            }
+        };
+        info!("mounting server \"{}\" at {}", server.name, conn.base_url);
+        names.push(server.name);
+        let mut fs = fs::PlexFS::new(api, sections, options.clone());
+        if let Some(cache) = &cache {
+            fs.set_cache(cache.clone());
+        }
+        if live {
+            fs.watch_notifications();
+        }
+        if prefetch {
+            fs.prefetch();
+        }
+        filesystems.push(fs);
+    }
+    if filesystems.is_empty() {
+        return Err(anyhow!("no reachable server with sections plexfs supports"));
+    }
+
+    let names = servers::server_names(&names, &options.names);
+    Ok(servers::ServersFS::new(names.into_iter().zip(filesystems).collect()))
+}
+
+fn mount(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let mountpoint = matches.values_of("mountpoint").unwrap().last().unwrap();
     let options = ["-o", "ro", "-o", "fsname=plex"]
         .iter()
         .map(|o| o.as_ref())
         .collect::<Vec<&OsStr>>();
-    fuse::mount(fs, mountpoint, &options).unwrap();
+
+    if matches.is_present("all-servers") {
+        if matches.is_present("dbus") {
+            return Err(anyhow!("--dbus can't be used with --all-servers"));
+        }
+        fuse::mount(servers_filesystem(config, matches)?, mountpoint, &options)?;
+        return Ok(());
+    }
+
+    let fs = filesystem(config, matches)?;
+    let profile = config.profile(profile_name(matches))?;
+    if matches.is_present("live") || profile.live.unwrap_or(false) {
+        fs.watch_notifications();
+    }
+    if matches.is_present("prefetch") || profile.prefetch.unwrap_or(false) {
+        fs.prefetch();
+    }
+
+    if matches.is_present("dbus") {
+        #[cfg(feature = "dbus")]
+        dbus_service::spawn(fs.control());
+        #[cfg(not(feature = "dbus"))]
+        return Err(anyhow!("plexfs was built without the \"dbus\" feature"));
+    }
+
+    fuse::mount(fs, mountpoint, &options)?;
+    Ok(())
+}
+
+fn run() -> Result<()> {
+    let matches = app().get_matches();
+    if matches.is_present("version") {
+        println!("plexfs {}", crate_version!());
+        return Ok(());
+    }
+
+    let config = match Config::load(matches.value_of("config")) {
+        Ok(config) => config,
+        // login creates the file.
+        Err(_) if matches.subcommand_name() == Some("login") => Config::default(),
+        Err(e) => return Err(e)
+    };
+
+    match matches.subcommand() {
+        ("mount", Some(sub)) => mount(&config, sub),
+        ("tree", Some(sub)) => {
+            let mut fs = filesystem(&config, sub)?;
+            let path = Path::new(sub.value_of("path").unwrap_or("/"));
+            cmd::tree::run(&mut fs, path, value_opt(sub, "depth"), sub.is_present("all"))
+        }
+        ("login", Some(sub)) => {
+            let path = match matches.value_of("config") {
+                Some(path) => PathBuf::from(path),
+                None => Config::default_path()
+                    .ok_or_else(|| anyhow!("no config file location; pass --config"))?
+            };
+            let name = sub.value_of("profile")
+                .or(config.default_profile.as_ref().map(|s| &s[..]))
+                .unwrap_or("default");
+            let options = api::ApiOptions {
+                proxy: sub.value_of("proxy").map(String::from),
+                ..api::ApiOptions::default()
+            };
+            let token = cmd::login::run(&options)?;
+            Config::save_token(&path, name, &token, sub.value_of("host"))?;
+            println!("Saved the token to profile \"{}\" in {}", name, path.display());
+            Ok(())
+        }
+        ("sections", Some(sub)) => {
+            let profile = config.profile(sub.value_of("profile"))?;
+            let conn = connection(sub, &profile)?;
+            cmd::sections::run(&*conn.backend()?)
+        }
+        ("ls", Some(sub)) => {
+            let mut fs = filesystem(&config, sub)?;
+            let path = Path::new(sub.value_of("path").unwrap_or("/"));
+            cmd::ls::run(&mut fs, path, sub.is_present("long"), sub.is_present("all"))
+        }
+        ("cat", Some(sub)) => {
+            let mut fs = filesystem(&config, sub)?;
+            let path = Path::new(sub.value_of("path").unwrap());
+            let range = match sub.value_of("range") {
+                Some(range) => Some(cmd::cat::parse_range(range)?),
+                None => None
+            };
+            cmd::cat::run(&mut fs, path, range)
+        }
+        ("verify", Some(sub)) => {
+            let mut fs = filesystem(&config, sub)?;
+            let local = Path::new(sub.value_of("local").unwrap());
+            let path = Path::new(sub.value_of("path").unwrap_or("/"));
+            cmd::verify::run(&mut fs, path, local, sub.is_present("spot-check"))
+        }
+        ("watch", Some(sub)) => {
+            let profile = config.profile(sub.value_of("profile"))?;
+            let conn = connection(sub, &profile)?;
+            let api = conn.api()?;
+            let section = match &conn.sections[..] {
+                [spec] => api.section(spec)?.key,
+                [] => return Err(anyhow!("no section given; pass --section or set it in a profile")),
+                _ => return Err(anyhow!("watch takes a single section"))
+            };
+            let target = cmd::watch::Target {
+                section: section,
+                library: Path::new(sub.value_of("library-path").unwrap()),
+                server_path: sub.value_of("server-path"),
+            };
+            let interval = Duration::from_secs(value_opt(sub, "interval").unwrap_or(10));
+            cmd::watch::run(&api, Path::new(sub.value_of("incoming").unwrap()), &target, interval)
+        }
+        ("serve-http", Some(sub)) => {
+            let mut fs = filesystem(&config, sub)?;
+            cmd::serve::run(&mut fs, sub.value_of("listen").unwrap_or("127.0.0.1:8080"))
+        }
+        ("browse", Some(sub)) => {
+            let mut fs = filesystem(&config, sub)?;
+            cmd::browse::run(&mut fs, sub.value_of("mountpoint").map(Path::new))
+        }
+        ("export", Some(sub)) => {
+            let mut fs = filesystem(&config, sub)?;
+            let path = Path::new(sub.value_of("path").unwrap());
+            let output = sub.value_of("output").unwrap_or("-");
+            let format = match sub.value_of("format") {
+                Some(format) => cmd::export::Format::from_name(format).unwrap(),
+                None => cmd::export::Format::from_output(output)
+            };
+            cmd::export::run(&mut fs, path, output, format)
+        }
+        ("cache", Some(sub)) => match sub.subcommand() {
+            ("seed", Some(sub)) => {
+                let mut fs = filesystem(&config, sub)?;
+                let local = Path::new(sub.value_of("local").unwrap());
+                let path = Path::new(sub.value_of("path").unwrap_or("/"));
+                cmd::cache::seed(&mut fs, path, local)
+            }
+            _ => unreachable!()
+        },
+        _ => {
+            app().print_help()?;
+            println!();
+            Ok(())
+        }
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    if let Err(e) = run() {
+        eprintln!("plexfs: {:#}", e);
+        process::exit(1);
+    }
 }
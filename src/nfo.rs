@@ -0,0 +1,82 @@
+//! Kodi's NFO files, which describe a movie, show or episode in XML for
+//! media centers that would otherwise look it up themselves.
+
+use std::fmt::Write;
+
+use super::api;
+
+/// The NFO describing `item`, None for kinds Kodi has none for.
+pub fn render(item: &api::Item) -> Option<String> {
+    let root = match item {
+        api::Item::Video { kind, .. } if kind == "movie" => "movie",
+        api::Item::Video { kind, .. } if kind == "episode" => "episodedetails",
+        api::Item::Directory { kind, .. } if kind == "show" => "tvshow",
+        _ => return None
+    };
+
+    let mut nfo = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n");
+    writeln!(nfo, "<{}>", root).unwrap();
+    element(&mut nfo, "title", item.title());
+    if let api::Item::Video { kind, grandparent_title, index, parent_index, .. } = item {
+        if kind == "episode" {
+            element(&mut nfo, "showtitle", grandparent_title);
+            if let Some(season) = parent_index {
+                element(&mut nfo, "season", &season.to_string());
+            }
+            if let Some(episode) = index {
+                element(&mut nfo, "episode", &episode.to_string());
+            }
+        }
+    }
+    element(&mut nfo, "plot", item.summary());
+    if let Some(year) = item.year() {
+        element(&mut nfo, "year", &year.to_string());
+    }
+
+    let (rating, user_rating) = item.ratings();
+    if let Some(rating) = rating {
+        writeln!(nfo, "  <ratings>\n    <rating name=\"plex\" max=\"10\" default=\"true\">\n      <value>{}</value>\n    </rating>\n  </ratings>", rating).unwrap();
+    }
+    if let Some(user_rating) = user_rating {
+        // Kodi's are whole numbers.
+        element(&mut nfo, "userrating", &(user_rating.round() as u64).to_string());
+    }
+    if let Some((source, id)) = unique_id(item.guid()) {
+        writeln!(nfo, "  <uniqueid type=\"{}\" default=\"true\">{}</uniqueid>", escape(source), escape(id)).unwrap();
+    }
+    for genre in item.genres() {
+        element(&mut nfo, "genre", &genre.tag);
+    }
+    writeln!(nfo, "</{}>", root).unwrap();
+    Some(nfo)
+}
+
+/// Splits a GUID into where it's from and the ID there: "plex://movie/5d77"
+/// into ("plex", "movie/5d77"), and the older
+/// "com.plexapp.agents.imdb://tt0111161?lang=en" into ("imdb", "tt0111161").
+fn unique_id(guid: &str) -> Option<(&str, &str)> {
+    let mut parts = guid.splitn(2, "://");
+    let scheme = parts.next()?;
+    let id = parts.next()?;
+    let source = scheme.rsplit('.').next().unwrap_or(scheme);
+    let id = id.split('?').next().unwrap_or(id);
+    match (source.is_empty(), id.is_empty()) {
+        (false, false) => Some((source, id)),
+        _ => None
+    }
+}
+
+/// Appends `<name>value</name>` on a line of its own, unless `value` is
+/// empty.
+fn element(nfo: &mut String, name: &str, value: &str) {
+    if !value.is_empty() {
+        writeln!(nfo, "  <{}>{}</{}>", name, escape(value), name).unwrap();
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
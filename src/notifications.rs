@@ -0,0 +1,147 @@
+//! Keeping cached listings in step with the library as the server reports
+//! changes to it.
+
+use std::io::{BufRead, BufReader};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::backend::MediaBackend;
+use super::control::{Command, Control};
+
+/// Wait before subscribing again after the connection drops, doubled on
+/// each failure in a row up to `MAX_RETRY_WAIT`.
+const RETRY_WAIT: Duration = Duration::from_secs(1);
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+/// Timeline states that change what a listing shows.
+const STATE_PROCESSED: u8 = 5;
+const STATE_DELETED: u8 = 9;
+
+#[derive(Debug, Deserialize)]
+struct TimelineEntry {
+    #[serde(rename="sectionID", default)]
+    section_id: String,
+    #[serde(rename="itemID", default)]
+    item_id: String,
+    #[serde(default)]
+    state: u8,
+}
+
+/// A notification's data. Servers send the entries either at the top level
+/// or, as over the websocket, wrapped in a container.
+#[derive(Debug, Deserialize)]
+struct Notification {
+    #[serde(rename="TimelineEntry", default)]
+    entries: Vec<TimelineEntry>,
+    #[serde(rename="NotificationContainer")]
+    container: Option<Box<Notification>>,
+}
+
+impl Notification {
+    fn into_entries(self) -> Vec<TimelineEntry> {
+        let mut entries = self.entries;
+        if let Some(container) = self.container {
+            entries.extend(container.into_entries());
+        }
+        entries
+    }
+}
+
+/// Listens for items being added, changed or deleted in `sections`, and has
+/// the filesystem drop the listings they're in. Reconnects until
+/// the process exits.
+pub fn spawn(api: Arc<dyn MediaBackend>, control: Arc<Control>, sections: Vec<u64>) {
+    thread::spawn(move || {
+        let mut wait = RETRY_WAIT;
+        loop {
+            match listen(&api, &control, &sections, &mut wait) {
+                Ok(()) => debug!("notifications ended, subscribing again"),
+                Err(e) => warn!("notifications: {}; subscribing again in {:?}", e, wait)
+            }
+            thread::sleep(wait);
+            wait = (wait * 2).min(MAX_RETRY_WAIT);
+        }
+    });
+}
+
+fn listen(api: &dyn MediaBackend, control: &Control, sections: &[u64], wait: &mut Duration) -> Result<()> {
+    let resp = api.notifications()?;
+    info!("listening for library changes");
+    *wait = RETRY_WAIT;
+
+    // Server-sent events: `data:` lines, ended by a blank line.
+    let mut data = String::new();
+    for line in BufReader::new(resp).lines() {
+        let line = line?;
+        if let Some(rest) = line.strip_prefix("data:") {
+            data.push_str(rest.trim_start());
+        } else if line.is_empty() && !data.is_empty() {
+            handle(control, sections, &data);
+            data.clear();
+        }
+    }
+    Ok(())
+}
+
+fn handle(control: &Control, sections: &[u64], data: &str) {
+    let notification: Notification = match serde_json::from_str(data) {
+        Ok(notification) => notification,
+        // Other kinds of events, like pings.
+        Err(_) => return
+    };
+    for entry in notification.into_entries() {
+        let section = match entry.section_id.parse::<u64>() {
+            Ok(section) if sections.contains(&section) => section,
+            _ => continue
+        };
+        match entry.state {
+            STATE_PROCESSED | STATE_DELETED => {
+                debug!("item {} in section {} changed (state {})", entry.item_id, section, entry.state);
+                control.send(Command::Refresh(section, entry.item_id.parse().ok()));
+            }
+            _ => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refreshes_changed_items() {
+        let control = Control::default();
+        handle(&control, &[1, 2], r#"{"NotificationContainer": {"type": "timeline", "size": 3, "TimelineEntry": [
+            {"identifier": "com.plexapp.plugins.library", "sectionID": "1", "itemID": "100", "type": 10, "title": "Song", "state": 5},
+            {"identifier": "com.plexapp.plugins.library", "sectionID": "2", "itemID": "200", "type": 9, "state": 9},
+            {"identifier": "com.plexapp.plugins.library", "sectionID": "1", "itemID": "101", "type": 10, "state": 0}
+        ]}}"#);
+        assert_eq!(control.take(), vec![Command::Refresh(1, Some(100)), Command::Refresh(2, Some(200))]);
+    }
+
+    #[test]
+    fn entries_at_the_top_level() {
+        let control = Control::default();
+        handle(&control, &[1], r#"{"TimelineEntry": [{"sectionID": "1", "itemID": "100", "state": 5}]}"#);
+        assert_eq!(control.take(), vec![Command::Refresh(1, Some(100))]);
+    }
+
+    #[test]
+    fn ignores_other_sections_and_events() {
+        let control = Control::default();
+        handle(&control, &[1], r#"{"NotificationContainer": {"TimelineEntry": [{"sectionID": "3", "itemID": "300", "state": 5}]}}"#);
+        handle(&control, &[1], r#"{"NotificationContainer": {"type": "playing", "PlaySessionStateNotification": [{"sessionKey": "1"}]}}"#);
+        handle(&control, &[1], "not json");
+        assert_eq!(control.take(), vec![]);
+    }
+
+    #[test]
+    fn items_without_ids_refresh_the_section() {
+        let control = Control::default();
+        handle(&control, &[1], r#"{"TimelineEntry": [{"sectionID": "1", "state": 9}]}"#);
+        assert_eq!(control.take(), vec![Command::Refresh(1, None)]);
+    }
+}
@@ -0,0 +1,34 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of threads running jobs in the order they were queued. The
+/// threads exit once the pool is dropped and the queue is drained.
+pub struct Pool {
+    sender: mpsc::Sender<Job>,
+}
+
+impl Pool {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                // Hold the lock only while taking the next job.
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => break
+                };
+                job();
+            });
+        }
+        Pool { sender: sender }
+    }
+
+    pub fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        // The workers only stop once the sender is dropped, so this can't fail.
+        let _ = self.sender.send(Box::new(job));
+    }
+}
@@ -0,0 +1,173 @@
+use std::cmp;
+
+use anyhow::{anyhow, Result};
+use unicode_normalization::UnicodeNormalization;
+
+/// Which platform's file name rules to follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    /// Only `/` and NUL are replaced.
+    Posix,
+    /// Also replaces characters NTFS and SMB reject (`\ : * ? " < > |`),
+    /// control characters, trailing dots and spaces, and renames reserved
+    /// device names like `CON` and `NUL.txt`.
+    Windows,
+}
+
+impl Charset {
+    pub const NAMES: &'static [&'static str] = &["posix", "windows"];
+
+    pub fn from_name(name: &str) -> Option<Charset> {
+        match name {
+            "posix" => Some(Charset::Posix),
+            "windows" => Some(Charset::Windows),
+            _ => None
+        }
+    }
+}
+
+impl Default for Charset {
+    fn default() -> Self {
+        Charset::Posix
+    }
+}
+
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The longest name most filesystems, and the kernel's FUSE lookups, accept.
+pub const DEFAULT_MAX_LEN: usize = 255;
+
+pub const DEFAULT_REPLACEMENT: char = '_';
+
+/// Length of the `~` and hash `truncate` puts in place of what it cuts off.
+const HASH_LEN: usize = 9;
+
+/// Turns Plex titles and server file names into names that are valid in
+/// the mount.
+#[derive(Debug, Clone)]
+pub struct NamePolicy {
+    pub charset: Charset,
+    /// Maximum name length in bytes.
+    pub max_len: usize,
+    /// What invalid characters are replaced with.
+    pub replacement: char,
+    /// Compose names into Unicode NFC, so a name typed on Linux matches one
+    /// a Mac server stored decomposed.
+    pub normalize: bool,
+    /// Remove control characters rather than keeping or replacing them.
+    pub strip_control: bool,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        NamePolicy {
+            charset: Charset::default(),
+            max_len: DEFAULT_MAX_LEN,
+            replacement: DEFAULT_REPLACEMENT,
+            normalize: false,
+            strip_control: false,
+        }
+    }
+}
+
+/// 32-bit FNV-1a, which unlike `DefaultHasher` is stable across Rust
+/// releases, so truncated names don't change between builds.
+fn fnv1a(s: &str) -> u32 {
+    s.bytes().fold(0x811c9dc5, |hash, b| (hash ^ b as u32).wrapping_mul(0x01000193))
+}
+
+/// The longest prefix of `s` that is at most `len` bytes and ends on a
+/// character boundary.
+fn truncate_at_char(s: &str, len: usize) -> &str {
+    let mut end = cmp::min(len, s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Shortens `name` to `max_len` bytes, keeping a short extension and
+/// replacing the cut-off part with a hash of the full name, so names that
+/// only differ after the cut stay distinct.
+fn truncate(name: &str, max_len: usize) -> String {
+    if name.len() <= max_len {
+        return name.to_string();
+    }
+
+    let ext = match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= 16 => &name[i..],
+        _ => ""
+    };
+    let hash = format!("~{:08x}", fnv1a(name));
+    let budget = max_len.saturating_sub(ext.len() + hash.len());
+    let stem = truncate_at_char(&name[..name.len() - ext.len()], budget);
+    let mut truncated = format!("{}{}{}", stem, hash, ext);
+    if truncated.len() > max_len {
+        truncated = truncate_at_char(&truncated, max_len).to_string();
+    }
+    truncated
+}
+
+impl NamePolicy {
+    fn is_invalid(&self, c: char) -> bool {
+        match self.charset {
+            Charset::Posix => c == '/' || c == '\0',
+            Charset::Windows => c < ' ' || "/\\:*?\"<>|".contains(c),
+        }
+    }
+
+    /// Fails if the replacement character would itself need replacing, or
+    /// would turn an empty name into `.` or `..`, or if `max_len` leaves no
+    /// room for a character of a truncated name beside its hash.
+    pub fn check(&self) -> Result<()> {
+        if self.max_len <= HASH_LEN {
+            return Err(anyhow!("names can't be limited to less than {} bytes", HASH_LEN + 1));
+        }
+        let trailing = self.charset == Charset::Windows && self.replacement == ' ';
+        if self.is_invalid(self.replacement) || self.replacement == '.' || trailing
+            || (self.strip_control && self.replacement.is_control()) {
+            return Err(anyhow!("{:?} can't replace invalid characters in {:?} names", self.replacement, self.charset));
+        }
+        Ok(())
+    }
+
+    pub fn sanitize(&self, name: &str) -> String {
+        let normalized: String;
+        let name = match self.normalize {
+            true => {
+                normalized = name.nfc().collect();
+                &normalized
+            }
+            false => name
+        };
+        let mut name: String = name.chars()
+            .filter(|c| !(self.strip_control && c.is_control()))
+            .map(|c| if self.is_invalid(c) { self.replacement } else { c })
+            .collect();
+
+        if let Charset::Windows = self.charset {
+            // Windows silently drops trailing dots and spaces.
+            let trimmed = name.trim_end_matches(|c: char| c == '.' || c == ' ').len();
+            if trimmed < name.len() {
+                let extra = name.len() - trimmed;
+                name.truncate(trimmed);
+                name.extend((0..extra).map(|_| self.replacement));
+            }
+
+            let stem_len = name.find('.').unwrap_or(name.len());
+            if WINDOWS_RESERVED.iter().any(|r| r.eq_ignore_ascii_case(&name[..stem_len])) {
+                name.insert(stem_len, self.replacement);
+            }
+        }
+
+        if name.is_empty() || name == "." || name == ".." {
+            name = self.replacement.to_string().repeat(cmp::max(1, name.len()));
+        }
+
+        truncate(&name, self.max_len)
+    }
+}
@@ -0,0 +1,134 @@
+use std::cell::Cell;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Whether a request is serving a FUSE operation someone is waiting on, or
+/// work that can wait, like pinning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Interactive,
+    Background,
+}
+
+thread_local! {
+    static PRIORITY: Cell<Priority> = Cell::new(Priority::Interactive);
+}
+
+/// Runs `f` with requests made on this thread scheduled as background work.
+pub fn background<T, F: FnOnce() -> T>(f: F) -> T {
+    with_priority(Priority::Background, f)
+}
+
+/// Runs `f` with requests made on this thread at `priority`, as when a
+/// thread works on behalf of another.
+pub fn with_priority<T, F: FnOnce() -> T>(priority: Priority, f: F) -> T {
+    let previous = PRIORITY.with(|p| p.replace(priority));
+    let result = f();
+    PRIORITY.with(|p| p.set(previous));
+    result
+}
+
+/// The priority of requests made on this thread.
+pub fn current() -> Priority {
+    PRIORITY.with(|p| p.get())
+}
+
+#[derive(Debug, Default)]
+struct State {
+    active: usize,
+    /// Interactive requests running or waiting for a slot.
+    interactive: usize,
+    /// Slots held by streaming responses, which are among `active`.
+    streams: usize,
+}
+
+/// Hands out a fixed number of request slots. Interactive requests take the
+/// next free slot; background requests also wait until no interactive
+/// request is running or queued, so they only use otherwise idle capacity.
+#[derive(Debug)]
+pub struct Scheduler {
+    slots: usize,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/// A request slot, released on drop.
+pub struct Permit<'a> {
+    scheduler: &'a Scheduler,
+    priority: Priority,
+}
+
+/// A slot held by a streaming response for as long as it's read, released
+/// on drop.
+pub struct StreamPermit {
+    scheduler: Arc<Scheduler>,
+}
+
+impl Scheduler {
+    pub fn new(slots: usize) -> Self {
+        Scheduler {
+            slots: slots.max(1),
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Waits for a slot at the calling thread's priority.
+    pub fn acquire(&self) -> Permit {
+        let priority = current();
+        let mut state = self.state.lock().unwrap();
+        match priority {
+            Priority::Interactive => {
+                state.interactive += 1;
+                while state.active >= self.slots {
+                    state = self.cond.wait(state).unwrap();
+                }
+            }
+            Priority::Background => {
+                while state.active >= self.slots || state.interactive > 0 {
+                    state = self.cond.wait(state).unwrap();
+                }
+            }
+        }
+        state.active += 1;
+        Permit {
+            scheduler: self,
+            priority: priority,
+        }
+    }
+
+    /// Takes a slot for a streaming response if one is free without waiting.
+    /// Streams only get half the slots, since they keep theirs while the
+    /// file is open, and other requests would otherwise wait for them to be
+    /// closed.
+    pub fn try_acquire_stream(self: Arc<Self>) -> Option<StreamPermit> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.active >= self.slots || state.streams >= self.slots / 2 {
+                return None;
+            }
+            state.active += 1;
+            state.streams += 1;
+        }
+        Some(StreamPermit { scheduler: self })
+    }
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        let mut state = self.scheduler.state.lock().unwrap();
+        state.active -= 1;
+        if let Priority::Interactive = self.priority {
+            state.interactive -= 1;
+        }
+        self.scheduler.cond.notify_all();
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        let mut state = self.scheduler.state.lock().unwrap();
+        state.active -= 1;
+        state.streams -= 1;
+        self.scheduler.cond.notify_all();
+    }
+}
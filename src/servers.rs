@@ -0,0 +1,260 @@
+//! Several servers in one mount, each a directory at the root holding what
+//! a mount of that server alone would show.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use anyhow::Result;
+use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory, ReplyEmpty, ReplyOpen, ReplyStatfs, ReplyXattr};
+use libc::{c_int, EISDIR, ENODATA, ENOENT};
+
+use super::fs::{self, PlexFS, Usage, DEFAULT_PAGE_SIZE, INO_ROOT, TTL};
+use super::sanitize::NamePolicy;
+
+/// Inodes are allocated as each server's are first seen, since a server's
+/// own inodes already use the whole range.
+pub struct ServersFS {
+    servers: Vec<(OsString, PlexFS)>,
+    /// (server index, the server's inode) for each inode above the root.
+    targets: Vec<(usize, u64)>,
+    inos: HashMap<(usize, u64), u64>,
+}
+
+impl ServersFS {
+    pub fn new(servers: Vec<(OsString, PlexFS)>) -> Self {
+        let mut fs = ServersFS {
+            servers: servers,
+            targets: vec![],
+            inos: HashMap::new(),
+        };
+        for i in 0..fs.servers.len() {
+            fs.ino(i, INO_ROOT);
+        }
+        fs
+    }
+
+    /// Applies each server's pending commands, as its own operations would.
+    fn apply_commands(&mut self) {
+        for (_, server) in self.servers.iter_mut() {
+            server.apply_commands();
+        }
+    }
+
+    fn ino(&mut self, server: usize, ino: u64) -> u64 {
+        if let Some(ino) = self.inos.get(&(server, ino)) {
+            return *ino;
+        }
+        self.targets.push((server, ino));
+        let outer = INO_ROOT + self.targets.len() as u64;
+        self.inos.insert((server, ino), outer);
+        outer
+    }
+
+    fn target(&self, ino: u64) -> Option<(usize, u64)> {
+        if ino <= INO_ROOT {
+            return None;
+        }
+        self.targets.get((ino - INO_ROOT - 1) as usize).copied()
+    }
+
+    fn attr(&mut self, ino: u64) -> Result<FileAttr> {
+        let attr = match self.target(ino) {
+            Some((server, inner)) => self.servers[server].1.attr(inner)?,
+            None if ino == INO_ROOT => {
+                let mut attr = self.servers[0].1.attr(INO_ROOT)?;
+                attr.size = self.servers.len() as u64;
+                attr.nlink = 2 + self.servers.len() as u32;
+                attr
+            }
+            None => return Err(fs::not_found(format!("no inode {}", ino)))
+        };
+        Ok(FileAttr { ino: ino, ..attr })
+    }
+
+    /// The errno to report for a directory that couldn't be listed.
+    fn failure(&self, ino: u64) -> Option<c_int> {
+        let (server, inner) = self.target(ino)?;
+        self.servers[server].1.failure(inner)
+    }
+
+    /// The first `wanted` entries of a directory, as `PlexFS::listing`.
+    fn listing(&mut self, ino: u64, wanted: usize) -> Vec<(OsString, u64, FileType)> {
+        match self.target(ino) {
+            Some((server, inner)) => {
+                let children = self.servers[server].1.listing(inner, wanted);
+                children.into_iter()
+                    .map(|(name, child, kind)| (name, self.ino(server, child), kind))
+                    .collect()
+            }
+            None => (0..self.servers.len())
+                .map(|i| (self.servers[i].0.clone(), self.ino(i, INO_ROOT), FileType::Directory))
+                .collect()
+        }
+    }
+
+    fn parent(&mut self, ino: u64) -> u64 {
+        match self.target(ino) {
+            Some((_, INO_ROOT)) | None => INO_ROOT,
+            Some((server, inner)) => {
+                let parent = self.servers[server].1.parent(inner);
+                self.ino(server, parent)
+            }
+        }
+    }
+}
+
+/// Names each server's directory, numbering repeated names.
+pub fn server_names(names: &[String], policy: &NamePolicy) -> Vec<OsString> {
+    let mut seen: HashMap<String, u32> = HashMap::new();
+    names.iter().map(|name| {
+        let name = policy.sanitize(name);
+        let n = seen.entry(name.clone()).or_insert(0);
+        *n += 1;
+        if *n == 1 {
+            OsString::from(name)
+        } else {
+            OsString::from(format!("{} ({})", name, n))
+        }
+    }).collect()
+}
+
+impl Filesystem for ServersFS {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!("lookup {} {:?}", parent, name);
+        self.apply_commands();
+
+        let found = match self.target(parent) {
+            Some((server, inner)) => self.servers[server].1.entry_attr(inner, name)
+                .map(|attr| (self.ino(server, attr.ino), attr)),
+            None => match self.servers.iter().position(|(n, _)| n == name) {
+                Some(server) => {
+                    let ino = self.ino(server, INO_ROOT);
+                    self.attr(ino).ok().map(|attr| (ino, attr))
+                }
+                None => None
+            }
+        };
+        match found {
+            Some((ino, attr)) => reply.entry(&TTL, &FileAttr { ino: ino, ..attr }, 0),
+            None => reply.error(self.failure(parent).unwrap_or(ENOENT))
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        debug!("getattr {}", ino);
+        self.apply_commands();
+
+        match self.attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => {
+                warn!("getattr of {} failed: {}", ino, e);
+                reply.error(fs::errno(&e))
+            }
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        debug!("open {}", ino);
+
+        let result = match self.target(ino) {
+            Some((server, inner)) => self.servers[server].1.open_file(inner),
+            None => return reply.error(EISDIR)
+        };
+        match result {
+            Ok((fh, flags)) => reply.opened(fh, flags),
+            Err(e) => {
+                warn!("open of {} failed: {}", ino, e);
+                reply.error(fs::errno(&e))
+            }
+        }
+    }
+
+    fn release(&mut self, _req: &Request, ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        // File handles are only unique within a server, so go by the inode.
+        if let Some((server, _)) = self.target(ino) {
+            self.servers[server].1.release_file(fh);
+        }
+        reply.ok();
+    }
+
+    fn read(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        debug!("read {} {} {}", ino, offset, size);
+        self.apply_commands();
+
+        match self.target(ino) {
+            Some((server, inner)) => self.servers[server].1.read_into(inner, fh, offset as u64, size, reply),
+            None => reply.error(ENOENT)
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        debug!("readdir {} {}", ino, offset);
+        self.apply_commands();
+
+        let mut listing = vec![
+            (OsString::from("."), ino, FileType::Directory),
+            (OsString::from(".."), self.parent(ino), FileType::Directory),
+        ];
+        let wanted = offset as usize + DEFAULT_PAGE_SIZE as usize;
+        listing.extend(self.listing(ino, wanted));
+        if let Some(errno) = self.failure(ino) {
+            return reply.error(errno);
+        }
+
+        for (i, (name, child, kind)) in listing.iter().enumerate().skip(offset as usize) {
+            if reply.add(*child, (i + 1) as i64, *kind, name) {
+                break
+            }
+        }
+
+        reply.ok();
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!("getxattr {} {:?}", ino, name);
+        self.apply_commands();
+
+        let result = match self.target(ino) {
+            Some((server, inner)) => self.servers[server].1.xattr(inner, name),
+            None => Ok(None)
+        };
+        match result {
+            Ok(Some(value)) => fs::reply_xattr(reply, &value, size),
+            Ok(None) => reply.error(ENODATA),
+            Err(e) => {
+                warn!("getxattr of {} failed: {}", ino, e);
+                reply.error(fs::errno(&e))
+            }
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr {}", ino);
+        self.apply_commands();
+
+        let result = match self.target(ino) {
+            Some((server, inner)) => self.servers[server].1.xattr_names(inner),
+            None => Ok(vec![])
+        };
+        match result {
+            Ok(names) => fs::reply_xattr(reply, &names, size),
+            Err(e) => {
+                warn!("listxattr of {} failed: {}", ino, e);
+                reply.error(fs::errno(&e))
+            }
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        debug!("statfs");
+        self.apply_commands();
+
+        let mut total = Usage::default();
+        for (_, server) in self.servers.iter_mut() {
+            let usage = server.usage();
+            total.bytes += usage.bytes;
+            total.files += usage.files;
+        }
+        let blocks = (total.bytes + fs::STATFS_BLOCK_SIZE - 1) / fs::STATFS_BLOCK_SIZE;
+        reply.statfs(blocks, 0, 0, total.files, 0, fs::STATFS_BLOCK_SIZE as u32, fs::NAME_MAX, fs::STATFS_BLOCK_SIZE as u32);
+    }
+}
@@ -0,0 +1,175 @@
+use std::cmp;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use anyhow::Result;
+
+use super::api::{Part, StreamBody};
+use super::backend::MediaBackend;
+
+/// Size of the pieces a stream is read ahead in.
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+
+/// How far apart reads of an open file can arrive and still be served from
+/// its stream. The reader pool's threads race each other, so the kernel's
+/// reads ahead reach the stream in any order.
+const WINDOW: u64 = 8 * CHUNK_SIZE;
+
+/// An open file's connection to the server. Reads that carry on from where
+/// the furthest one so far ended, give or take `WINDOW`, are served from a
+/// single streaming response, which a background thread reads ahead of the
+/// reader; any other read is a Range request of its own, after which
+/// streaming resumes from where that read ended.
+pub struct Stream {
+    pump: Option<Pump>,
+    /// Where the next sequential read is expected: the end of the furthest
+    /// read so far.
+    position: u64,
+    /// Chunks read ahead of the reader at most.
+    readahead: usize,
+}
+
+/// The thread reading a streaming response ahead. An empty chunk marks the
+/// end of the response, which is the end of the file unless the connection
+/// was closed early. Dropping the receiver stops it.
+struct Pump {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    /// What's been received and not yet dropped, for reads that arrive
+    /// after ones further on.
+    buffer: Vec<u8>,
+    /// The offset of `buffer` in the file.
+    start: u64,
+    eof: bool,
+}
+
+impl Pump {
+    fn start(mut response: StreamBody, offset: u64, readahead: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(readahead);
+        thread::spawn(move || loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+            let result = (&mut response).take(CHUNK_SIZE).read_to_end(&mut chunk);
+            let done = match result {
+                Ok(0) | Err(_) => true,
+                Ok(_) => false
+            };
+            if sender.send(result.map(|_| chunk)).is_err() || done {
+                break;
+            }
+        });
+        Pump {
+            chunks: receiver,
+            buffer: vec![],
+            start: offset,
+            eof: false,
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.buffer.len() as u64
+    }
+
+    /// Whether a read at `offset` can be served from the response.
+    fn covers(&self, offset: u64) -> bool {
+        offset >= self.start && offset <= self.end() + WINDOW
+    }
+
+    /// Appends up to `size` bytes at `offset` to `buf`, waiting for the
+    /// thread as needed. Fails if the response broke, keeping what was read
+    /// before.
+    fn read(&mut self, buf: &mut Vec<u8>, offset: u64, size: usize) -> io::Result<()> {
+        let mut result = Ok(());
+        while self.end() < offset + size as u64 && !self.eof {
+            match self.chunks.recv() {
+                Ok(Ok(chunk)) if chunk.is_empty() => self.eof = true,
+                Ok(Ok(chunk)) => self.buffer.extend(chunk),
+                Ok(Err(e)) => {
+                    result = Err(e);
+                    break
+                }
+                Err(_) => {
+                    result = Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream thread stopped"));
+                    break
+                }
+            }
+        }
+        let from = (offset - self.start) as usize;
+        if from < self.buffer.len() {
+            let to = cmp::min(from + size, self.buffer.len());
+            buf.extend_from_slice(&self.buffer[from..to]);
+        }
+        result
+    }
+
+    /// Drops what's before `offset`.
+    fn trim(&mut self, offset: u64) {
+        if offset > self.start {
+            let n = cmp::min(offset - self.start, self.buffer.len() as u64);
+            self.buffer.drain(..n as usize);
+            self.start += n;
+        }
+    }
+}
+
+impl Stream {
+    /// `readahead` is in bytes, rounded down to whole chunks.
+    pub fn new(readahead: u64) -> Self {
+        Stream {
+            pump: None,
+            position: 0,
+            readahead: (readahead / CHUNK_SIZE) as usize,
+        }
+    }
+
+    /// Reads up to `size` bytes at `offset`, returning fewer only at the end
+    /// of the file.
+    pub fn read(&mut self, api: &dyn MediaBackend, part: &Part, offset: u64, size: u32) -> Result<Vec<u8>> {
+        let covered = self.pump.as_ref().map_or(false, |pump| pump.covers(offset));
+        let ahead = offset >= self.position && offset - self.position <= WINDOW;
+        if !covered && !ahead {
+            let body = api.file(part, offset as i64, size)?;
+            // A read just behind the others that's no longer buffered is
+            // only late. Anything else is a seek. The stream is reopened
+            // lazily, so a one-off read elsewhere, like a player probing the
+            // end of the file, doesn't cost a connection or read ahead of the
+            // wrong place.
+            if offset + WINDOW < self.position || offset > self.position {
+                self.pump = None;
+                self.position = offset + body.len() as u64;
+            }
+            return Ok(body);
+        }
+
+        if !covered {
+            self.pump = None;
+            if let Some(response) = api.stream(part, self.position)? {
+                self.pump = Some(Pump::start(response, self.position, self.readahead));
+            }
+        }
+        let mut buf = Vec::with_capacity(size as usize);
+        if let Some(pump) = &mut self.pump {
+            match pump.read(&mut buf, offset, size as usize) {
+                Err(e) => {
+                    warn!("stream of {} broke at {}: {}", part.key, offset + buf.len() as u64, e);
+                    self.pump = None;
+                }
+                // A response that ends before the file does was cut off.
+                Ok(()) if pump.eof && part.size > 0 && offset + (buf.len() as u64) < part.size => {
+                    warn!("stream of {} ended early at {} of {}", part.key, offset + buf.len() as u64, part.size);
+                    self.pump = None;
+                }
+                Ok(()) => ()
+            }
+        }
+        // Range requests retry what the broken stream didn't deliver, or
+        // read what there was no stream for.
+        if buf.len() < size as usize && self.pump.is_none() {
+            let start = offset + buf.len() as u64;
+            buf.extend(api.file(part, start as i64, size - buf.len() as u32)?);
+        }
+        self.position = cmp::max(self.position, offset + buf.len() as u64);
+        if let Some(pump) = &mut self.pump {
+            pump.trim(self.position.saturating_sub(WINDOW));
+        }
+        Ok(buf)
+    }
+}
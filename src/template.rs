@@ -0,0 +1,218 @@
+//! Path templates like `{artist}/{album} ({year})/{track:02} - {title}.{ext}`,
+//! which place each file by its metadata rather than where it is on the
+//! server.
+
+use anyhow::{anyhow, Result};
+
+use super::api;
+
+/// The fields a template can use.
+pub const FIELDS: &[&str] = &[
+    "title", "artist", "album", "show", "year", "track", "disc", "season", "episode", "file", "ext",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    /// A field, zero-padded to `width` digits if it's a number.
+    Field { name: String, width: usize },
+}
+
+/// A parsed path template. Each `/`-separated component becomes a directory,
+/// and the last the file name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    components: Vec<Vec<Segment>>,
+}
+
+impl Template {
+    /// Whether `layout` is a template rather than the name of a layout.
+    pub fn is_template(layout: &str) -> bool {
+        layout.contains('{')
+    }
+
+    /// Parses a template. `{{` and `}}` stand for literal braces.
+    pub fn parse(template: &str) -> Result<Template> {
+        let mut components = vec![];
+        let mut segments = vec![];
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => return Err(anyhow!("template \"{}\" has an unclosed {{", template))
+                        }
+                    }
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(text.split_off(0)));
+                    }
+                    segments.push(field(&spec)?);
+                }
+                '}' => return Err(anyhow!("template \"{}\" has an unopened }}", template)),
+                '/' => {
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(text.split_off(0)));
+                    }
+                    components.push(segments.split_off(0));
+                }
+                c => text.push(c)
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        components.push(segments);
+        components.retain(|segments| !segments.is_empty());
+        if components.is_empty() {
+            return Err(anyhow!("template is empty"));
+        }
+        Ok(Template { components: components })
+    }
+
+    /// The path of `media` of `item`, a component at a time. Fields the item
+    /// doesn't have are left empty, and components that end up empty are
+    /// left out.
+    pub fn render(&self, item: &api::Item, media: &api::Media) -> Vec<String> {
+        self.components.iter()
+            .map(|segments| segments.iter().map(|segment| match segment {
+                Segment::Text(text) => text.clone(),
+                Segment::Field { name, width } => value(item, media, name, *width)
+            }).collect::<String>())
+            .map(|component| component.trim().to_string())
+            .filter(|component| !component.is_empty())
+            .collect()
+    }
+}
+
+/// Parses what's between braces: a field name, then optionally `:` and a
+/// width like `02`.
+fn field(spec: &str) -> Result<Segment> {
+    let mut parts = spec.splitn(2, ':');
+    let name = parts.next().unwrap_or("").trim();
+    if !FIELDS.contains(&name) {
+        return Err(anyhow!("unknown template field \"{}\"; expected one of {}", name, FIELDS.join(", ")));
+    }
+    let width = match parts.next() {
+        Some(width) => width.parse()
+            .map_err(|_| anyhow!("template field \"{}\" has an invalid width; expected e.g. {{track:02}}", spec))?,
+        None => 0
+    };
+    Ok(Segment::Field { name: name.to_string(), width: width })
+}
+
+fn value(item: &api::Item, media: &api::Media, name: &str, width: usize) -> String {
+    let number = |n: Option<u64>| n.map_or(String::new(), |n| format!("{:0width$}", n, width = width));
+    // Servers on Windows report paths with backslashes.
+    let file = media.part.file.rsplit(|c| c == '/' || c == '\\').next().unwrap_or("");
+    match (name, item) {
+        ("title", _) => item.title().to_string(),
+        ("artist", api::Item::Track { grandparent_title, .. }) => grandparent_title.clone(),
+        ("album", api::Item::Track { parent_title, .. }) => parent_title.clone(),
+        ("show", api::Item::Video { kind, grandparent_title, .. }) if kind == "episode" => grandparent_title.clone(),
+        ("year", _) => number(item.year()),
+        ("track", api::Item::Track { index, .. }) => number(*index),
+        ("disc", api::Item::Track { parent_index, .. }) => number(*parent_index),
+        ("season", api::Item::Video { parent_index, .. }) => number(*parent_index),
+        ("episode", api::Item::Video { index, .. }) => number(*index),
+        ("file", _) => match file.rfind('.') {
+            Some(i) if i > 0 => file[..i].to_string(),
+            _ => file.to_string()
+        },
+        ("ext", _) => match file.rfind('.') {
+            Some(i) if i > 0 && i + 1 < file.len() => file[i + 1..].to_string(),
+            _ => media.extension().unwrap_or("").to_string()
+        },
+        _ => String::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track() -> (api::Item, api::Media) {
+        let media = api::Media {
+            container: Some("flac".to_string()),
+            part: api::Part { file: "C:\\Music\\Band\\03 Song.flac".to_string(), ..api::Part::default() },
+            ..api::Media::default()
+        };
+        let item = api::Item::Track {
+            rating_key: 1,
+            guid: String::new(),
+            title: "Song".to_string(),
+            index: Some(3),
+            parent_index: Some(1),
+            parent_rating_key: 2,
+            parent_title: "Album".to_string(),
+            parent_year: Some(1999),
+            grandparent_title: "Band".to_string(),
+            grandparent_rating_key: 3,
+            original_title: None,
+            summary: String::new(),
+            rating: None,
+            user_rating: None,
+            genres: vec![],
+            last_viewed_at: 0,
+            added_at: 0,
+            updated_at: 0,
+            media: vec![media.clone()],
+        };
+        (item, media)
+    }
+
+    fn render(template: &str) -> Vec<String> {
+        let (item, media) = track();
+        Template::parse(template).unwrap().render(&item, &media)
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(render("{artist}/{album} ({year})/{track:02} - {title}.{ext}"),
+                   vec!["Band", "Album (1999)", "03 - Song.flac"]);
+        assert_eq!(render("{file}.{ext}"), vec!["03 Song.flac"]);
+    }
+
+    #[test]
+    fn widths() {
+        assert_eq!(render("{disc}-{track:03}"), vec!["1-003"]);
+        assert_eq!(render("{year:06}"), vec!["001999"]);
+        assert!(Template::parse("{track:x}").is_err());
+        assert!(Template::parse("{track:}").is_err());
+    }
+
+    #[test]
+    fn braces() {
+        assert_eq!(render("{{{title}}}"), vec!["{Song}"]);
+        assert_eq!(render("{{title}}"), vec!["{title}"]);
+        assert!(Template::parse("{title").is_err());
+        assert!(Template::parse("title}").is_err());
+        assert!(Template::parse("{nope}").is_err());
+    }
+
+    #[test]
+    fn empty_components() {
+        assert_eq!(render("{show}/ {season} /{title}"), vec!["Song"]);
+        assert_eq!(render("/{artist}//{title}/"), vec!["Band", "Song"]);
+        assert!(Template::parse("/").is_err());
+        assert!(Template::parse("").is_err());
+    }
+
+    #[test]
+    fn is_template() {
+        assert!(Template::is_template("{title}"));
+        assert!(!Template::is_template("server-paths"));
+    }
+}
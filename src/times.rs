@@ -0,0 +1,107 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::api;
+
+/// A timestamp to fall back to when Plex leaves one unset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSource {
+    Viewed,
+    Updated,
+    Added,
+    /// The time the filesystem was mounted.
+    Mount,
+}
+
+impl TimeSource {
+    pub const NAMES: &'static [&'static str] = &["viewed", "updated", "added", "mount"];
+
+    pub fn from_name(name: &str) -> Option<TimeSource> {
+        match name {
+            "viewed" => Some(TimeSource::Viewed),
+            "updated" => Some(TimeSource::Updated),
+            "added" => Some(TimeSource::Added),
+            "mount" => Some(TimeSource::Mount),
+            _ => None
+        }
+    }
+}
+
+/// Decides what to report for timestamps Plex sends as 0 or omits, like
+/// `lastViewedAt` on items that were never played.
+#[derive(Debug, Clone)]
+pub struct TimePolicy {
+    /// Tried in order; the epoch is used if all of them are unset.
+    pub fallback: Vec<TimeSource>,
+    pub mounted_at: SystemTime,
+}
+
+impl Default for TimePolicy {
+    fn default() -> Self {
+        TimePolicy {
+            fallback: vec![TimeSource::Updated, TimeSource::Added, TimeSource::Mount],
+            mounted_at: SystemTime::now(),
+        }
+    }
+}
+
+/// How reads through the mount affect the reported atime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtimeMode {
+    /// Report `lastViewedAt`, so atime tracks playback on any client.
+    Plex,
+    /// Report mtime, so attributes only change when the item does.
+    NoAtime,
+    /// Like `Plex`, but reads through the mount also bump atime, at most
+    /// once a day per file.
+    RelAtime,
+}
+
+impl AtimeMode {
+    pub const NAMES: &'static [&'static str] = &["plex", "noatime", "relatime"];
+
+    pub fn from_name(name: &str) -> Option<AtimeMode> {
+        match name {
+            "plex" => Some(AtimeMode::Plex),
+            "noatime" => Some(AtimeMode::NoAtime),
+            "relatime" => Some(AtimeMode::RelAtime),
+            _ => None
+        }
+    }
+}
+
+impl Default for AtimeMode {
+    fn default() -> Self {
+        AtimeMode::Plex
+    }
+}
+
+/// Minimum time between local atime updates in `RelAtime` mode.
+pub const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn from_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+impl TimePolicy {
+    /// Returns `secs`, or the first fallback that `item` has set.
+    pub fn resolve(&self, secs: u64, item: &api::Item) -> SystemTime {
+        if secs != 0 {
+            return from_secs(secs);
+        }
+
+        let (viewed, updated, added) = item.timestamps();
+        for source in self.fallback.iter() {
+            let secs = match source {
+                TimeSource::Viewed => viewed,
+                TimeSource::Updated => updated,
+                TimeSource::Added => added,
+                TimeSource::Mount => return self.mounted_at,
+            };
+            if secs != 0 {
+                return from_secs(secs);
+            }
+        }
+
+        UNIX_EPOCH
+    }
+}